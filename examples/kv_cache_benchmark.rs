@@ -0,0 +1,84 @@
+//! Compares the preallocated kv cache with growing the cache by `Tensor::cat` on CPU,
+//! using the attention shapes of the Dart v2 100M model.
+//!
+//! cargo run --release --example kv_cache_benchmark
+
+use anyhow::Result;
+use candle_core::{Device, Tensor};
+
+use dartrs::models::kv_cache::KvCache;
+
+const NUM_LAYERS: usize = 8;
+const NUM_HEADS: usize = 8;
+const NUM_KV_HEADS: usize = 1;
+const HEAD_DIM: usize = 96;
+const MAX_POSITION_EMBEDDINGS: usize = 1024;
+
+trait Cache {
+    fn append(&mut self, k: &Tensor, v: &Tensor) -> Result<(Tensor, Tensor)>;
+}
+
+/// The previous implementation, concatenating the whole cache every step.
+struct ConcatCache(Option<(Tensor, Tensor)>);
+
+impl Cache for ConcatCache {
+    fn append(&mut self, k: &Tensor, v: &Tensor) -> Result<(Tensor, Tensor)> {
+        let (k, v) = match &self.0 {
+            None => (k.clone(), v.clone()),
+            Some((prev_k, prev_v)) => {
+                (Tensor::cat(&[prev_k, k], 2)?, Tensor::cat(&[prev_v, v], 2)?)
+            }
+        };
+        self.0 = Some((k.clone(), v.clone()));
+        Ok((k, v))
+    }
+}
+
+impl Cache for KvCache {
+    fn append(&mut self, k: &Tensor, v: &Tensor) -> Result<(Tensor, Tensor)> {
+        Ok(KvCache::append(self, k, v)?)
+    }
+}
+
+// generates `num_tokens` tokens one by one and returns tokens/s
+fn bench<C: Cache>(caches: &mut [C], num_tokens: usize, device: &Device) -> Result<f64> {
+    let scale = 1f64 / f64::sqrt(HEAD_DIM as f64);
+    let start = std::time::Instant::now();
+    for _ in 0..num_tokens {
+        for cache in caches.iter_mut() {
+            let q = Tensor::randn(0f32, 1., (1, NUM_HEADS, 1, HEAD_DIM), device)?;
+            let k = Tensor::randn(0f32, 1., (1, NUM_KV_HEADS, 1, HEAD_DIM), device)?;
+            let v = Tensor::randn(0f32, 1., (1, NUM_KV_HEADS, 1, HEAD_DIM), device)?;
+            let (k, v) = cache.append(&k, &v)?;
+
+            let k = candle_transformers::utils::repeat_kv(k, NUM_HEADS / NUM_KV_HEADS)?;
+            let v = candle_transformers::utils::repeat_kv(v, NUM_HEADS / NUM_KV_HEADS)?;
+            let attn_weights = (q.matmul(&k.transpose(2, 3)?)? * scale)?;
+            let attn_weights = candle_nn::ops::softmax_last_dim(&attn_weights)?;
+            attn_weights.matmul(&v)?;
+        }
+    }
+    Ok(num_tokens as f64 / start.elapsed().as_secs_f64())
+}
+
+fn main() -> Result<()> {
+    let device = Device::Cpu;
+
+    for num_tokens in [128, 512, MAX_POSITION_EMBEDDINGS] {
+        let mut concat = (0..NUM_LAYERS)
+            .map(|_| ConcatCache(None))
+            .collect::<Vec<_>>();
+        let concat = bench(&mut concat, num_tokens, &device)?;
+
+        let mut preallocated = (0..NUM_LAYERS)
+            .map(|_| KvCache::new(2, MAX_POSITION_EMBEDDINGS))
+            .collect::<Vec<_>>();
+        let preallocated = bench(&mut preallocated, num_tokens, &device)?;
+
+        println!(
+            "{num_tokens:>5} tokens: Tensor::cat {concat:>8.2} token/s, preallocated {preallocated:>8.2} token/s ({:.2}x)",
+            preallocated / concat
+        );
+    }
+    Ok(())
+}
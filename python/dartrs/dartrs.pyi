@@ -1,4 +1,5 @@
 from abc import ABC
+from typing import Any, Callable, Literal
 
 class DartDType:
     BF16: ...
@@ -13,29 +14,238 @@ class DartDevice:
     @classmethod
     def Cuda(cls, id: int) -> ...: ...
 
+class Embedding:
+    """Hidden states or embeddings as float32, convertible with `numpy.asarray`."""
+
+    def shape(self) -> list[int]: ...
+    def tolist(self) -> list[float] | list[list[float]]: ...
+    def __array__(self, dtype=None, copy: bool | None = None): ...
+
 class GenerationConfig:
+    """Can be pickled to send it to other processes, with the tokenizer as its json.
+
+    Every argument of `__init__` is also a property, validated when it is set."""
+
+    device: DartDevice
+    tokenizer: DartTokenizer
+    prompt: str
+    eos_token: int | None
+    max_new_tokens: int
+    temperature: float | None
+    top_p: float | None
+    top_k: int | None
+    ban_token_ids: list[int] | None
+    seed: int | None
+    ban_tags: list[str]
+
     def __init__(
         self,
         device: DartDevice,
         tokenizer: DartTokenizer,
         prompt: str,
-        eos_token: str | None = None,
+        *,
+        eos_token: int | None = None,
         max_new_tokens: int | None = None,
         temperature: float | None = None,
         top_p: float | None = None,
         top_k: int | None = None,
         ban_token_ids: list[int] | None = None,
         seed: int | None = None,
-    ) -> None: ...
-    def tokenizer(self) -> DartTokenizer: ...
-    def prompt(self) -> str: ...
-    def max_new_tokens(self) -> int: ...
+        ban_tags: list[str] | None = None,
+    ) -> None:
+        """`ban_tags` accepts glob patterns (`*nude*`), regexes (`re:^nude`) and
+        categories of special tokens (`category:rating`, `aspect_ratio`, `length`,
+        `identity` or `special`). General tags have no category, ban them with globs
+        and regexes. `max_new_tokens` defaults to 256.
+
+        Raises `ValueError` unless `temperature > 0`, `0 < top_p <= 1`, `top_k >= 1`
+        and `max_new_tokens >= 1`, or if the eos token is not in the tokenizer."""
+        ...
+
+    def replace(self, **changes: Any) -> GenerationConfig:
+        """Returns a copy with the given fields changed, e.g. `config.replace(seed=1)`."""
+        ...
 
 class GenerationCache:
     def __init__(self, input_tokens: list[int]) -> None: ...
     def input_tokens(self) -> list[int]: ...
     def output_tokens(self) -> list[int]: ...
     def finished(self) -> bool: ...
+    def truncate_output_tokens(self, len: int) -> None:
+        """Keeps the first `len` output tokens to regenerate the rest, reusing the kv cache."""
+        ...
+
+    def length_tag(self, tokenizer: DartTokenizer) -> LengthTag:
+        """Returns the length bucket the prompt and the generated tags actually fall into."""
+        ...
+
+class CancellationToken:
+    """Stops the generations it is passed to from another thread, which return the tags so far."""
+
+    def __init__(self) -> None: ...
+    def cancel(self) -> None: ...
+    def cancelled(self) -> bool: ...
+
+class GenerationTask:
+    """A generation running in a worker thread, stopped at the next token by `cancel`."""
+
+    def cancel(self) -> None: ...
+    def cancelled(self) -> bool: ...
+
+class TagRouting:
+    """The experts selected in each layer for a generated tag."""
+
+    def tag(self) -> str: ...
+    def token(self) -> int: ...
+    def experts(self) -> list[list[int]]:
+        """The selected experts of each layer."""
+        ...
+
+    def weights(self) -> list[list[float]]:
+        """The routing weights of the selected experts, normalized to sum to 1 in each layer."""
+        ...
+
+class TagAttention:
+    """The attention weights of a generated tag."""
+
+    def tag(self) -> str: ...
+    def token(self) -> int: ...
+    def weights(self) -> list[list[list[float]]]:
+        """`(num_layers, num_heads, context_len)`, how much the tag attended to each token
+        of the prompt and the tags generated before it."""
+        ...
+
+class DartModel:
+    """Loads any supported architecture, chosen by `architectures` in `config.json`.
+
+    `hub_name` can also be a local directory with the files of the repository,
+    which is read without network access.
+
+    The models can be shared by threads. The GIL is released while a model runs,
+    and the calls on the same model run one at a time."""
+
+    def __init__(
+        self,
+        hub_name: str,
+        revision: str | None = None,
+        dtype: DartDType = DartDType.FP32,
+        device: DartDevice = DartDevice.Cpu(),
+        auth_token: str | None = None,
+    ) -> None: ...
+    def architecture(self) -> Literal["mistral", "mixtral", "llama", "opt"]: ...
+    def generate(
+        self, config: GenerationConfig, cancellation: CancellationToken | None = None
+    ) -> str:
+        """Generates text, returning the tags generated so far once `cancellation` is cancelled."""
+        raise NotImplementedError
+
+    def spawn_generate(
+        self,
+        config: GenerationConfig,
+        on_token: Callable[[int], None] | None,
+        on_done: Callable[[str | None, BaseException | None], None],
+    ) -> GenerationTask:
+        """Generates text in a worker thread, calling `on_token(token)` with each token and
+        then `on_done(text, error)` from the worker thread. `on_token` raising stops the generation.
+        """
+        ...
+
+    def generate_with_attention_weights(
+        self, config: GenerationConfig
+    ) -> tuple[str, list[str], list[TagAttention]]:
+        """Generates text, returning the prompt and generated tokens and the attention weights
+        of every generated tag over them. Not available with flash-attn."""
+        ...
+
+    def hidden_states(self, input_ids: list[int]) -> Embedding:
+        """The final normed hidden states of every input token, `(seq_len, hidden_size)`."""
+        ...
+
+    def pooled_hidden_states(self, input_ids: list[int]) -> Embedding:
+        """The mean of the hidden states over the tokens, `(hidden_size,)`."""
+        ...
+
+    def token_embeddings(self, input_ids: list[int]) -> Embedding:
+        """The input embeddings of the tokens, `(seq_len, hidden_size)`."""
+        ...
+
+    def generate_with_expert_routing(
+        self, config: GenerationConfig
+    ) -> tuple[str, list[TagRouting]]:
+        """Generates text, returning the experts selected in each layer for every generated tag.
+        Only mixture of experts models support this."""
+        ...
+
+    def get_next_token(
+        self,
+        config: GenerationConfig,
+        cache: GenerationCache,
+    ) -> tuple[int, GenerationCache]: ...
+    def load_lora(
+        self,
+        name: str,
+        adapter: str,
+        scale: float | None = 1.0,
+        revision: str | None = None,
+        auth_token: str | None = None,
+    ) -> None:
+        """Loads a PEFT LoRA adapter from a local directory or the hub as `name`.
+        It is applied at runtime with `scale` until it is merged or removed."""
+        ...
+
+    def set_lora_scale(self, name: str, scale: float) -> None:
+        """Changes the scale of a loaded adapter, `0.0` disables it."""
+        ...
+
+    def remove_lora(self, name: str) -> None: ...
+    def merge_lora(self, name: str) -> None:
+        """Merges the adapter into the base weights with its current scale."""
+        ...
+
+    def lora_adapters(self) -> list[tuple[str, float]]:
+        """The names and the scales of the loaded adapters."""
+        ...
+
+    def _clear_kv_cache(self) -> None: ...
+
+class DartSpeculativeModel:
+    """Speculative decoding with a small draft model and a larger target model."""
+
+    def __init__(
+        self,
+        draft_hub_name: str,
+        target_hub_name: str,
+        num_draft_tokens: int | None = 4,
+        draft_revision: str | None = None,
+        target_revision: str | None = None,
+        dtype: DartDType = DartDType.FP32,
+        device: DartDevice = DartDevice.Cpu(),
+        auth_token: str | None = None,
+    ) -> None: ...
+    def generate(
+        self, config: GenerationConfig, cancellation: CancellationToken | None = None
+    ) -> str:
+        """Generates text, returning the tags generated so far once `cancellation` is cancelled."""
+        raise NotImplementedError
+
+    def spawn_generate(
+        self,
+        config: GenerationConfig,
+        on_token: Callable[[int], None] | None,
+        on_done: Callable[[str | None, BaseException | None], None],
+    ) -> GenerationTask:
+        """Generates text in a worker thread, calling `on_token(token)` with each token and
+        then `on_done(text, error)` from the worker thread. `on_token` raising stops the generation.
+        """
+        ...
+
+    def get_next_tokens(
+        self,
+        config: GenerationConfig,
+        cache: GenerationCache,
+    ) -> tuple[list[int], GenerationCache]: ...
+    def acceptance_rate(self) -> float: ...
+    def _clear_kv_cache(self) -> None: ...
 
 class DartV2Mistral:
     def __init__(
@@ -46,14 +256,72 @@ class DartV2Mistral:
         device: DartDevice = DartDevice.Cpu(),
         auth_token: str | None = None,
     ) -> None: ...
-    def generate(self, config: GenerationConfig) -> str:
+    def generate(
+        self, config: GenerationConfig, cancellation: CancellationToken | None = None
+    ) -> str:
+        """Generates text, returning the tags generated so far once `cancellation` is cancelled."""
         raise NotImplementedError
 
+    def spawn_generate(
+        self,
+        config: GenerationConfig,
+        on_token: Callable[[int], None] | None,
+        on_done: Callable[[str | None, BaseException | None], None],
+    ) -> GenerationTask:
+        """Generates text in a worker thread, calling `on_token(token)` with each token and
+        then `on_done(text, error)` from the worker thread. `on_token` raising stops the generation.
+        """
+        ...
+
+    def generate_with_attention_weights(
+        self, config: GenerationConfig
+    ) -> tuple[str, list[str], list[TagAttention]]:
+        """Generates text, returning the prompt and generated tokens and the attention weights
+        of every generated tag over them. Not available with flash-attn."""
+        ...
+
+    def hidden_states(self, input_ids: list[int]) -> Embedding:
+        """The final normed hidden states of every input token, `(seq_len, hidden_size)`."""
+        ...
+
+    def pooled_hidden_states(self, input_ids: list[int]) -> Embedding:
+        """The mean of the hidden states over the tokens, `(hidden_size,)`."""
+        ...
+
+    def token_embeddings(self, input_ids: list[int]) -> Embedding:
+        """The input embeddings of the tokens, `(seq_len, hidden_size)`."""
+        ...
+
     def get_next_token(
         self,
         config: GenerationConfig,
         cache: GenerationCache,
     ) -> tuple[int, GenerationCache]: ...
+    def load_lora(
+        self,
+        name: str,
+        adapter: str,
+        scale: float | None = 1.0,
+        revision: str | None = None,
+        auth_token: str | None = None,
+    ) -> None:
+        """Loads a PEFT LoRA adapter from a local directory or the hub as `name`.
+        It is applied at runtime with `scale` until it is merged or removed."""
+        ...
+
+    def set_lora_scale(self, name: str, scale: float) -> None:
+        """Changes the scale of a loaded adapter, `0.0` disables it."""
+        ...
+
+    def remove_lora(self, name: str) -> None: ...
+    def merge_lora(self, name: str) -> None:
+        """Merges the adapter into the base weights with its current scale."""
+        ...
+
+    def lora_adapters(self) -> list[tuple[str, float]]:
+        """The names and the scales of the loaded adapters."""
+        ...
+
     def _clear_kv_cache(self) -> None: ...
 
 class DartV2Mixtral:
@@ -65,9 +333,140 @@ class DartV2Mixtral:
         device: DartDevice = DartDevice.Cpu(),
         auth_token: str | None = None,
     ) -> None: ...
-    def generate(self, config: GenerationConfig) -> str:
+    def generate(
+        self, config: GenerationConfig, cancellation: CancellationToken | None = None
+    ) -> str:
+        """Generates text, returning the tags generated so far once `cancellation` is cancelled."""
+        raise NotImplementedError
+
+    def spawn_generate(
+        self,
+        config: GenerationConfig,
+        on_token: Callable[[int], None] | None,
+        on_done: Callable[[str | None, BaseException | None], None],
+    ) -> GenerationTask:
+        """Generates text in a worker thread, calling `on_token(token)` with each token and
+        then `on_done(text, error)` from the worker thread. `on_token` raising stops the generation.
+        """
+        ...
+
+    def generate_with_attention_weights(
+        self, config: GenerationConfig
+    ) -> tuple[str, list[str], list[TagAttention]]:
+        """Generates text, returning the prompt and generated tokens and the attention weights
+        of every generated tag over them. Not available with flash-attn."""
+        ...
+
+    def hidden_states(self, input_ids: list[int]) -> Embedding:
+        """The final normed hidden states of every input token, `(seq_len, hidden_size)`."""
+        ...
+
+    def pooled_hidden_states(self, input_ids: list[int]) -> Embedding:
+        """The mean of the hidden states over the tokens, `(hidden_size,)`."""
+        ...
+
+    def token_embeddings(self, input_ids: list[int]) -> Embedding:
+        """The input embeddings of the tokens, `(seq_len, hidden_size)`."""
+        ...
+
+    def generate_with_expert_routing(
+        self, config: GenerationConfig
+    ) -> tuple[str, list[TagRouting]]:
+        """Generates text, returning the experts selected in each layer for every generated tag.
+        Only mixture of experts models support this."""
+        ...
+
+    def get_next_token(
+        self,
+        config: GenerationConfig,
+        cache: GenerationCache,
+    ) -> tuple[int, GenerationCache]: ...
+    def load_lora(
+        self,
+        name: str,
+        adapter: str,
+        scale: float | None = 1.0,
+        revision: str | None = None,
+        auth_token: str | None = None,
+    ) -> None:
+        """Loads a PEFT LoRA adapter from a local directory or the hub as `name`.
+        It is applied at runtime with `scale` until it is merged or removed."""
+        ...
+
+    def set_lora_scale(self, name: str, scale: float) -> None:
+        """Changes the scale of a loaded adapter, `0.0` disables it."""
+        ...
+
+    def remove_lora(self, name: str) -> None: ...
+    def merge_lora(self, name: str) -> None:
+        """Merges the adapter into the base weights with its current scale."""
+        ...
+
+    def lora_adapters(self) -> list[tuple[str, float]]:
+        """The names and the scales of the loaded adapters."""
+        ...
+
+    def _clear_kv_cache(self) -> None: ...
+
+class DartV2Llama:
+    def __init__(
+        self,
+        hub_name: str,
+        revision: str | None = None,
+        dtype: DartDType = DartDType.FP32,
+        device: DartDevice = DartDevice.Cpu(),
+        auth_token: str | None = None,
+    ) -> None: ...
+    def generate(
+        self, config: GenerationConfig, cancellation: CancellationToken | None = None
+    ) -> str:
+        """Generates text, returning the tags generated so far once `cancellation` is cancelled."""
+        raise NotImplementedError
+
+    def spawn_generate(
+        self,
+        config: GenerationConfig,
+        on_token: Callable[[int], None] | None,
+        on_done: Callable[[str | None, BaseException | None], None],
+    ) -> GenerationTask:
+        """Generates text in a worker thread, calling `on_token(token)` with each token and
+        then `on_done(text, error)` from the worker thread. `on_token` raising stops the generation.
+        """
+        ...
+
+    def get_next_token(
+        self,
+        config: GenerationConfig,
+        cache: GenerationCache,
+    ) -> tuple[int, GenerationCache]: ...
+    def _clear_kv_cache(self) -> None: ...
+
+class DartV1Opt:
+    def __init__(
+        self,
+        hub_name: str,
+        revision: str | None = None,
+        dtype: DartDType = DartDType.FP32,
+        device: DartDevice = DartDevice.Cpu(),
+        auth_token: str | None = None,
+    ) -> None: ...
+    def generate(
+        self, config: GenerationConfig, cancellation: CancellationToken | None = None
+    ) -> str:
+        """Generates text, returning the tags generated so far once `cancellation` is cancelled."""
         raise NotImplementedError
 
+    def spawn_generate(
+        self,
+        config: GenerationConfig,
+        on_token: Callable[[int], None] | None,
+        on_done: Callable[[str | None, BaseException | None], None],
+    ) -> GenerationTask:
+        """Generates text in a worker thread, calling `on_token(token)` with each token and
+        then `on_done(text, error)` from the worker thread. `on_token` raising stops the generation.
+        """
+        ...
+
     def get_next_token(
         self,
         config: GenerationConfig,
@@ -82,6 +481,12 @@ class DartTokenizer:
         revision: str | None = None,
         auth_token: str | None = None,
     ) -> DartTokenizer: ...
+    @classmethod
+    def from_json(cls, json: str) -> DartTokenizer:
+        """Loads a tokenizer from the content of `tokenizer.json`."""
+        ...
+
+    def to_json(self) -> str: ...
     def encode(self, text: str) -> list[int]: ...
     def decode(
         self, token_ids: list[int], skip_special_tokens: bool | None = None
@@ -108,7 +513,10 @@ class DartTokenizer:
         ...
 
 class Tag(ABC):
-    def __init__(self, tag: str) -> None: ...
+    def __init__(self, tag: str) -> None:
+        """Accepts either the short name (`tall`) or the full special tag (`<|aspect_ratio:tall|>`)."""
+        ...
+
     def to_tag(self) -> str: ...
 
 class LengthTag(Tag):
@@ -117,6 +525,19 @@ class LengthTag(Tag):
     Medium: LengthTag
     Long: LengthTag
     VeryLong: LengthTag
+    @staticmethod
+    def from_tag_count(count: int) -> LengthTag:
+        """Chooses the length tag for the desired number of general tags."""
+        ...
+
+    @staticmethod
+    def from_tags(tags: str) -> LengthTag:
+        """Chooses the length tag of comma separated tags."""
+        ...
+
+    def tag_count_range(self) -> tuple[int, int | None]:
+        """Returns the range [min, max) of the number of general tags."""
+        ...
 
 class AspectRatioTag(Tag):
     UltraWide: AspectRatioTag
@@ -124,6 +545,16 @@ class AspectRatioTag(Tag):
     Square: AspectRatioTag
     Tall: AspectRatioTag
     UltraTall: AspectRatioTag
+    @staticmethod
+    def from_resolution(width: int, height: int) -> AspectRatioTag:
+        """Chooses the aspect ratio tag for an image size.
+
+        Raises `ValueError` if the width or the height is 0."""
+        ...
+
+    def sdxl_resolution(self) -> tuple[int, int]:
+        """Returns a suggested SDXL resolution (width, height) for the tag."""
+        ...
 
 class RatingTag(Tag):
     Sfw: RatingTag
@@ -148,6 +579,31 @@ class SpecialTag(Tag):
     GeneralStart: SpecialTag
     GeneralEnd: SpecialTag
     InputEnd: SpecialTag
+    RatingStart: SpecialTag
+    RatingEnd: SpecialTag
+
+class RatingTagV1(Tag):
+    Sfw: RatingTagV1
+    General: RatingTagV1
+    Sensitive: RatingTagV1
+    Nsfw: RatingTagV1
+    Questionable: RatingTagV1
+    Explicit: RatingTagV1
+
+class LengthTagV1(Tag):
+    VeryShort: LengthTagV1
+    Short: LengthTagV1
+    Long: LengthTagV1
+    VeryLong: LengthTagV1
+
+def compose_prompt_v1(
+    copyright: str,
+    character: str,
+    rating: RatingTagV1,
+    length: LengthTagV1,
+    prompt: str,
+    do_completion: bool,
+) -> str: ...
 
 def compose_prompt_v2(
     copyright: str,
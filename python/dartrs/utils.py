@@ -10,13 +10,14 @@ def get_generation_config(
     prompt: str,
     tokenizer: dartrs.DartTokenizer,
     device: dartrs.DartDevice = dartrs.DartDevice.Cpu(),
-    eos_token: str | None = None,
+    eos_token: int | None = None,
     max_new_tokens: int | None = 256,
     temperature: float | None = 1.0,
     top_p: float | None = 1.0,
     top_k: int | None = 100,
     ban_token_ids: list[int] | None = None,
     seed: int | None = None,
+    ban_tags: list[str] | None = None,
 ) -> dartrs.GenerationConfig:
     return dartrs.GenerationConfig(
         device=device,
@@ -29,4 +30,5 @@ def get_generation_config(
         top_k=top_k,
         ban_token_ids=ban_token_ids,
         seed=seed,
+        ban_tags=ban_tags,
     )
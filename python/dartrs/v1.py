@@ -0,0 +1,50 @@
+from typing import Literal
+
+from . import dartrs
+from . import utils
+from .v2 import V2Model
+
+LengthTag = Literal["very_short", "short", "long", "very_long"]
+
+RatingTag = Literal["sfw", "general", "sensitive", "nsfw", "questionable", "explicit"]
+
+
+def compose_prompt(
+    prompt: str = "",
+    copyright: str = "",
+    character: str = "",
+    rating: RatingTag = "general",
+    length: LengthTag = "long",
+    do_completion: bool = True,
+):
+    return dartrs.compose_prompt_v1(
+        copyright=copyright,
+        character=character,
+        rating=dartrs.RatingTagV1(rating),
+        length=dartrs.LengthTagV1(length),
+        prompt=prompt,
+        do_completion=do_completion,
+    )
+
+
+class OptModel(V2Model):
+    """Dart v1 model. The generation API is the same as the v2 models."""
+
+    @classmethod
+    def from_pretrained(
+        cls,
+        hub_name: str,
+        revision: str | None = None,
+        dtype: utils.DType = "fp32",
+        device: utils.Device = "cpu",
+        auth_token: str | None = None,
+    ) -> V2Model:
+        return cls(
+            dartrs.DartV1Opt(
+                hub_name,
+                revision,
+                dartrs.DartDType(dtype),
+                dartrs.DartDevice(device),
+                auth_token,
+            )
+        )
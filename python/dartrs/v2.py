@@ -1,4 +1,5 @@
-from typing import Literal, Generator
+import asyncio
+from typing import Any, AsyncGenerator, Literal, Generator
 
 from . import dartrs
 from . import utils
@@ -25,27 +26,185 @@ def compose_prompt(
     return dartrs.compose_prompt_v2(
         copyright=copyright,
         character=character,
-        rating=dartrs.RatingTag(f"<|rating:{rating}|>"),
-        aspect_ratio=dartrs.AspectRatioTag(f"<|aspect_ratio:{aspect_ratio}|>"),
-        length=dartrs.LengthTag(f"<|length:{length}|>"),
-        identity_level=dartrs.IdentityTag(f"<|identity:{identity}|>"),
+        rating=dartrs.RatingTag(rating),
+        aspect_ratio=dartrs.AspectRatioTag(aspect_ratio),
+        length=dartrs.LengthTag(length),
+        identity_level=dartrs.IdentityTag(identity),
         prompt=prompt,
         do_completion=do_completion,
     )
 
 
+async def _agenerate(model: Any, config: dartrs.GenerationConfig) -> str:
+    # the worker thread reports back through the event loop
+    loop = asyncio.get_running_loop()
+    future = loop.create_future()
+
+    def on_done(text: str | None, error: BaseException | None) -> None:
+        loop.call_soon_threadsafe(_set_future, future, text, error)
+
+    task = model.spawn_generate(config, None, on_done)
+    try:
+        return await future
+    finally:
+        task.cancel()
+
+
+def _set_future(
+    future: asyncio.Future, text: str | None, error: BaseException | None
+) -> None:
+    if future.done():  # cancelled
+        return
+    if error is not None:
+        future.set_exception(error)
+    else:
+        future.set_result(text)
+
+
+async def _agenerate_stream(
+    model: Any, config: dartrs.GenerationConfig
+) -> AsyncGenerator[str, None]:
+    loop = asyncio.get_running_loop()
+    queue: asyncio.Queue[int | tuple[str | None, BaseException | None]] = (
+        asyncio.Queue()
+    )
+
+    def on_token(token: int) -> None:
+        loop.call_soon_threadsafe(queue.put_nowait, token)
+
+    def on_done(text: str | None, error: BaseException | None) -> None:
+        loop.call_soon_threadsafe(queue.put_nowait, (text, error))
+
+    tokenizer = config.tokenizer
+    task = model.spawn_generate(config, on_token, on_done)
+    try:
+        while True:
+            item = await queue.get()
+            if isinstance(item, int):
+                yield tokenizer.decode([item], skip_special_tokens=True)
+                continue
+            _text, error = item
+            if error is not None:
+                raise error
+            return
+    finally:
+        task.cancel()
+
+
 class V2Model:
-    model: dartrs.DartV2Mistral | dartrs.DartV2Mixtral
+    model: (
+        dartrs.DartModel
+        | dartrs.DartV2Mistral
+        | dartrs.DartV2Mixtral
+        | dartrs.DartV2Llama
+        | dartrs.DartV1Opt
+    )
 
     def __init__(
         self,
-        model: dartrs.DartV2Mistral | dartrs.DartV2Mixtral,
+        model: (
+            dartrs.DartModel
+            | dartrs.DartV2Mistral
+            | dartrs.DartV2Mixtral
+            | dartrs.DartV2Llama
+            | dartrs.DartV1Opt
+        ),
     ) -> None:
         self.model = model
 
-    def generate(self, config: dartrs.GenerationConfig) -> str:
-        """Generates tags."""
-        return self.model.generate(config)
+    @classmethod
+    def from_pretrained(
+        cls,
+        hub_name: str,
+        revision: str | None = None,
+        dtype: utils.DType = "fp32",
+        device: utils.Device = "cpu",
+        auth_token: str | None = None,
+    ) -> "V2Model":
+        """Loads a model of any supported architecture."""
+        return cls(
+            dartrs.DartModel(
+                hub_name,
+                revision,
+                dartrs.DartDType(dtype),
+                dartrs.DartDevice(device),
+                auth_token,
+            )
+        )
+
+    def generate(
+        self,
+        config: dartrs.GenerationConfig,
+        cancellation: dartrs.CancellationToken | None = None,
+    ) -> str:
+        """Generates tags. Cancelling `cancellation` from another thread returns the tags
+        generated so far, and Ctrl-C raises KeyboardInterrupt."""
+        return self.model.generate(config, cancellation)
+
+    def load_lora(
+        self,
+        name: str,
+        adapter: str,
+        scale: float = 1.0,
+        merge: bool = False,
+        revision: str | None = None,
+        auth_token: str | None = None,
+    ) -> None:
+        """Loads a PEFT LoRA adapter from a local directory or the hub.
+        With `merge`, the adapter is merged into the base weights and can not be changed later."""
+        self.model.load_lora(name, adapter, scale, revision, auth_token)
+        if merge:
+            self.model.merge_lora(name)
+
+    def set_lora_scale(self, name: str, scale: float) -> None:
+        """Changes the scale of a loaded adapter, `0.0` disables it."""
+        self.model.set_lora_scale(name, scale)
+
+    def remove_lora(self, name: str) -> None:
+        self.model.remove_lora(name)
+
+    def embed(
+        self,
+        text: str,
+        tokenizer: dartrs.DartTokenizer,
+        pooling: Literal["mean", "none"] = "mean",
+    ) -> dartrs.Embedding:
+        """Embeds tags with the final normed hidden states of the model.
+        `numpy.asarray` converts the result to an array."""
+        input_ids = tokenizer.encode(text)
+        if pooling == "mean":
+            return self.model.pooled_hidden_states(input_ids)
+        return self.model.hidden_states(input_ids)
+
+    def embed_tags(
+        self, tags: list[str], tokenizer: dartrs.DartTokenizer
+    ) -> dartrs.Embedding:
+        """Returns the input embedding of each tag, `(len(tags), hidden_size)`."""
+        vocab = tokenizer.get_vocab()
+        missing = [tag for tag in tags if tag not in vocab]
+        if missing:
+            raise ValueError(f"unknown tags: {missing}")
+        return self.model.token_embeddings([vocab[tag] for tag in tags])
+
+    def generate_with_attention_weights(
+        self, config: dartrs.GenerationConfig
+    ) -> tuple[str, list[str], list[dartrs.TagAttention]]:
+        """Generates tags, returning the prompt and generated tokens and the attention weights
+        of every generated tag over them, to see which tokens influenced each tag."""
+        if not hasattr(self.model, "generate_with_attention_weights"):
+            raise ValueError(
+                "the model does not support recording the attention weights"
+            )
+        return self.model.generate_with_attention_weights(config)
+
+    def generate_with_expert_routing(
+        self, config: dartrs.GenerationConfig
+    ) -> tuple[str, list[dartrs.TagRouting]]:
+        """Generates tags, returning the experts selected in each layer for every generated tag.
+        Only mixture of experts models support this."""
+        if not hasattr(self.model, "generate_with_expert_routing"):
+            raise ValueError("the model is not a mixture of experts model")
+        return self.model.generate_with_expert_routing(config)
 
     def _get_next_token(
         self, config: dartrs.GenerationConfig, cache: dartrs.GenerationCache
@@ -60,12 +219,12 @@ class V2Model:
     ]:
         """Generates tags and returns the final decoded text."""
 
-        tokens = config.tokenizer().encode(config.prompt())
+        tokens = config.tokenizer.encode(config.prompt)
         cache = dartrs.GenerationCache(tokens)
 
-        for _ in range(0, config.max_new_tokens()):
+        for _ in range(0, config.max_new_tokens):
             token, cache = self._get_next_token(config, cache)
-            tag = config.tokenizer().decode([token], skip_special_tokens=True)
+            tag = config.tokenizer.decode([token], skip_special_tokens=True)
             yield tag
 
             if cache.finished():
@@ -73,11 +232,23 @@ class V2Model:
 
         self.model._clear_kv_cache()  # clear kv cache
 
-        decoded = config.tokenizer().decode(
+        decoded = config.tokenizer.decode(
             cache.output_tokens(), skip_special_tokens=True
         )
         return decoded
 
+    async def agenerate(self, config: dartrs.GenerationConfig) -> str:
+        """Generates tags in a worker thread without blocking the event loop.
+        Cancelling the coroutine stops the generation at the next tag."""
+        return await _agenerate(self.model, config)
+
+    def agenerate_stream(
+        self, config: dartrs.GenerationConfig
+    ) -> AsyncGenerator[str, None]:
+        """Generates tags in a worker thread, yielding each tag as it is generated.
+        Cancelling the iteration or closing the generator stops the generation."""
+        return _agenerate_stream(self.model, config)
+
 
 class MixtralModel(V2Model):
     @classmethod
@@ -119,3 +290,109 @@ class MistralModel(V2Model):
                 auth_token,
             )
         )
+
+
+class LlamaModel(V2Model):
+    @classmethod
+    def from_pretrained(
+        cls,
+        hub_name: str,
+        revision: str | None = None,
+        dtype: utils.DType = "fp32",
+        device: utils.Device = "cpu",
+        auth_token: str | None = None,
+    ) -> V2Model:
+        return cls(
+            dartrs.DartV2Llama(
+                hub_name,
+                revision,
+                dartrs.DartDType(dtype),
+                dartrs.DartDevice(device),
+                auth_token,
+            )
+        )
+
+
+class SpeculativeModel:
+    """Generates with the target model, using a smaller model to draft the tags.
+
+    The output follows the same distribution as the target model alone.
+    """
+
+    model: dartrs.DartSpeculativeModel
+
+    def __init__(self, model: dartrs.DartSpeculativeModel) -> None:
+        self.model = model
+
+    @classmethod
+    def from_pretrained(
+        cls,
+        draft_hub_name: str,
+        target_hub_name: str,
+        num_draft_tokens: int = 4,
+        dtype: utils.DType = "fp32",
+        device: utils.Device = "cpu",
+        auth_token: str | None = None,
+    ) -> "SpeculativeModel":
+        return cls(
+            dartrs.DartSpeculativeModel(
+                draft_hub_name,
+                target_hub_name,
+                num_draft_tokens,
+                None,
+                None,
+                dartrs.DartDType(dtype),
+                dartrs.DartDevice(device),
+                auth_token,
+            )
+        )
+
+    def generate(
+        self,
+        config: dartrs.GenerationConfig,
+        cancellation: dartrs.CancellationToken | None = None,
+    ) -> str:
+        """Generates tags. Cancelling `cancellation` from another thread returns the tags
+        generated so far, and Ctrl-C raises KeyboardInterrupt."""
+        return self.model.generate(config, cancellation)
+
+    def generate_stream(self, config: dartrs.GenerationConfig) -> Generator[
+        str,  # tag
+        None,
+        str,  # final decoded text
+    ]:
+        """Generates tags and returns the final decoded text."""
+
+        tokens = config.tokenizer.encode(config.prompt)
+        cache = dartrs.GenerationCache(tokens)
+
+        while len(cache.output_tokens()) < config.max_new_tokens:
+            next_tokens, cache = self.model.get_next_tokens(config, cache)
+            for token in next_tokens:
+                yield config.tokenizer.decode([token], skip_special_tokens=True)
+
+            if cache.finished():
+                break
+
+        self.model._clear_kv_cache()  # clear kv cache
+
+        decoded = config.tokenizer.decode(
+            cache.output_tokens(), skip_special_tokens=True
+        )
+        return decoded
+
+    async def agenerate(self, config: dartrs.GenerationConfig) -> str:
+        """Generates tags in a worker thread without blocking the event loop.
+        Cancelling the coroutine stops the generation at the next tag."""
+        return await _agenerate(self.model, config)
+
+    def agenerate_stream(
+        self, config: dartrs.GenerationConfig
+    ) -> AsyncGenerator[str, None]:
+        """Generates tags in a worker thread, yielding each tag as it is generated.
+        Cancelling the iteration or closing the generator stops the generation."""
+        return _agenerate_stream(self.model, config)
+
+    def acceptance_rate(self) -> float:
+        """The ratio of drafted tags accepted by the target model."""
+        return self.model.acceptance_rate()
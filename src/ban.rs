@@ -0,0 +1,302 @@
+use anyhow::{Error as E, Result};
+use regex::{Regex, RegexSet};
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use tokenizers::Tokenizer;
+
+use crate::tags::{AspectRatioTag, IdentityTag, LengthTag, RatingTag, SpecialTag, Tag};
+
+/// Categories of the special tokens that can be recognized from the vocabulary alone.
+///
+/// The vocabulary does not tell what a general tag is about, so content classes
+/// such as nudity are banned with `re:` and glob patterns instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TagCategory {
+    Rating,
+    AspectRatio,
+    Length,
+    Identity,
+    Special,
+}
+
+impl TagCategory {
+    fn contains(&self, tag: &str) -> bool {
+        match self {
+            Self::Rating => RatingTag::is_special(tag),
+            Self::AspectRatio => AspectRatioTag::is_special(tag),
+            Self::Length => LengthTag::is_special(tag),
+            Self::Identity => IdentityTag::is_special(tag),
+            Self::Special => SpecialTag::is_special(tag),
+        }
+    }
+}
+
+impl FromStr for TagCategory {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rating" => Ok(Self::Rating),
+            "aspect_ratio" => Ok(Self::AspectRatio),
+            "length" => Ok(Self::Length),
+            "identity" => Ok(Self::Identity),
+            "special" => Ok(Self::Special),
+            _ => Err(E::msg(format!("invalid tag category: {s}"))),
+        }
+    }
+}
+
+impl fmt::Display for TagCategory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Self::Rating => "rating",
+            Self::AspectRatio => "aspect_ratio",
+            Self::Length => "length",
+            Self::Identity => "identity",
+            Self::Special => "special",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// A rule matching tag names in the vocabulary.
+///
+/// Parsed from a string as `re:<regex>`, `category:<category>` or a glob
+/// pattern where `*` matches any characters and `?` matches one character.
+/// `category:` only matches special tokens, see `TagCategory`.
+#[derive(Debug, Clone)]
+pub enum BanPattern {
+    Glob(String),
+    Regex(Regex),
+    Category(TagCategory),
+}
+
+impl BanPattern {
+    fn to_regex(&self) -> Option<String> {
+        match self {
+            Self::Glob(glob) => {
+                let mut pattern = String::from("^");
+                for c in glob.chars() {
+                    match c {
+                        '*' => pattern.push_str(".*"),
+                        '?' => pattern.push('.'),
+                        _ => pattern.push_str(&regex::escape(&c.to_string())),
+                    }
+                }
+                pattern.push('$');
+                Some(pattern)
+            }
+            Self::Regex(regex) => Some(regex.as_str().to_string()),
+            Self::Category(_) => None,
+        }
+    }
+}
+
+impl FromStr for BanPattern {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(regex) = s.strip_prefix("re:") {
+            Ok(Self::Regex(Regex::new(regex)?))
+        } else if let Some(category) = s.strip_prefix("category:") {
+            Ok(Self::Category(TagCategory::from_str(category)?))
+        } else {
+            Ok(Self::Glob(s.to_string()))
+        }
+    }
+}
+
+impl fmt::Display for BanPattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Glob(glob) => write!(f, "{}", glob),
+            Self::Regex(regex) => write!(f, "re:{}", regex.as_str()),
+            Self::Category(category) => write!(f, "category:{}", category),
+        }
+    }
+}
+
+/// A set of patterns resolved to the token ids to ban during generation.
+#[derive(Debug, Clone, Default)]
+pub struct BanList {
+    patterns: Vec<BanPattern>,
+}
+
+impl BanList {
+    pub fn new(patterns: Vec<BanPattern>) -> Self {
+        Self { patterns }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    pub fn patterns(&self) -> &[BanPattern] {
+        &self.patterns
+    }
+
+    /// Returns the ids of every token matching any of the patterns.
+    pub fn resolve(&self, tokenizer: &Tokenizer) -> Result<Vec<u32>> {
+        self.find_token_ids(tokenizer)
+    }
+
+    fn key(&self) -> Vec<String> {
+        self.patterns.iter().map(|p| p.to_string()).collect()
+    }
+
+    fn find_token_ids(&self, tokenizer: &Tokenizer) -> Result<Vec<u32>> {
+        let regex_set = RegexSet::new(self.patterns.iter().filter_map(|p| p.to_regex()))?;
+        let categories = self
+            .patterns
+            .iter()
+            .filter_map(|p| match p {
+                BanPattern::Category(category) => Some(*category),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        let mut ids = tokenizer
+            .get_vocab(true)
+            .into_iter()
+            .filter(|(token, _)| {
+                regex_set.is_match(token) || categories.iter().any(|c| c.contains(token))
+            })
+            .map(|(_, id)| id)
+            .collect::<Vec<u32>>();
+        ids.sort_unstable();
+        Ok(ids)
+    }
+}
+
+// the number of ban lists a cache keeps
+const BAN_CACHE_SIZE: usize = 16;
+
+// the patterns of a ban list and their token ids
+type BanCacheEntry = (Vec<String>, Arc<Vec<u32>>);
+
+/// Token ids resolved from ban lists over the vocabulary of one tokenizer.
+///
+/// Keep it with the tokenizer it is used for. The clones share the cache,
+/// which holds the most recently used `BAN_CACHE_SIZE` ban lists.
+#[derive(Debug, Clone, Default)]
+pub struct BanCache {
+    entries: Arc<Mutex<VecDeque<BanCacheEntry>>>,
+}
+
+impl BanCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns `ban_list.resolve(tokenizer)`, resolving it only the first time.
+    pub fn resolve(&self, ban_list: &BanList, tokenizer: &Tokenizer) -> Result<Arc<Vec<u32>>> {
+        let key = ban_list.key();
+        let mut entries = self.entries.lock().map_err(|e| E::msg(e.to_string()))?;
+        if let Some(index) = entries.iter().position(|(k, _)| *k == key) {
+            let entry = entries.remove(index).unwrap();
+            let ids = entry.1.clone();
+            entries.push_front(entry);
+            return Ok(ids);
+        }
+        let ids = Arc::new(ban_list.resolve(tokenizer)?);
+        entries.truncate(BAN_CACHE_SIZE - 1);
+        entries.push_front((key, ids.clone()));
+        Ok(ids)
+    }
+}
+
+impl FromStr for BanList {
+    type Err = anyhow::Error;
+
+    /// Parses a comma separated list of patterns.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let patterns = s
+            .split(',')
+            .map(|p| p.trim())
+            .filter(|p| !p.is_empty())
+            .map(BanPattern::from_str)
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self::new(patterns))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use tokenizers::models::wordlevel::WordLevel;
+
+    fn tokenizer() -> Tokenizer {
+        let vocab = [
+            "<|bos|>",
+            "<|eos|>",
+            "<|rating:sfw|>",
+            "<|rating:explicit|>",
+            "<|length:long|>",
+            "1girl",
+            "nude",
+            "completely nude",
+            "nipples",
+            "cat ears",
+        ]
+        .iter()
+        .enumerate()
+        .map(|(id, token)| (token.to_string(), id as u32))
+        .collect::<HashMap<_, _>>();
+        let model = WordLevel::builder()
+            .vocab(vocab)
+            .unk_token("<|bos|>".to_string())
+            .build()
+            .unwrap();
+        Tokenizer::new(model)
+    }
+
+    #[test]
+    fn test_glob() {
+        let ban_list = BanList::from_str("*nude, nipple?").unwrap();
+        let ids = ban_list.resolve(&tokenizer()).unwrap();
+        assert_eq!(ids, vec![6, 7, 8]);
+    }
+
+    #[test]
+    fn test_regex_and_category() {
+        let ban_list = BanList::new(vec![
+            BanPattern::from_str("re:^cat").unwrap(),
+            BanPattern::Category(TagCategory::Rating),
+        ]);
+        let ids = ban_list.resolve(&tokenizer()).unwrap();
+        assert_eq!(ids, vec![2, 3, 9]);
+    }
+
+    #[test]
+    fn test_cache() {
+        let tokenizer = tokenizer();
+        let cache = BanCache::new();
+        let ban_list = BanList::from_str("*nude").unwrap();
+        let ids = cache.resolve(&ban_list, &tokenizer).unwrap();
+        assert_eq!(*ids, vec![6, 7]);
+        assert!(Arc::ptr_eq(
+            &ids,
+            &cache.clone().resolve(&ban_list, &tokenizer).unwrap()
+        ));
+
+        for i in 0..BAN_CACHE_SIZE {
+            let other = BanList::from_str(&format!("tag{i}")).unwrap();
+            cache.resolve(&other, &tokenizer).unwrap();
+        }
+        assert_eq!(cache.entries.lock().unwrap().len(), BAN_CACHE_SIZE);
+        // the least recently used ban list was evicted
+        assert!(!Arc::ptr_eq(
+            &ids,
+            &cache.resolve(&ban_list, &tokenizer).unwrap()
+        ));
+    }
+
+    #[test]
+    fn test_invalid_pattern() {
+        assert!(BanPattern::from_str("re:(").is_err());
+        assert!(BanPattern::from_str("category:copyright").is_err());
+    }
+}
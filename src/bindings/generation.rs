@@ -1,16 +1,49 @@
+use std::str::FromStr;
+
+use crate::ban::{BanList, BanPattern};
 use crate::bindings::models::{DartDevice, DartTokenizer};
-use crate::generation::{GenerationCache, GenerationConfig};
+use crate::bindings::tags::DartLengthTag;
+use crate::generation::attention_weights::TagAttention;
+use crate::generation::expert_routing::TagRouting;
+use crate::generation::{
+    CancellationToken, GenerationCache, GenerationConfig, DEFAULT_MAX_NEW_TOKENS,
+};
 
 use candle_core::Device;
+use pyo3::exceptions;
 use pyo3::prelude::*;
+use pyo3::types::PyDict;
 use tokenizers::Tokenizer;
 
-#[pyclass(name = "GenerationConfig")]
+#[pyclass(module = "dartrs.dartrs", name = "GenerationConfig")]
 #[derive(Clone, Debug)]
 pub(crate) struct DartGenerationConfig {
+    #[pyo3(get, set)]
     device: DartDevice,
+    #[pyo3(get)]
     tokenizer: DartTokenizer,
+    #[pyo3(get, set)]
     prompt: String,
+    #[pyo3(get)]
+    eos_token: Option<u32>,
+    #[pyo3(get)]
+    max_new_tokens: usize,
+    #[pyo3(get)]
+    temperature: Option<f64>,
+    #[pyo3(get)]
+    top_p: Option<f64>,
+    #[pyo3(get)]
+    top_k: Option<usize>,
+    #[pyo3(get, set)]
+    ban_token_ids: Option<Vec<u32>>,
+    #[pyo3(get, set)]
+    seed: Option<u64>,
+    ban_list: BanList,
+}
+
+/// The keyword-only arguments of `new`, `None` when not given
+#[derive(Default)]
+struct GenerationOptions {
     eos_token: Option<u32>,
     max_new_tokens: Option<usize>,
     temperature: Option<f64>,
@@ -18,68 +51,300 @@ pub(crate) struct DartGenerationConfig {
     top_k: Option<usize>,
     ban_token_ids: Option<Vec<u32>>,
     seed: Option<u64>,
+    ban_tags: Option<Vec<String>>,
 }
 
-impl From<DartGenerationConfig> for GenerationConfig {
-    fn from(config: DartGenerationConfig) -> Self {
+impl GenerationOptions {
+    fn extract(method: &str, options: Option<&Bound<'_, PyDict>>) -> PyResult<Self> {
+        let mut extracted = Self::default();
+        for (name, value) in options.into_iter().flat_map(|options| options.iter()) {
+            let name = name.extract::<String>()?;
+            match name.as_str() {
+                "eos_token" => extracted.eos_token = value.extract()?,
+                "max_new_tokens" => extracted.max_new_tokens = value.extract()?,
+                "temperature" => extracted.temperature = value.extract()?,
+                "top_p" => extracted.top_p = value.extract()?,
+                "top_k" => extracted.top_k = value.extract()?,
+                "ban_token_ids" => extracted.ban_token_ids = value.extract()?,
+                "seed" => extracted.seed = value.extract()?,
+                "ban_tags" => extracted.ban_tags = value.extract()?,
+                _ => {
+                    return Err(exceptions::PyTypeError::new_err(format!(
+                        "{}() got an unexpected keyword argument '{}'",
+                        method, name
+                    )))
+                }
+            }
+        }
+        Ok(extracted)
+    }
+}
+
+/// The fields `replace` accepts, the arguments of `new`
+const FIELDS: [&str; 11] = [
+    "device",
+    "tokenizer",
+    "prompt",
+    "eos_token",
+    "max_new_tokens",
+    "temperature",
+    "top_p",
+    "top_k",
+    "ban_token_ids",
+    "seed",
+    "ban_tags",
+];
+
+impl TryFrom<DartGenerationConfig> for GenerationConfig {
+    type Error = PyErr;
+
+    fn try_from(config: DartGenerationConfig) -> PyResult<Self> {
+        // the tokenizer and the eos token can be set separately
+        config.check_eos_token()?;
+        let ban_cache = config.tokenizer.ban_cache.clone();
+
         GenerationConfig::new(
             Device::from(config.device),
             Tokenizer::from(config.tokenizer),
             config.prompt,
             config.eos_token,
-            config.max_new_tokens,
+            Some(config.max_new_tokens),
             config.temperature,
             config.top_p,
             config.top_k,
             config.ban_token_ids,
             config.seed,
         )
+        .with_ban_list(&config.ban_list, &ban_cache)
+        .map_err(|e| exceptions::PyValueError::new_err(format!("Failed to ban tags: {}", e)))
     }
 }
 
 #[pymethods]
 impl DartGenerationConfig {
     #[new]
+    #[pyo3(signature = (device, tokenizer, prompt, **options))]
     fn new(
         device: DartDevice,
         tokenizer: DartTokenizer,
         prompt: String,
-        eos_token: Option<u32>,
-        max_new_tokens: Option<usize>,
-        temperature: Option<f64>,
-        top_p: Option<f64>,
-        top_k: Option<usize>,
-        ban_token_ids: Option<Vec<u32>>,
-        seed: Option<u64>,
-    ) -> Self {
-        Self {
+        options: Option<&Bound<'_, PyDict>>,
+    ) -> PyResult<Self> {
+        let mut config = Self {
             device,
             tokenizer,
             prompt,
-            eos_token,
-            max_new_tokens,
-            temperature,
-            top_p,
-            top_k,
-            ban_token_ids,
-            seed,
+            eos_token: None,
+            max_new_tokens: DEFAULT_MAX_NEW_TOKENS,
+            temperature: None,
+            top_p: None,
+            top_k: None,
+            ban_token_ids: None,
+            seed: None,
+            ban_list: BanList::default(),
+        };
+        config.apply(GenerationOptions::extract("__init__", options)?)?;
+        Ok(config)
+    }
+
+    #[setter]
+    fn set_tokenizer(&mut self, tokenizer: DartTokenizer) {
+        self.tokenizer = tokenizer;
+    }
+
+    #[setter]
+    fn set_eos_token(&mut self, eos_token: Option<u32>) {
+        self.eos_token = eos_token;
+    }
+
+    /// `None` resets it to the default
+    #[setter]
+    fn set_max_new_tokens(&mut self, max_new_tokens: Option<usize>) -> PyResult<()> {
+        let max_new_tokens = max_new_tokens.unwrap_or(DEFAULT_MAX_NEW_TOKENS);
+        if max_new_tokens == 0 {
+            return Err(exceptions::PyValueError::new_err(
+                "max_new_tokens must be at least 1",
+            ));
         }
+        self.max_new_tokens = max_new_tokens;
+        Ok(())
     }
 
-    fn tokenizer(&self) -> DartTokenizer {
-        self.tokenizer.clone()
+    #[setter]
+    fn set_temperature(&mut self, temperature: Option<f64>) -> PyResult<()> {
+        if let Some(temperature) = temperature {
+            if !(temperature > 0.0 && temperature.is_finite()) {
+                return Err(exceptions::PyValueError::new_err(format!(
+                    "temperature must be positive, got {}",
+                    temperature
+                )));
+            }
+        }
+        self.temperature = temperature;
+        Ok(())
     }
 
-    fn prompt(&self) -> &str {
-        &self.prompt
+    #[setter]
+    fn set_top_p(&mut self, top_p: Option<f64>) -> PyResult<()> {
+        if let Some(top_p) = top_p {
+            if !(top_p > 0.0 && top_p <= 1.0) {
+                return Err(exceptions::PyValueError::new_err(format!(
+                    "top_p must be in (0, 1], got {}",
+                    top_p
+                )));
+            }
+        }
+        self.top_p = top_p;
+        Ok(())
     }
 
-    fn max_new_tokens(&self) -> Option<usize> {
-        self.max_new_tokens
+    #[setter]
+    fn set_top_k(&mut self, top_k: Option<usize>) -> PyResult<()> {
+        if top_k == Some(0) {
+            return Err(exceptions::PyValueError::new_err(
+                "top_k must be at least 1",
+            ));
+        }
+        self.top_k = top_k;
+        Ok(())
+    }
+
+    #[getter]
+    fn ban_tags(&self) -> Vec<String> {
+        self.ban_list
+            .patterns()
+            .iter()
+            .map(|pattern| pattern.to_string())
+            .collect()
+    }
+
+    #[setter]
+    fn set_ban_tags(&mut self, ban_tags: Option<Vec<String>>) -> PyResult<()> {
+        let patterns = ban_tags
+            .unwrap_or_default()
+            .iter()
+            .map(|tag| BanPattern::from_str(tag))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| exceptions::PyValueError::new_err(format!("invalid ban tag: {}", e)))?;
+        self.ban_list = BanList::new(patterns);
+        Ok(())
+    }
+
+    /// Returns a copy with the given fields changed, validated like `new`.
+    #[pyo3(signature = (**changes))]
+    fn replace(slf: &Bound<'_, Self>, changes: Option<&Bound<'_, PyDict>>) -> PyResult<Py<Self>> {
+        let config = Bound::new(slf.py(), slf.borrow().clone())?;
+        if let Some(changes) = changes {
+            for (name, value) in changes.iter() {
+                let name = name.extract::<String>()?;
+                if !FIELDS.contains(&name.as_str()) {
+                    return Err(exceptions::PyTypeError::new_err(format!(
+                        "replace() got an unexpected keyword argument '{}'",
+                        name
+                    )));
+                }
+                config.setattr(name.as_str(), value)?;
+            }
+        }
+        config.borrow().check_eos_token()?;
+        Ok(config.unbind())
+    }
+
+    fn __repr__(&self, py: Python<'_>) -> PyResult<String> {
+        let repr = |value: PyObject| -> PyResult<String> { Ok(value.bind(py).repr()?.to_string()) };
+        Ok(format!(
+            "GenerationConfig(device={}, tokenizer={}, prompt={}, eos_token={}, max_new_tokens={}, \
+             temperature={}, top_p={}, top_k={}, ban_token_ids={}, seed={}, ban_tags={})",
+            repr(self.device.clone().into_py(py))?,
+            repr(self.tokenizer.clone().into_py(py))?,
+            repr(self.prompt.to_object(py))?,
+            repr(self.eos_token.to_object(py))?,
+            repr(self.max_new_tokens.to_object(py))?,
+            repr(self.temperature.to_object(py))?,
+            repr(self.top_p.to_object(py))?,
+            repr(self.top_k.to_object(py))?,
+            repr(self.ban_token_ids.to_object(py))?,
+            repr(self.seed.to_object(py))?,
+            repr(self.ban_tags().to_object(py))?,
+        ))
+    }
+
+    fn __eq__(&self, other: PyRef<'_, Self>) -> PyResult<bool> {
+        Ok(self.device == other.device
+            && self.prompt == other.prompt
+            && self.eos_token == other.eos_token
+            && self.max_new_tokens == other.max_new_tokens
+            && self.temperature == other.temperature
+            && self.top_p == other.top_p
+            && self.top_k == other.top_k
+            && self.ban_token_ids == other.ban_token_ids
+            && self.seed == other.seed
+            && self.ban_tags() == other.ban_tags()
+            && self.tokenizer.json()? == other.tokenizer.json()?)
+    }
+
+    // pickled as the arguments of `new` with the options as the state, the tokenizer as its json
+    fn __reduce__(&self, py: Python<'_>) -> PyResult<(PyObject, PyObject, PyObject)> {
+        let args = (
+            self.device.clone(),
+            self.tokenizer.clone(),
+            self.prompt.clone(),
+        );
+        let state = PyDict::new_bound(py);
+        state.set_item("eos_token", self.eos_token)?;
+        state.set_item("max_new_tokens", self.max_new_tokens)?;
+        state.set_item("temperature", self.temperature)?;
+        state.set_item("top_p", self.top_p)?;
+        state.set_item("top_k", self.top_k)?;
+        state.set_item("ban_token_ids", self.ban_token_ids.clone())?;
+        state.set_item("seed", self.seed)?;
+        state.set_item("ban_tags", self.ban_tags())?;
+        Ok((
+            py.get_type_bound::<Self>().into_py(py),
+            args.into_py(py),
+            state.into_py(py),
+        ))
+    }
+
+    fn __setstate__(&mut self, state: &Bound<'_, PyDict>) -> PyResult<()> {
+        self.apply(GenerationOptions::extract("__setstate__", Some(state))?)
+    }
+}
+
+impl DartGenerationConfig {
+    fn apply(&mut self, options: GenerationOptions) -> PyResult<()> {
+        self.eos_token = options.eos_token;
+        self.set_max_new_tokens(options.max_new_tokens)?;
+        self.set_temperature(options.temperature)?;
+        self.set_top_p(options.top_p)?;
+        self.set_top_k(options.top_k)?;
+        self.ban_token_ids = options.ban_token_ids;
+        self.seed = options.seed;
+        self.set_ban_tags(options.ban_tags)?;
+        self.check_eos_token()
+    }
+
+    // `GenerationConfig::new` panics without an eos token
+    fn check_eos_token(&self) -> PyResult<()> {
+        let tokenizer = &self.tokenizer.tokenizer;
+        match self.eos_token {
+            Some(eos_token) if tokenizer.id_to_token(eos_token).is_none() => {
+                Err(exceptions::PyValueError::new_err(format!(
+                    "eos_token {} is not in the vocabulary of the tokenizer",
+                    eos_token
+                )))
+            }
+            None if tokenizer.token_to_id("<|eos|>").is_none() => {
+                Err(exceptions::PyValueError::new_err(
+                    "the tokenizer does not have <|eos|>, pass eos_token",
+                ))
+            }
+            _ => Ok(()),
+        }
     }
 }
 
-#[pyclass(name = "GenerationCache")]
+#[pyclass(module = "dartrs.dartrs", name = "GenerationCache")]
 #[derive(Clone, Debug)]
 pub(crate) struct DartGenerationCache {
     pub input_tokens: Vec<u32>,
@@ -123,6 +388,11 @@ impl DartGenerationCache {
         self.finished = false;
     }
 
+    fn truncate_output_tokens(&mut self, len: usize) {
+        self.output_tokens.truncate(len);
+        self.finished = false;
+    }
+
     fn input_tokens(&self) -> Vec<u32> {
         self.input_tokens.clone()
     }
@@ -134,4 +404,154 @@ impl DartGenerationCache {
     fn finished(&self) -> bool {
         self.finished
     }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "GenerationCache(input_tokens={:?}, output_tokens={:?}, finished={})",
+            self.input_tokens,
+            self.output_tokens,
+            if self.finished { "True" } else { "False" }
+        )
+    }
+
+    fn __eq__(&self, other: PyRef<'_, Self>) -> bool {
+        self.input_tokens == other.input_tokens
+            && self.output_tokens == other.output_tokens
+            && self.finished == other.finished
+    }
+
+    fn __reduce__(&self, py: Python<'_>) -> (PyObject, (Vec<u32>,), PyObject) {
+        let state = (self.output_tokens.clone(), self.finished);
+        (
+            py.get_type_bound::<Self>().into_py(py),
+            (self.input_tokens.clone(),),
+            state.into_py(py),
+        )
+    }
+
+    fn __setstate__(&mut self, state: (Vec<u32>, bool)) {
+        (self.output_tokens, self.finished) = state;
+    }
+
+    fn length_tag(&self, tokenizer: DartTokenizer) -> PyResult<DartLengthTag> {
+        let cache = GenerationCache::from(self.clone());
+        match cache.length_tag(&tokenizer.tokenizer) {
+            Ok(tag) => Ok(DartLengthTag::from(tag)),
+            Err(e) => Err(exceptions::PyValueError::new_err(format!(
+                "Failed to count tags: {}",
+                e
+            ))),
+        }
+    }
+}
+
+/// Stops the generations it is passed to from another thread, which return the tags so far.
+#[pyclass(module = "dartrs.dartrs", name = "CancellationToken")]
+#[derive(Clone, Debug, Default)]
+pub(crate) struct DartCancellationToken {
+    pub(crate) token: CancellationToken,
+}
+
+#[pymethods]
+impl DartCancellationToken {
+    #[new]
+    fn new() -> Self {
+        Self::default()
+    }
+
+    fn cancel(&self) {
+        self.token.cancel();
+    }
+
+    fn cancelled(&self) -> bool {
+        self.token.is_cancelled()
+    }
+}
+
+/// A generation running in a worker thread, stopped at the next token by `cancel`.
+#[pyclass(module = "dartrs.dartrs", name = "GenerationTask")]
+#[derive(Clone, Debug, Default)]
+pub(crate) struct DartGenerationTask {
+    pub(crate) cancellation: CancellationToken,
+}
+
+#[pymethods]
+impl DartGenerationTask {
+    fn cancel(&self) {
+        self.cancellation.cancel();
+    }
+
+    fn cancelled(&self) -> bool {
+        self.cancellation.is_cancelled()
+    }
+}
+
+/// The experts selected in each layer for a generated tag.
+#[pyclass(module = "dartrs.dartrs", name = "TagRouting")]
+#[derive(Clone, Debug)]
+pub(crate) struct DartTagRouting {
+    routing: TagRouting,
+}
+
+impl From<TagRouting> for DartTagRouting {
+    fn from(routing: TagRouting) -> Self {
+        Self { routing }
+    }
+}
+
+#[pymethods]
+impl DartTagRouting {
+    fn tag(&self) -> &str {
+        &self.routing.tag
+    }
+
+    fn token(&self) -> u32 {
+        self.routing.token
+    }
+
+    fn experts(&self) -> Vec<Vec<usize>> {
+        self.routing
+            .layers
+            .iter()
+            .map(|layer| layer.experts.clone())
+            .collect()
+    }
+
+    fn weights(&self) -> Vec<Vec<f32>> {
+        self.routing
+            .layers
+            .iter()
+            .map(|layer| layer.weights.clone())
+            .collect()
+    }
+}
+
+/// The attention weights of a generated tag.
+#[pyclass(module = "dartrs.dartrs", name = "TagAttention")]
+#[derive(Clone, Debug)]
+pub(crate) struct DartTagAttention {
+    attention: TagAttention,
+}
+
+impl From<TagAttention> for DartTagAttention {
+    fn from(attention: TagAttention) -> Self {
+        Self { attention }
+    }
+}
+
+#[pymethods]
+impl DartTagAttention {
+    fn tag(&self) -> &str {
+        &self.attention.tag
+    }
+
+    fn token(&self) -> u32 {
+        self.attention.token
+    }
+
+    fn weights(&self) -> PyResult<Vec<Vec<Vec<f32>>>> {
+        self.attention.weights.to_vec3().map_err(|e| {
+            exceptions::PyValueError::new_err(format!("Failed to read the weights: {}", e))
+        })
+    }
 }
@@ -1,21 +1,36 @@
 use std::collections::HashMap;
-
-use crate::bindings::generation::{DartGenerationCache, DartGenerationConfig};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::ban::BanCache;
+use crate::bindings::generation::{
+    DartCancellationToken, DartGenerationCache, DartGenerationConfig, DartGenerationTask,
+    DartTagAttention, DartTagRouting,
+};
+use crate::generation::attention_weights::AttentionWeightsGeneration;
+use crate::generation::expert_routing::ExpertRoutingGeneration;
+use crate::generation::speculative::SpeculativeGeneration;
 use crate::generation::{GenerationCache, GenerationConfig, TextGeneration};
+use crate::models::lora::{LoraAdapter, LoraModel};
 use crate::models::{
-    mistral, mixtral, MistralModelBuilder, MixtralModelBuilder, ModelBuilder, ModelRepositoy,
+    self, llama, mistral, mixtral, opt, CausalLM, DartModelBuilder, LlamaModelBuilder,
+    MistralModelBuilder, MixtralModelBuilder, ModelBuilder, ModelRepositoy, OptModelBuilder,
 };
 
-use candle_core::{DType, Device};
+use candle_core::{DType, Device, Tensor};
+#[cfg(feature = "hub")]
 use hf_hub::api::sync::ApiBuilder;
-use hf_hub::Repo;
-use hf_hub::RepoType;
 use tokenizers::Tokenizer;
 
 use pyo3::exceptions;
 use pyo3::prelude::*;
+use pyo3::types::PyType;
 
-#[pyclass]
+#[pyclass(module = "dartrs.dartrs")]
 #[derive(Debug, Clone)]
 pub(crate) enum DartDType {
     BF16,
@@ -46,13 +61,23 @@ impl DartDType {
     }
 }
 
-#[pyclass]
-#[derive(Debug, Clone)]
+#[pyclass(module = "dartrs.dartrs")]
+#[derive(Debug, Clone, PartialEq)]
 pub(crate) enum DartDevice {
     Cpu {},
     Cuda { id: usize },
 }
 
+impl DartDevice {
+    /// The name `new` parses, `cpu` or `cuda:<id>`.
+    pub(crate) fn name(&self) -> String {
+        match self {
+            DartDevice::Cpu {} => "cpu".to_string(),
+            DartDevice::Cuda { id } => format!("cuda:{}", id),
+        }
+    }
+}
+
 impl From<DartDevice> for Device {
     fn from(device: DartDevice) -> Self {
         match device {
@@ -70,7 +95,7 @@ impl DartDevice {
     #[new]
     fn new(device: String) -> PyResult<Self> {
         if device.starts_with("cuda") {
-            let id = device[4..].parse().unwrap_or(0);
+            let id = device[4..].trim_start_matches(':').parse().unwrap_or(0);
             Ok(DartDevice::Cuda { id })
         } else if device == "cpu" {
             Ok(DartDevice::Cpu {})
@@ -78,179 +103,696 @@ impl DartDevice {
             Err(exceptions::PyValueError::new_err("invalid device"))
         }
     }
-}
 
-macro_rules! generate {
-    ($self:ident, $config:ident) => {
-        match $self.model.generate(&mut $config) {
-            Ok(text) => Ok(text),
-            Err(e) => Err(exceptions::PyOSError::new_err(format!(
-                "Failed to generate text: {}",
-                e
-            ))),
-        }
-    };
+    fn __repr__(&self) -> String {
+        format!("DartDevice('{}')", self.name())
+    }
+
+    fn __eq__(&self, other: PyRef<'_, Self>) -> bool {
+        *self == *other
+    }
+
+    fn __reduce__(&self, py: Python<'_>) -> (PyObject, (String,)) {
+        (py.get_type_bound::<Self>().into_py(py), (self.name(),))
+    }
 }
 
-#[pyclass]
-pub(crate) struct DartV2Mistral {
-    model: mistral::Model,
+/// Hidden states or embeddings as float32, convertible with `numpy.asarray`.
+#[pyclass(module = "dartrs.dartrs", name = "Embedding")]
+#[derive(Debug, Clone)]
+pub(crate) struct DartEmbedding {
+    data: Vec<f32>,
+    shape: Vec<usize>,
 }
 
-impl From<mistral::Model> for DartV2Mistral {
-    fn from(model: mistral::Model) -> Self {
-        Self { model }
+impl DartEmbedding {
+    fn from_tensor(xs: &Tensor) -> anyhow::Result<Self> {
+        Ok(Self {
+            data: xs.to_dtype(DType::F32)?.flatten_all()?.to_vec1()?,
+            shape: xs.dims().to_vec(),
+        })
     }
 }
 
 #[pymethods]
-impl DartV2Mistral {
-    #[new]
-    fn new(
-        hub_name: String,
-        revision: Option<String>,
-        dtype: Option<DartDType>,
-        device: Option<DartDevice>,
-        auth_token: Option<String>,
-    ) -> PyResult<Self> {
-        let builder = ApiBuilder::default();
-        let builder = builder.with_token(auth_token);
-        let api = match builder.build() {
-            Ok(api) => api,
-            Err(e) => {
-                return Err(exceptions::PyOSError::new_err(format!(
-                    "Failed to create API: {}",
-                    e
-                )))
-            }
-        };
-        let dtype = dtype.unwrap_or(DartDType::FP32);
-        let dtype = DType::from(dtype);
-        let device = device.unwrap_or(DartDevice::Cpu {});
-        let device = Device::from(device);
-
-        let repo = ModelRepositoy::new(hub_name.clone(), api.clone(), revision);
+impl DartEmbedding {
+    fn shape(&self) -> Vec<usize> {
+        self.shape.clone()
+    }
 
-        let model = MistralModelBuilder::load(&repo, dtype, &device);
-        match model {
-            Ok(model) => Ok(Self { model }),
-            Err(e) => Err(exceptions::PyOSError::new_err(format!(
-                "Failed to load model: {}",
-                e
-            ))),
+    fn tolist(&self, py: Python<'_>) -> PyObject {
+        match self.shape.as_slice() {
+            [_, dim] => self
+                .data
+                .chunks(*dim)
+                .map(|row| row.to_vec())
+                .collect::<Vec<_>>()
+                .to_object(py),
+            _ => self.data.to_object(py),
         }
     }
 
-    fn generate(&mut self, config: DartGenerationConfig) -> PyResult<String> {
-        let mut config = GenerationConfig::from(config);
-        generate!(self, config)
+    #[pyo3(signature = (dtype=None, copy=None))]
+    fn __array__(
+        &self,
+        py: Python<'_>,
+        dtype: Option<PyObject>,
+        copy: Option<bool>,
+    ) -> PyResult<PyObject> {
+        // a new array is always created, whatever `copy` is
+        let _ = copy;
+        let numpy = py.import_bound("numpy")?;
+        let array = numpy
+            .call_method1("array", (self.data.clone(), "float32"))?
+            .call_method1("reshape", (self.shape.clone(),))?;
+        let array = match dtype {
+            Some(dtype) => array.call_method1("astype", (dtype,))?,
+            None => array,
+        };
+        Ok(array.unbind())
+    }
+}
+
+// a local directory, or the repository on the hub otherwise
+fn model_repository(
+    name: &str,
+    revision: Option<String>,
+    auth_token: Option<String>,
+) -> PyResult<ModelRepositoy> {
+    if Path::new(name).is_dir() {
+        Ok(ModelRepositoy::local(name))
+    } else {
+        hub_repository(name, revision, auth_token)
+    }
+}
+
+#[cfg(feature = "hub")]
+fn hub_repository(
+    name: &str,
+    revision: Option<String>,
+    auth_token: Option<String>,
+) -> PyResult<ModelRepositoy> {
+    let api = ApiBuilder::default()
+        .with_token(auth_token)
+        .build()
+        .map_err(|e| exceptions::PyOSError::new_err(format!("Failed to create API: {}", e)))?;
+    Ok(ModelRepositoy::new(name.to_string(), api, revision))
+}
+
+#[cfg(not(feature = "hub"))]
+fn hub_repository(
+    name: &str,
+    _revision: Option<String>,
+    _auth_token: Option<String>,
+) -> PyResult<ModelRepositoy> {
+    Err(exceptions::PyOSError::new_err(format!(
+        "{} is not a local directory, and dartrs is built without the hub feature",
+        name
+    )))
+}
+
+fn load_lora_adapter(
+    name: &str,
+    adapter: &str,
+    revision: Option<String>,
+    auth_token: Option<String>,
+) -> PyResult<LoraAdapter> {
+    let repo = model_repository(adapter, revision, auth_token)?;
+    LoraAdapter::from_repo(name, &repo)
+        .map_err(|e| exceptions::PyOSError::new_err(format!("Failed to load lora: {}", e)))
+}
+
+fn lora_error<T>(result: anyhow::Result<T>) -> PyResult<T> {
+    result.map_err(|e| exceptions::PyValueError::new_err(format!("{}", e)))
+}
+
+// a model shared by python threads and the generation workers, the calls wait for each other
+struct SharedModel<M> {
+    model: Arc<Mutex<M>>,
+}
+
+// the models and the speculative generation, which generate text in a worker thread
+trait GenerateWith {
+    fn generate_with(
+        &mut self,
+        config: &mut GenerationConfig,
+        on_token: &mut dyn FnMut(u32) -> bool,
+    ) -> anyhow::Result<String>;
+}
+
+impl<M: TextGeneration> GenerateWith for M {
+    fn generate_with(
+        &mut self,
+        config: &mut GenerationConfig,
+        on_token: &mut dyn FnMut(u32) -> bool,
+    ) -> anyhow::Result<String> {
+        TextGeneration::generate_with(self, config, on_token)
     }
+}
 
-    fn get_next_token(
+impl<D: CausalLM, T: CausalLM> GenerateWith for SpeculativeGeneration<D, T> {
+    fn generate_with(
         &mut self,
-        config: DartGenerationConfig,
-        cache: DartGenerationCache,
-    ) -> PyResult<(u32, DartGenerationCache)> {
-        let mut config = GenerationConfig::from(config);
-        let mut cache = GenerationCache::from(cache);
-        match self.model.get_next_token(&mut config, &mut cache) {
-            Ok(token) => Ok((token, DartGenerationCache::from(cache))),
-            Err(e) => Err(exceptions::PyOSError::new_err(format!(
-                "Failed to get next token: {}",
-                e
-            ))),
+        config: &mut GenerationConfig,
+        on_token: &mut dyn FnMut(u32) -> bool,
+    ) -> anyhow::Result<String> {
+        SpeculativeGeneration::generate_with(self, config, on_token)
+    }
+}
+
+impl<M: Send> SharedModel<M> {
+    fn new(model: M) -> Self {
+        Self {
+            model: Arc::new(Mutex::new(model)),
         }
     }
 
-    fn _clear_kv_cache(&mut self) {
-        self.model.clear_kv_cache();
+    fn lock(&self) -> PyResult<MutexGuard<'_, M>> {
+        lock_model(&self.model)
     }
-}
 
-#[pyclass]
-pub(crate) struct DartV2Mixtral {
-    model: mixtral::Model,
+    // runs `f` on the model without holding the GIL, so that other python threads keep running
+    fn run<T, F>(&self, py: Python<'_>, f: F) -> PyResult<T>
+    where
+        T: Send,
+        F: FnOnce(&mut M) -> PyResult<T> + Send,
+    {
+        py.allow_threads(|| f(&mut *self.lock()?))
+    }
 }
 
-impl From<mixtral::Model> for DartV2Mixtral {
-    fn from(model: mixtral::Model) -> Self {
-        Self { model }
+impl<M: GenerateWith + Send + 'static> SharedModel<M> {
+    // generates in a worker thread, calling `on_token(token)` with each token and then
+    // `on_done(text, error)` from the worker. `on_token` raising stops the generation
+    fn spawn_generate(
+        &self,
+        config: GenerationConfig,
+        on_token: Option<PyObject>,
+        on_done: PyObject,
+    ) -> DartGenerationTask {
+        let task = DartGenerationTask::default();
+        let mut config = config.with_cancellation(task.cancellation.clone());
+        let model = self.model.clone();
+        thread::spawn(move || {
+            let mut on_token = |token: u32| match &on_token {
+                Some(on_token) => Python::with_gil(|py| match on_token.call1(py, (token,)) {
+                    Ok(_) => true,
+                    Err(e) => {
+                        e.write_unraisable_bound(py, None);
+                        false
+                    }
+                }),
+                None => true,
+            };
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                lock_model(&model)?
+                    .generate_with(&mut config, &mut on_token)
+                    .map_err(|e| {
+                        exceptions::PyOSError::new_err(format!("Failed to generate text: {}", e))
+                    })
+            }))
+            .unwrap_or_else(|_| {
+                Err(exceptions::PyRuntimeError::new_err(
+                    "the generation panicked",
+                ))
+            });
+
+            Python::with_gil(|py| {
+                let args = match result {
+                    Ok(text) => (text.into_py(py), py.None()),
+                    Err(e) => (py.None(), e.into_value(py).into_py(py)),
+                };
+                if let Err(e) = on_done.call1(py, args) {
+                    e.write_unraisable_bound(py, None);
+                }
+            });
+        });
+        task
     }
 }
 
-#[pymethods]
-impl DartV2Mixtral {
-    #[new]
-    fn new(
-        hub_name: String,
-        revision: Option<String>,
-        dtype: Option<DartDType>,
-        device: Option<DartDevice>,
-        auth_token: Option<String>,
-    ) -> PyResult<Self> {
-        let builder = ApiBuilder::default();
-        let builder = builder.with_token(auth_token);
-        let api = match builder.build() {
-            Ok(api) => api,
-            Err(e) => {
-                return Err(exceptions::PyOSError::new_err(format!(
-                    "Failed to create API: {}",
-                    e
-                )))
-            }
-        };
-        let dtype = dtype.unwrap_or(DartDType::FP32);
-        let device = device.unwrap_or(DartDevice::Cpu {});
-        let device = Device::from(device);
-        let dtype = DType::from(dtype);
+fn generation_config(
+    config: DartGenerationConfig,
+    cancellation: Option<DartCancellationToken>,
+) -> PyResult<GenerationConfig> {
+    let config = GenerationConfig::try_from(config)?;
+    Ok(match cancellation {
+        Some(cancellation) => config.with_cancellation(cancellation.token),
+        None => config,
+    })
+}
 
-        let repo = ModelRepositoy::new(hub_name.clone(), api.clone(), revision);
+fn lock_model<M>(model: &Mutex<M>) -> PyResult<MutexGuard<'_, M>> {
+    model
+        .lock()
+        .map_err(|_| exceptions::PyRuntimeError::new_err("the model is unusable after a panic"))
+}
+
+// runs `$method` on the input ids and drops the batch dimension
+macro_rules! embed {
+    ($model:ident, $method:ident, $input_ids:ident) => {
+        Tensor::new($input_ids.as_slice(), $model.device())
+            .and_then(|input| $model.$method(&input.unsqueeze(0)?))
+            .map_err(anyhow::Error::from)
+            .and_then(|xs| DartEmbedding::from_tensor(&xs.squeeze(0)?))
+            .map_err(|e| exceptions::PyOSError::new_err(format!("Failed to embed: {}", e)))
+    };
+}
 
-        let model = MixtralModelBuilder::load(&repo, dtype, &device);
-        match model {
-            Ok(model) => Ok(Self { model }),
+macro_rules! generate_with_attention_weights {
+    ($model:ident, $config:ident) => {
+        match $model.generate_with_attention_weights(&mut $config) {
+            Ok((text, tokens, attention)) => Ok((
+                text,
+                tokens,
+                attention.into_iter().map(DartTagAttention::from).collect(),
+            )),
             Err(e) => Err(exceptions::PyOSError::new_err(format!(
-                "Failed to load model: {}",
+                "Failed to generate text: {}",
                 e
             ))),
         }
-    }
-
-    fn generate(&mut self, config: DartGenerationConfig) -> PyResult<String> {
-        let mut config = GenerationConfig::from(config);
-        generate!(self, config)
-    }
+    };
+}
 
-    fn get_next_token(
-        &mut self,
-        config: DartGenerationConfig,
-        cache: DartGenerationCache,
-    ) -> PyResult<(u32, DartGenerationCache)> {
-        let mut config = GenerationConfig::from(config);
-        let mut cache = GenerationCache::from(cache);
-        match self.model.get_next_token(&mut config, &mut cache) {
-            Ok(token) => Ok((token, DartGenerationCache::from(cache))),
+macro_rules! generate_with_expert_routing {
+    ($model:ident, $config:ident) => {
+        match $model.generate_with_expert_routing(&mut $config) {
+            Ok((text, routing)) => Ok((
+                text,
+                routing.into_iter().map(DartTagRouting::from).collect(),
+            )),
             Err(e) => Err(exceptions::PyOSError::new_err(format!(
-                "Failed to get next token: {}",
+                "Failed to generate text: {}",
                 e
             ))),
         }
-    }
+    };
+}
 
-    fn _clear_kv_cache(&mut self) {
-        self.model.clear_kv_cache();
+// generates without the GIL, taking it every `SIGNAL_CHECK_INTERVAL` to check the signals,
+// so that Ctrl-C stops the generation with a KeyboardInterrupt
+fn generate_checking_signals<M: GenerateWith>(
+    model: &mut M,
+    config: &mut GenerationConfig,
+) -> PyResult<String> {
+    let mut checked = Instant::now();
+    let mut interrupt = None;
+    let result = model.generate_with(config, &mut |_| {
+        if checked.elapsed() < SIGNAL_CHECK_INTERVAL {
+            return true;
+        }
+        checked = Instant::now();
+        match Python::with_gil(|py| py.check_signals()) {
+            Ok(()) => true,
+            Err(e) => {
+                interrupt = Some(e);
+                false
+            }
+        }
+    });
+    if let Some(e) = interrupt {
+        return Err(e);
     }
+    result.map_err(|e| exceptions::PyOSError::new_err(format!("Failed to generate text: {}", e)))
+}
+
+const SIGNAL_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+macro_rules! generate {
+    ($model:ident, $config:ident) => {
+        generate_checking_signals($model, &mut $config)
+    };
+}
+
+// defines a pyclass generating with `SharedModel<$model>`. every model has `generate`,
+// `spawn_generate` and `_clear_kv_cache`, the other methods are added by the groups
+// listed in brackets, e.g. `load(MistralModelBuilder)` for the constructor
+macro_rules! py_model {
+    ($(#[$attr:meta])* $name:ident, $model:ty, [$($groups:tt)*]) => {
+        $(#[$attr])*
+        #[pyclass(module = "dartrs.dartrs")]
+        pub(crate) struct $name {
+            model: SharedModel<$model>,
+        }
+
+        impl From<$model> for $name {
+            fn from(model: $model) -> Self {
+                Self {
+                    model: SharedModel::new(model),
+                }
+            }
+        }
+
+        py_model!(@methods $name, [$($groups)*] [
+            /// Generates text, returning the tags generated so far once `cancellation` is cancelled.
+            #[pyo3(signature = (config, cancellation=None))]
+            fn generate(
+                &self,
+                py: Python<'_>,
+                config: DartGenerationConfig,
+                cancellation: Option<DartCancellationToken>,
+            ) -> PyResult<String> {
+                let mut config = generation_config(config, cancellation)?;
+                self.model.run(py, |model| generate!(model, config))
+            }
+
+            /// Generates text in a worker thread, see `GenerationTask`.
+            #[pyo3(signature = (config, on_token, on_done))]
+            fn spawn_generate(
+                &self,
+                config: DartGenerationConfig,
+                on_token: Option<PyObject>,
+                on_done: PyObject,
+            ) -> PyResult<DartGenerationTask> {
+                let config = GenerationConfig::try_from(config)?;
+                Ok(self.model.spawn_generate(config, on_token, on_done))
+            }
+
+            fn _clear_kv_cache(&self, py: Python<'_>) -> PyResult<()> {
+                self.model.run(py, |model| {
+                    model.clear_kv_cache();
+                    Ok(())
+                })
+            }
+        ]);
+    };
+
+    (@methods $name:ident, [] [$($methods:tt)*]) => {
+        #[pymethods]
+        impl $name {
+            $($methods)*
+        }
+    };
+
+    (@methods $name:ident, [load($builder:ident) $(, $($rest:tt)*)?] [$($methods:tt)*]) => {
+        py_model!(@methods $name, [$($($rest)*)?] [$($methods)*
+            #[new]
+            fn new(
+                hub_name: String,
+                revision: Option<String>,
+                dtype: Option<DartDType>,
+                device: Option<DartDevice>,
+                auth_token: Option<String>,
+            ) -> PyResult<Self> {
+                let dtype = dtype.unwrap_or(DartDType::FP32);
+                let device = device.unwrap_or(DartDevice::Cpu {});
+                let device = Device::from(device);
+                let dtype = DType::from(dtype);
+
+                let repo = model_repository(&hub_name, revision, auth_token)?;
+
+                let model = $builder::load(&repo, dtype, &device);
+                match model {
+                    Ok(model) => Ok(Self {
+                        model: SharedModel::new(model),
+                    }),
+                    Err(e) => Err(exceptions::PyOSError::new_err(format!(
+                        "Failed to load model: {}",
+                        e
+                    ))),
+                }
+            }
+        ]);
+    };
+
+    (@methods $name:ident, [next_token $(, $($rest:tt)*)?] [$($methods:tt)*]) => {
+        py_model!(@methods $name, [$($($rest)*)?] [$($methods)*
+            fn get_next_token(
+                &self,
+                py: Python<'_>,
+                config: DartGenerationConfig,
+                cache: DartGenerationCache,
+            ) -> PyResult<(u32, DartGenerationCache)> {
+                let mut config = GenerationConfig::try_from(config)?;
+                let mut cache = GenerationCache::from(cache);
+                let token = self.model.run(py, |model| {
+                    model.get_next_token(&mut config, &mut cache).map_err(|e| {
+                        exceptions::PyOSError::new_err(format!("Failed to get next token: {}", e))
+                    })
+                })?;
+                Ok((token, DartGenerationCache::from(cache)))
+            }
+        ]);
+    };
+
+    (@methods $name:ident, [attention_weights $(, $($rest:tt)*)?] [$($methods:tt)*]) => {
+        py_model!(@methods $name, [$($($rest)*)?] [$($methods)*
+            /// Generates text, returning the prompt and generated tokens and the attention weights
+            /// of every generated tag over them.
+            fn generate_with_attention_weights(
+                &self,
+                py: Python<'_>,
+                config: DartGenerationConfig,
+            ) -> PyResult<(String, Vec<String>, Vec<DartTagAttention>)> {
+                let mut config = GenerationConfig::try_from(config)?;
+                self.model
+                    .run(py, |model| generate_with_attention_weights!(model, config))
+            }
+        ]);
+    };
+
+    (@methods $name:ident, [expert_routing $(, $($rest:tt)*)?] [$($methods:tt)*]) => {
+        py_model!(@methods $name, [$($($rest)*)?] [$($methods)*
+            /// Generates text, returning the experts selected in each layer for every generated tag.
+            fn generate_with_expert_routing(
+                &self,
+                py: Python<'_>,
+                config: DartGenerationConfig,
+            ) -> PyResult<(String, Vec<DartTagRouting>)> {
+                let mut config = GenerationConfig::try_from(config)?;
+                self.model
+                    .run(py, |model| generate_with_expert_routing!(model, config))
+            }
+        ]);
+    };
+
+    (@methods $name:ident, [embeddings $(, $($rest:tt)*)?] [$($methods:tt)*]) => {
+        py_model!(@methods $name, [$($($rest)*)?] [$($methods)*
+            /// The final normed hidden states of every input token, `(seq_len, hidden_size)`.
+            fn hidden_states(
+                &self,
+                py: Python<'_>,
+                input_ids: Vec<u32>,
+            ) -> PyResult<DartEmbedding> {
+                self.model
+                    .run(py, |model| embed!(model, hidden_states, input_ids))
+            }
+
+            /// The mean of the hidden states over the tokens, `(hidden_size,)`.
+            fn pooled_hidden_states(
+                &self,
+                py: Python<'_>,
+                input_ids: Vec<u32>,
+            ) -> PyResult<DartEmbedding> {
+                self.model
+                    .run(py, |model| embed!(model, pooled_hidden_states, input_ids))
+            }
+
+            /// The input embeddings of the tokens, `(seq_len, hidden_size)`.
+            fn token_embeddings(
+                &self,
+                py: Python<'_>,
+                input_ids: Vec<u32>,
+            ) -> PyResult<DartEmbedding> {
+                self.model
+                    .run(py, |model| embed!(model, token_embeddings, input_ids))
+            }
+        ]);
+    };
+
+    (@methods $name:ident, [lora $(, $($rest:tt)*)?] [$($methods:tt)*]) => {
+        py_model!(@methods $name, [$($($rest)*)?] [$($methods)*
+            /// Loads a LoRA adapter from a local directory or the hub, applied with `scale` (1.0 by default).
+            fn load_lora(
+                &self,
+                py: Python<'_>,
+                name: String,
+                adapter: String,
+                scale: Option<f64>,
+                revision: Option<String>,
+                auth_token: Option<String>,
+            ) -> PyResult<()> {
+                let adapter = load_lora_adapter(&name, &adapter, revision, auth_token)?;
+                self.model.run(py, |model| {
+                    // the cached keys and values are of the weights without the adapter
+                    model.clear_kv_cache();
+                    lora_error(model.load_lora(&adapter, scale.unwrap_or(1.0)))
+                })
+            }
+
+            fn set_lora_scale(&self, py: Python<'_>, name: String, scale: f64) -> PyResult<()> {
+                self.model.run(py, |model| {
+                    model.clear_kv_cache();
+                    lora_error(model.set_lora_scale(&name, scale))
+                })
+            }
+
+            fn remove_lora(&self, py: Python<'_>, name: String) -> PyResult<()> {
+                self.model.run(py, |model| {
+                    model.clear_kv_cache();
+                    lora_error(model.remove_lora(&name))
+                })
+            }
+
+            fn merge_lora(&self, py: Python<'_>, name: String) -> PyResult<()> {
+                self.model
+                    .run(py, |model| lora_error(model.merge_lora(&name)))
+            }
+
+            fn lora_adapters(&self, py: Python<'_>) -> PyResult<Vec<(String, f64)>> {
+                self.model
+                    .run(py, |model| lora_error(model.lora_adapters()))
+            }
+        ]);
+    };
+
+    (@methods $name:ident, [architecture $(, $($rest:tt)*)?] [$($methods:tt)*]) => {
+        py_model!(@methods $name, [$($($rest)*)?] [$($methods)*
+            fn architecture(&self, py: Python<'_>) -> PyResult<String> {
+                self.model.run(py, |model| {
+                    Ok(format!("{:?}", model.architecture()).to_lowercase())
+                })
+            }
+        ]);
+    };
+
+    (@methods $name:ident, [speculative $(, $($rest:tt)*)?] [$($methods:tt)*]) => {
+        py_model!(@methods $name, [$($($rest)*)?] [$($methods)*
+            #[new]
+            #[allow(clippy::too_many_arguments)]
+            fn new(
+                draft_hub_name: String,
+                target_hub_name: String,
+                num_draft_tokens: Option<usize>,
+                draft_revision: Option<String>,
+                target_revision: Option<String>,
+                dtype: Option<DartDType>,
+                device: Option<DartDevice>,
+                auth_token: Option<String>,
+            ) -> PyResult<Self> {
+                let dtype = dtype.unwrap_or(DartDType::FP32);
+                let device = device.unwrap_or(DartDevice::Cpu {});
+                let device = Device::from(device);
+                let dtype = DType::from(dtype);
+
+                let draft_repo =
+                    model_repository(&draft_hub_name, draft_revision, auth_token.clone())?;
+                let target_repo = model_repository(&target_hub_name, target_revision, auth_token)?;
+
+                let model = DartModelBuilder::load(&draft_repo, dtype, &device).and_then(|draft| {
+                    let target = DartModelBuilder::load(&target_repo, dtype, &device)?;
+                    SpeculativeGeneration::new(draft, target, num_draft_tokens.unwrap_or(4))
+                });
+                match model {
+                    Ok(model) => Ok(Self {
+                        model: SharedModel::new(model),
+                    }),
+                    Err(e) => Err(exceptions::PyOSError::new_err(format!(
+                        "Failed to load model: {}",
+                        e
+                    ))),
+                }
+            }
+
+            fn get_next_tokens(
+                &self,
+                py: Python<'_>,
+                config: DartGenerationConfig,
+                cache: DartGenerationCache,
+            ) -> PyResult<(Vec<u32>, DartGenerationCache)> {
+                let mut config = GenerationConfig::try_from(config)?;
+                let mut cache = GenerationCache::from(cache);
+                let tokens = self.model.run(py, |model| {
+                    model.get_next_tokens(&mut config, &mut cache).map_err(|e| {
+                        exceptions::PyOSError::new_err(format!("Failed to get next tokens: {}", e))
+                    })
+                })?;
+                Ok((tokens, DartGenerationCache::from(cache)))
+            }
+
+            fn acceptance_rate(&self, py: Python<'_>) -> PyResult<f64> {
+                self.model.run(py, |model| Ok(model.acceptance_rate()))
+            }
+        ]);
+    };
 }
 
-#[pyclass]
+py_model!(
+    DartV2Mistral,
+    mistral::Model,
+    [
+        load(MistralModelBuilder),
+        next_token,
+        attention_weights,
+        embeddings,
+        lora
+    ]
+);
+
+py_model!(
+    DartV2Mixtral,
+    mixtral::Model,
+    [
+        load(MixtralModelBuilder),
+        next_token,
+        attention_weights,
+        expert_routing,
+        embeddings,
+        lora
+    ]
+);
+
+py_model!(
+    DartV2Llama,
+    llama::Model,
+    [load(LlamaModelBuilder), next_token]
+);
+
+py_model!(DartV1Opt, opt::Model, [load(OptModelBuilder), next_token]);
+
+py_model!(
+    /// Loads any supported architecture, chosen by `architectures` in `config.json`.
+    DartModel,
+    models::DartModel,
+    [
+        load(DartModelBuilder),
+        architecture,
+        next_token,
+        attention_weights,
+        expert_routing,
+        embeddings,
+        lora
+    ]
+);
+
+py_model!(
+    /// Speculative decoding with a small draft model and a larger target model.
+    DartSpeculativeModel,
+    SpeculativeGeneration<models::DartModel, models::DartModel>,
+    [speculative]
+);
+
+#[pyclass(module = "dartrs.dartrs")]
 #[derive(Debug, Clone)]
 pub(crate) struct DartTokenizer {
     pub tokenizer: Tokenizer,
+    // the ban lists resolved over this vocabulary, shared by the clones
+    pub ban_cache: BanCache,
 }
 
 impl DartTokenizer {
     fn new(tokenizer: Tokenizer) -> Self {
-        Self { tokenizer }
+        Self {
+            tokenizer,
+            ban_cache: BanCache::new(),
+        }
+    }
+
+    pub(crate) fn json(&self) -> PyResult<String> {
+        self.tokenizer.to_string(false).map_err(|e| {
+            exceptions::PyValueError::new_err(format!("Failed to serialize tokenizer: {}", e))
+        })
     }
 }
 
@@ -269,22 +811,7 @@ impl DartTokenizer {
         revision: String,
         auth_token: Option<String>,
     ) -> PyResult<Self> {
-        let builder = ApiBuilder::default();
-        let builder = builder.with_token(auth_token);
-        let api = match builder.build() {
-            Ok(api) => api,
-            Err(e) => {
-                return Err(exceptions::PyOSError::new_err(format!(
-                    "Failed to create API: {}",
-                    e
-                )))
-            }
-        };
-        let repo = api.repo(Repo::with_revision(
-            identifier.to_string(),
-            RepoType::Model,
-            revision,
-        ));
+        let repo = model_repository(identifier, Some(revision), auth_token)?;
         let tokenizer_json = match repo.get("tokenizer.json") {
             Ok(tokenizer_json) => tokenizer_json,
             Err(e) => {
@@ -301,6 +828,36 @@ impl DartTokenizer {
         Ok(Self::new(tokenizer))
     }
 
+    /// Loads a tokenizer from the content of `tokenizer.json`.
+    #[classmethod]
+    fn from_json(_cls: &Bound<'_, PyType>, json: &str) -> PyResult<Self> {
+        let tokenizer = Tokenizer::from_str(json).map_err(|e| {
+            exceptions::PyValueError::new_err(format!("Failed to load tokenizer: {}", e))
+        })?;
+        Ok(Self::new(tokenizer))
+    }
+
+    fn to_json(&self) -> PyResult<String> {
+        self.json()
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "DartTokenizer(vocab_size={})",
+            self.tokenizer.get_vocab_size(true)
+        )
+    }
+
+    fn __eq__(&self, other: PyRef<'_, Self>) -> PyResult<bool> {
+        Ok(self.json()? == other.json()?)
+    }
+
+    // pickled as its json
+    fn __reduce__(slf: &Bound<'_, Self>) -> PyResult<(PyObject, (String,))> {
+        let from_json = slf.get_type().getattr("from_json")?;
+        Ok((from_json.unbind(), (slf.borrow().json()?,)))
+    }
+
     fn encode(&self, text: String) -> PyResult<Vec<u32>> {
         let encoding = self
             .tokenizer
@@ -1,7 +1,10 @@
-use crate::bindings::tags::{DartAspectRatioTag, DartIdentityTag, DartLengthTag, DartRatingTag};
-use crate::prompt::compose_prompt_v2;
+use crate::bindings::tags::{
+    DartAspectRatioTag, DartIdentityTag, DartLengthTag, DartLengthTagV1, DartRatingTag,
+    DartRatingTagV1,
+};
+use crate::prompt::{compose_prompt_v1, compose_prompt_v2};
 
-use crate::tags::{AspectRatioTag, IdentityTag, LengthTag, RatingTag};
+use crate::tags::{AspectRatioTag, IdentityTag, LengthTag, LengthTagV1, RatingTag, RatingTagV1};
 
 use pyo3::prelude::*;
 
@@ -32,3 +35,18 @@ pub fn dart_compose_prompt_v2(
         do_completion,
     )
 }
+
+#[pyfunction(name = "compose_prompt_v1")]
+pub fn dart_compose_prompt_v1(
+    copyright: &str,
+    character: &str,
+    rating: DartRatingTagV1,
+    length: DartLengthTagV1,
+    prompt: &str,
+    do_completion: bool,
+) -> String {
+    let rating = RatingTagV1::from(rating);
+    let length = LengthTagV1::from(length);
+
+    compose_prompt_v1(copyright, character, rating, length, prompt, do_completion)
+}
@@ -1,9 +1,13 @@
-use crate::tags::{AspectRatioTag, IdentityTag, LengthTag, RatingTag, SpecialTag, Tag};
+use std::str::FromStr;
+
+use crate::tags::{
+    AspectRatioTag, IdentityTag, LengthTag, LengthTagV1, RatingTag, RatingTagV1, SpecialTag, Tag,
+};
 
 use pyo3::exceptions;
 use pyo3::prelude::*;
 
-#[pyclass(name = "LengthTag")]
+#[pyclass(module = "dartrs.dartrs", name = "LengthTag")]
 #[derive(Debug, Clone)]
 pub enum DartLengthTag {
     VeryShort,
@@ -25,26 +29,53 @@ impl From<DartLengthTag> for LengthTag {
     }
 }
 
+impl From<LengthTag> for DartLengthTag {
+    fn from(tag: LengthTag) -> Self {
+        match tag {
+            LengthTag::VeryShort => DartLengthTag::VeryShort,
+            LengthTag::Short => DartLengthTag::Short,
+            LengthTag::Medium => DartLengthTag::Medium,
+            LengthTag::Long => DartLengthTag::Long,
+            LengthTag::VeryLong => DartLengthTag::VeryLong,
+        }
+    }
+}
+
 #[pymethods]
 impl DartLengthTag {
     #[new]
     fn new(tag: &str) -> PyResult<Self> {
-        match tag {
-            "<|length:very_short|>" => Ok(DartLengthTag::VeryShort),
-            "<|length:short|>" => Ok(DartLengthTag::Short),
-            "<|length:medium|>" => Ok(DartLengthTag::Medium),
-            "<|length:long|>" => Ok(DartLengthTag::Long),
-            "<|length:very_long|>" => Ok(DartLengthTag::VeryLong),
-            _ => Err(exceptions::PyValueError::new_err("invalid length tag")),
+        match LengthTag::from_str(tag) {
+            Ok(tag) => Ok(Self::from(tag)),
+            Err(e) => Err(exceptions::PyValueError::new_err(e.to_string())),
         }
     }
 
     fn to_tag(&self) -> String {
         LengthTag::from(self.clone()).to_tag()
     }
+
+    // pickled as the tag, which `new` parses
+    fn __reduce__(&self, py: Python<'_>) -> (PyObject, (String,)) {
+        (py.get_type_bound::<Self>().into_py(py), (self.to_tag(),))
+    }
+
+    #[staticmethod]
+    fn from_tag_count(count: usize) -> Self {
+        Self::from(LengthTag::from_tag_count(count))
+    }
+
+    #[staticmethod]
+    fn from_tags(tags: &str) -> Self {
+        Self::from(LengthTag::from_tags(tags))
+    }
+
+    fn tag_count_range(&self) -> (usize, Option<usize>) {
+        LengthTag::from(self.clone()).tag_count_range()
+    }
 }
 
-#[pyclass(name = "AspectRatioTag")]
+#[pyclass(module = "dartrs.dartrs", name = "AspectRatioTag")]
 #[derive(Debug, Clone)]
 pub enum DartAspectRatioTag {
     UltraWide,
@@ -66,28 +97,51 @@ impl From<DartAspectRatioTag> for AspectRatioTag {
     }
 }
 
+impl From<AspectRatioTag> for DartAspectRatioTag {
+    fn from(tag: AspectRatioTag) -> Self {
+        match tag {
+            AspectRatioTag::UltraWide => DartAspectRatioTag::UltraWide,
+            AspectRatioTag::Wide => DartAspectRatioTag::Wide,
+            AspectRatioTag::Square => DartAspectRatioTag::Square,
+            AspectRatioTag::Tall => DartAspectRatioTag::Tall,
+            AspectRatioTag::UltraTall => DartAspectRatioTag::UltraTall,
+        }
+    }
+}
+
 #[pymethods]
 impl DartAspectRatioTag {
     #[new]
     fn new(tag: &str) -> PyResult<Self> {
-        match tag {
-            "<|aspect_ratio:ultra_wide|>" => Ok(DartAspectRatioTag::UltraWide),
-            "<|aspect_ratio:wide|>" => Ok(DartAspectRatioTag::Wide),
-            "<|aspect_ratio:square|>" => Ok(DartAspectRatioTag::Square),
-            "<|aspect_ratio:tall|>" => Ok(DartAspectRatioTag::Tall),
-            "<|aspect_ratio:ultra_tall|>" => Ok(DartAspectRatioTag::UltraTall),
-            _ => Err(exceptions::PyValueError::new_err(
-                "invalid aspect ratio tag",
-            )),
+        match AspectRatioTag::from_str(tag) {
+            Ok(tag) => Ok(Self::from(tag)),
+            Err(e) => Err(exceptions::PyValueError::new_err(e.to_string())),
         }
     }
 
     fn to_tag(&self) -> String {
         AspectRatioTag::from(self.clone()).to_tag()
     }
+
+    // pickled as the tag, which `new` parses
+    fn __reduce__(&self, py: Python<'_>) -> (PyObject, (String,)) {
+        (py.get_type_bound::<Self>().into_py(py), (self.to_tag(),))
+    }
+
+    #[staticmethod]
+    fn from_resolution(width: u32, height: u32) -> PyResult<Self> {
+        match AspectRatioTag::from_resolution(width, height) {
+            Ok(tag) => Ok(Self::from(tag)),
+            Err(e) => Err(exceptions::PyValueError::new_err(e.to_string())),
+        }
+    }
+
+    fn sdxl_resolution(&self) -> (u32, u32) {
+        AspectRatioTag::from(self.clone()).sdxl_resolution()
+    }
 }
 
-#[pyclass(name = "RatingTag")]
+#[pyclass(module = "dartrs.dartrs", name = "RatingTag")]
 #[derive(Debug, Clone)]
 pub enum DartRatingTag {
     Sfw,
@@ -111,27 +165,40 @@ impl From<DartRatingTag> for RatingTag {
     }
 }
 
+impl From<RatingTag> for DartRatingTag {
+    fn from(tag: RatingTag) -> Self {
+        match tag {
+            RatingTag::Sfw => DartRatingTag::Sfw,
+            RatingTag::General => DartRatingTag::General,
+            RatingTag::Sensitive => DartRatingTag::Sensitive,
+            RatingTag::Nsfw => DartRatingTag::Nsfw,
+            RatingTag::Questionable => DartRatingTag::Questionable,
+            RatingTag::Explicit => DartRatingTag::Explicit,
+        }
+    }
+}
+
 #[pymethods]
 impl DartRatingTag {
     #[new]
     fn new(tag: &str) -> PyResult<Self> {
-        match tag {
-            "<|rating:sfw|>" => Ok(DartRatingTag::Sfw),
-            "<|rating:general|>" => Ok(DartRatingTag::General),
-            "<|rating:sensitive|>" => Ok(DartRatingTag::Sensitive),
-            "<|rating:nsfw|>" => Ok(DartRatingTag::Nsfw),
-            "<|rating:questionable|>" => Ok(DartRatingTag::Questionable),
-            "<|rating:explicit|>" => Ok(DartRatingTag::Explicit),
-            _ => Err(exceptions::PyValueError::new_err("invalid rating tag")),
+        match RatingTag::from_str(tag) {
+            Ok(tag) => Ok(Self::from(tag)),
+            Err(e) => Err(exceptions::PyValueError::new_err(e.to_string())),
         }
     }
 
     fn to_tag(&self) -> String {
         RatingTag::from(self.clone()).to_tag()
     }
+
+    // pickled as the tag, which `new` parses
+    fn __reduce__(&self, py: Python<'_>) -> (PyObject, (String,)) {
+        (py.get_type_bound::<Self>().into_py(py), (self.to_tag(),))
+    }
 }
 
-#[pyclass(name = "IdentityTag")]
+#[pyclass(module = "dartrs.dartrs", name = "IdentityTag")]
 #[derive(Debug, Clone)]
 pub enum DartIdentityTag {
     Free,
@@ -149,24 +216,37 @@ impl From<DartIdentityTag> for IdentityTag {
     }
 }
 
+impl From<IdentityTag> for DartIdentityTag {
+    fn from(tag: IdentityTag) -> Self {
+        match tag {
+            IdentityTag::None => DartIdentityTag::Free,
+            IdentityTag::Lax => DartIdentityTag::Lax,
+            IdentityTag::Strict => DartIdentityTag::Strict,
+        }
+    }
+}
+
 #[pymethods]
 impl DartIdentityTag {
     #[new]
     fn new(tag: &str) -> PyResult<Self> {
-        match tag {
-            "<|identity:none|>" => Ok(DartIdentityTag::Free),
-            "<|identity:lax|>" => Ok(DartIdentityTag::Lax),
-            "<|identity:strict|>" => Ok(DartIdentityTag::Strict),
-            _ => Err(exceptions::PyValueError::new_err("invalid identity tag")),
+        match IdentityTag::from_str(tag) {
+            Ok(tag) => Ok(Self::from(tag)),
+            Err(e) => Err(exceptions::PyValueError::new_err(e.to_string())),
         }
     }
 
     fn to_tag(&self) -> String {
         IdentityTag::from(self.clone()).to_tag()
     }
+
+    // pickled as the tag, which `new` parses
+    fn __reduce__(&self, py: Python<'_>) -> (PyObject, (String,)) {
+        (py.get_type_bound::<Self>().into_py(py), (self.to_tag(),))
+    }
 }
 
-#[pyclass(name = "SpecialTag")]
+#[pyclass(module = "dartrs.dartrs", name = "SpecialTag")]
 #[derive(Debug, Clone)]
 pub enum DartSpecialTag {
     Bos,
@@ -178,6 +258,8 @@ pub enum DartSpecialTag {
     GeneralStart,
     GeneralEnd,
     InputEnd,
+    RatingStart,
+    RatingEnd,
 }
 
 impl From<DartSpecialTag> for SpecialTag {
@@ -192,6 +274,26 @@ impl From<DartSpecialTag> for SpecialTag {
             DartSpecialTag::GeneralStart => SpecialTag::GeneralStart,
             DartSpecialTag::GeneralEnd => SpecialTag::GeneralEnd,
             DartSpecialTag::InputEnd => SpecialTag::InputEnd,
+            DartSpecialTag::RatingStart => SpecialTag::RatingStart,
+            DartSpecialTag::RatingEnd => SpecialTag::RatingEnd,
+        }
+    }
+}
+
+impl From<SpecialTag> for DartSpecialTag {
+    fn from(tag: SpecialTag) -> Self {
+        match tag {
+            SpecialTag::Bos => DartSpecialTag::Bos,
+            SpecialTag::Eos => DartSpecialTag::Eos,
+            SpecialTag::CopyrightStart => DartSpecialTag::CopyrightStart,
+            SpecialTag::CopyrightEnd => DartSpecialTag::CopyrightEnd,
+            SpecialTag::CharacterStart => DartSpecialTag::CharacterStart,
+            SpecialTag::CharacterEnd => DartSpecialTag::CharacterEnd,
+            SpecialTag::GeneralStart => DartSpecialTag::GeneralStart,
+            SpecialTag::GeneralEnd => DartSpecialTag::GeneralEnd,
+            SpecialTag::InputEnd => DartSpecialTag::InputEnd,
+            SpecialTag::RatingStart => DartSpecialTag::RatingStart,
+            SpecialTag::RatingEnd => DartSpecialTag::RatingEnd,
         }
     }
 }
@@ -200,21 +302,127 @@ impl From<DartSpecialTag> for SpecialTag {
 impl DartSpecialTag {
     #[new]
     fn new(tag: &str) -> PyResult<Self> {
-        match tag {
-            "<|bos|>" => Ok(DartSpecialTag::Bos),
-            "<|eos|>" => Ok(DartSpecialTag::Eos),
-            "<copyright>" => Ok(DartSpecialTag::CopyrightStart),
-            "</copyright>" => Ok(DartSpecialTag::CopyrightEnd),
-            "<character>" => Ok(DartSpecialTag::CharacterStart),
-            "</character>" => Ok(DartSpecialTag::CharacterEnd),
-            "<general>" => Ok(DartSpecialTag::GeneralStart),
-            "</general>" => Ok(DartSpecialTag::GeneralEnd),
-            "<|input_end|>" => Ok(DartSpecialTag::InputEnd),
-            _ => Err(exceptions::PyValueError::new_err("invalid reserved tag")),
+        match SpecialTag::from_str(tag) {
+            Ok(tag) => Ok(Self::from(tag)),
+            Err(e) => Err(exceptions::PyValueError::new_err(e.to_string())),
         }
     }
 
     fn to_tag(&self) -> String {
         SpecialTag::from(self.clone()).to_tag()
     }
+
+    // pickled as the tag, which `new` parses
+    fn __reduce__(&self, py: Python<'_>) -> (PyObject, (String,)) {
+        (py.get_type_bound::<Self>().into_py(py), (self.to_tag(),))
+    }
+}
+
+#[pyclass(module = "dartrs.dartrs", name = "RatingTagV1")]
+#[derive(Debug, Clone)]
+pub enum DartRatingTagV1 {
+    Sfw,
+    General,
+    Sensitive,
+    Nsfw,
+    Questionable,
+    Explicit,
+}
+
+impl From<DartRatingTagV1> for RatingTagV1 {
+    fn from(tag: DartRatingTagV1) -> Self {
+        match tag {
+            DartRatingTagV1::Sfw => RatingTagV1::Sfw,
+            DartRatingTagV1::General => RatingTagV1::General,
+            DartRatingTagV1::Sensitive => RatingTagV1::Sensitive,
+            DartRatingTagV1::Nsfw => RatingTagV1::Nsfw,
+            DartRatingTagV1::Questionable => RatingTagV1::Questionable,
+            DartRatingTagV1::Explicit => RatingTagV1::Explicit,
+        }
+    }
+}
+
+impl From<RatingTagV1> for DartRatingTagV1 {
+    fn from(tag: RatingTagV1) -> Self {
+        match tag {
+            RatingTagV1::Sfw => DartRatingTagV1::Sfw,
+            RatingTagV1::General => DartRatingTagV1::General,
+            RatingTagV1::Sensitive => DartRatingTagV1::Sensitive,
+            RatingTagV1::Nsfw => DartRatingTagV1::Nsfw,
+            RatingTagV1::Questionable => DartRatingTagV1::Questionable,
+            RatingTagV1::Explicit => DartRatingTagV1::Explicit,
+        }
+    }
+}
+
+#[pymethods]
+impl DartRatingTagV1 {
+    #[new]
+    fn new(tag: &str) -> PyResult<Self> {
+        match RatingTagV1::from_str(tag) {
+            Ok(tag) => Ok(Self::from(tag)),
+            Err(e) => Err(exceptions::PyValueError::new_err(e.to_string())),
+        }
+    }
+
+    fn to_tag(&self) -> String {
+        RatingTagV1::from(self.clone()).to_tag()
+    }
+
+    // pickled as the name, since the tag is a list of tags
+    fn __reduce__(&self, py: Python<'_>) -> (PyObject, (String,)) {
+        let name = RatingTagV1::from(self.clone()).name().to_string();
+        (py.get_type_bound::<Self>().into_py(py), (name,))
+    }
+}
+
+#[pyclass(module = "dartrs.dartrs", name = "LengthTagV1")]
+#[derive(Debug, Clone)]
+pub enum DartLengthTagV1 {
+    VeryShort,
+    Short,
+    Long,
+    VeryLong,
+}
+
+impl From<DartLengthTagV1> for LengthTagV1 {
+    fn from(tag: DartLengthTagV1) -> Self {
+        match tag {
+            DartLengthTagV1::VeryShort => LengthTagV1::VeryShort,
+            DartLengthTagV1::Short => LengthTagV1::Short,
+            DartLengthTagV1::Long => LengthTagV1::Long,
+            DartLengthTagV1::VeryLong => LengthTagV1::VeryLong,
+        }
+    }
+}
+
+impl From<LengthTagV1> for DartLengthTagV1 {
+    fn from(tag: LengthTagV1) -> Self {
+        match tag {
+            LengthTagV1::VeryShort => DartLengthTagV1::VeryShort,
+            LengthTagV1::Short => DartLengthTagV1::Short,
+            LengthTagV1::Long => DartLengthTagV1::Long,
+            LengthTagV1::VeryLong => DartLengthTagV1::VeryLong,
+        }
+    }
+}
+
+#[pymethods]
+impl DartLengthTagV1 {
+    #[new]
+    fn new(tag: &str) -> PyResult<Self> {
+        match LengthTagV1::from_str(tag) {
+            Ok(tag) => Ok(Self::from(tag)),
+            Err(e) => Err(exceptions::PyValueError::new_err(e.to_string())),
+        }
+    }
+
+    fn to_tag(&self) -> String {
+        LengthTagV1::from(self.clone()).to_tag()
+    }
+
+    // pickled as the tag, which `new` parses
+    fn __reduce__(&self, py: Python<'_>) -> (PyObject, (String,)) {
+        (py.get_type_bound::<Self>().into_py(py), (self.to_tag(),))
+    }
 }
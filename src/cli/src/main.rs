@@ -1,23 +1,35 @@
+use std::ffi::OsStr;
+use std::marker::PhantomData;
+use std::str::FromStr;
+
 use anyhow::Result;
-use clap::{Parser, ValueEnum};
+use clap::builder::{PossibleValue, PossibleValuesParser, TypedValueParser};
+use clap::error::ErrorKind;
+use clap::{Arg, Command, Parser, ValueEnum};
 
 use candle_core::{DType, Device};
 
+#[cfg(feature = "hub")]
 use hf_hub::api::sync::Api;
 
-use dartrs::generation::{GenerationConfig, TextGeneration};
+use dartrs::generation::speculative::SpeculativeGeneration;
+use dartrs::generation::{CancellationToken, GenerationConfig, TextGeneration};
+use dartrs::models::lora::{LoraAdapter, LoraModel};
 use dartrs::models::*;
-use dartrs::prompt::compose_prompt_v2;
-use dartrs::tags::{AspectRatioTag, IdentityTag, LengthTag, RatingTag};
+use dartrs::prompt::{compose_prompt_v1, compose_prompt_v2};
+use dartrs::tags::{AspectRatioTag, IdentityTag, LengthTag, LengthTagV1, RatingTag, RatingTagV1};
 
 #[derive(Debug, Clone, ValueEnum)]
 enum ModelType {
-    // #[clap(name = "llama")]
-    // Llama,
+    #[clap(name = "llama")]
+    Llama,
     #[clap(name = "mistral")]
     Mistral,
     #[clap(name = "mixtral")]
     Mixtral,
+    /// Dart v1, prompted without the aspect ratio and the identity
+    #[clap(name = "opt")]
+    Opt,
 }
 
 #[derive(Debug, Clone, ValueEnum)]
@@ -40,6 +52,42 @@ impl From<DTypeArg> for DType {
     }
 }
 
+/// Parses a tag with `FromStr` and lists the short names of the tags in `--help`,
+/// like a `ValueEnum`. The full special tags are accepted too.
+#[derive(Clone)]
+struct TagParser<T> {
+    names: Vec<&'static str>,
+    tag: PhantomData<T>,
+}
+
+fn tag_parser<T>(variants: &[T], name: fn(&T) -> &'static str) -> TagParser<T> {
+    TagParser {
+        names: variants.iter().map(name).collect(),
+        tag: PhantomData,
+    }
+}
+
+impl<T: FromStr + Clone + Send + Sync + 'static> TypedValueParser for TagParser<T> {
+    type Value = T;
+
+    fn parse_ref(&self, cmd: &Command, arg: Option<&Arg>, value: &OsStr) -> Result<T, clap::Error> {
+        match value.to_str().map(T::from_str) {
+            Some(Ok(tag)) => Ok(tag),
+            // reports the invalid value with the possible values
+            _ => Err(PossibleValuesParser::new(self.names.clone())
+                .parse_ref(cmd, arg, value)
+                .err()
+                .unwrap_or_else(|| clap::Error::new(ErrorKind::InvalidValue).with_cmd(cmd))),
+        }
+    }
+
+    fn possible_values(&self) -> Option<Box<dyn Iterator<Item = PossibleValue> + '_>> {
+        Some(Box::new(
+            self.names.iter().map(|&name| PossibleValue::new(name)),
+        ))
+    }
+}
+
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
@@ -52,22 +100,38 @@ struct Args {
     #[clap(long, default_value = "main")]
     revision: Option<String>,
 
+    /// Small model drafting tags for `--model-name` (speculative decoding)
+    #[clap(long)]
+    draft_model_name: Option<String>,
+
+    /// Number of tags the draft model proposes at once
+    #[clap(long, default_value = "4")]
+    num_draft_tokens: usize,
+
     #[clap(long, default_value = "")]
     copyright: String,
 
     #[clap(long, default_value = "")]
     character: String,
 
-    #[clap(long, value_enum, default_value = "long")]
+    #[clap(long, default_value = "long", value_parser = tag_parser(&LengthTag::VARIANTS, LengthTag::name))]
     length: LengthTag,
 
-    #[clap(long, value_enum, default_value = "tall")]
+    #[clap(long, default_value = "tall", value_parser = tag_parser(&AspectRatioTag::VARIANTS, AspectRatioTag::name))]
     aspect_ratio: AspectRatioTag,
 
-    #[clap(long, value_enum, default_value = "sfw")]
+    /// Image width used to choose the aspect ratio tag instead of `--aspect-ratio`
+    #[clap(long, requires = "height", value_parser = clap::value_parser!(u32).range(1..))]
+    width: Option<u32>,
+
+    /// Image height used to choose the aspect ratio tag instead of `--aspect-ratio`
+    #[clap(long, requires = "width", value_parser = clap::value_parser!(u32).range(1..))]
+    height: Option<u32>,
+
+    #[clap(long, default_value = "sfw", value_parser = tag_parser(&RatingTag::VARIANTS, RatingTag::name))]
     rating: RatingTag,
 
-    #[clap(long, value_enum, default_value = "lax")]
+    #[clap(long, default_value = "lax", value_parser = tag_parser(&IdentityTag::VARIANTS, IdentityTag::name))]
     identity: IdentityTag,
 
     #[clap(long, short, default_value = "")]
@@ -84,6 +148,26 @@ struct Args {
 
     #[clap(long, default_value = "fp32")]
     dtype: DTypeArg,
+
+    /// Use flash-attn, needs `--use-cuda` and the `flash-attn` feature
+    #[clap(long, requires = "use_cuda")]
+    use_flash_attn: bool,
+
+    /// Compute the attention over chunks of this many tokens to save memory
+    #[clap(long)]
+    attention_chunk_size: Option<usize>,
+
+    /// LoRA adapter in the PEFT format, a local directory or a repository on the hub. Can be repeated
+    #[clap(long)]
+    lora: Vec<String>,
+
+    /// Scale of each `--lora` in the same order, 1.0 when omitted
+    #[clap(long)]
+    lora_scale: Vec<f64>,
+
+    /// Merge the LoRA adapters into the base weights instead of applying them at runtime
+    #[clap(long)]
+    merge_lora: bool,
 }
 
 macro_rules! run {
@@ -97,6 +181,42 @@ macro_rules! run {
     };
 }
 
+fn load_loras<M: LoraModel>(
+    model: &mut M,
+    adapters: &[String],
+    scales: &[f64],
+    merge: bool,
+) -> Result<()> {
+    for (i, adapter) in adapters.iter().enumerate() {
+        let repo = repository(adapter, None)?;
+        let lora = LoraAdapter::from_repo(adapter, &repo)?;
+        model.load_lora(&lora, scales.get(i).cloned().unwrap_or(1.0))?;
+        if merge {
+            model.merge_lora(adapter)?;
+        }
+    }
+    Ok(())
+}
+
+// a local directory, or the repository on the hub otherwise
+fn repository(name: &str, revision: Option<String>) -> Result<ModelRepositoy> {
+    if std::path::Path::new(name).is_dir() {
+        return Ok(ModelRepositoy::local(name));
+    }
+    #[cfg(feature = "hub")]
+    {
+        Ok(ModelRepositoy::new(name.to_string(), Api::new()?, revision))
+    }
+    #[cfg(not(feature = "hub"))]
+    {
+        let _ = revision;
+        Err(anyhow::anyhow!(
+            "{} is not a local directory, build with the `hub` feature to download it",
+            name
+        ))
+    }
+}
+
 fn main() -> Result<()> {
     let args = Args::parse();
     println!(
@@ -113,16 +233,26 @@ fn main() -> Result<()> {
     let max_new_tokens = args.max_new_tokens;
     let dtype = DType::from(args.dtype);
 
-    let api = Api::new()?;
-
     let device = match args.use_cuda {
         true => Device::cuda_if_available(0),
         false => Ok(Device::Cpu),
     }?;
 
+    // Ctrl-C stops the generation and prints the tags so far, pressing it again exits
+    let cancellation = CancellationToken::new();
+    ctrlc::set_handler({
+        let cancellation = cancellation.clone();
+        move || {
+            if cancellation.is_cancelled() {
+                std::process::exit(130);
+            }
+            cancellation.cancel();
+        }
+    })?;
+
     let start = std::time::Instant::now();
 
-    let repo = ModelRepositoy::new(model_name.clone(), api.clone(), revision);
+    let repo = repository(&model_name, revision)?;
 
     let tokenizer = repo.load_tokenizer()?;
 
@@ -131,17 +261,33 @@ fn main() -> Result<()> {
     let top_k = Some(100);
     let seed = args.seed;
 
+    let aspect_ratio = match (args.width, args.height) {
+        (Some(width), Some(height)) => AspectRatioTag::from_resolution(width, height)?,
+        _ => args.aspect_ratio,
+    };
+
     // generate text
-    let prompt = compose_prompt_v2(
-        &args.copyright,
-        &args.character,
-        args.rating,
-        args.aspect_ratio,
-        args.length,
-        args.identity,
-        &args.prompt,
-        true,
-    );
+    let prompt = match model_type {
+        // the v1 tags have the same names, without the medium length
+        ModelType::Opt => compose_prompt_v1(
+            &args.copyright,
+            &args.character,
+            RatingTagV1::from_str(args.rating.name())?,
+            LengthTagV1::from_str(args.length.name())?,
+            &args.prompt,
+            true,
+        ),
+        _ => compose_prompt_v2(
+            &args.copyright,
+            &args.character,
+            args.rating,
+            aspect_ratio,
+            args.length,
+            args.identity,
+            &args.prompt,
+            true,
+        ),
+    };
     let mut generation_config = GenerationConfig::new(
         device.clone(),
         tokenizer,
@@ -153,21 +299,76 @@ fn main() -> Result<()> {
         top_k,
         Some(Vec::new()),
         seed,
-    );
+    )
+    .with_cancellation(cancellation.clone());
+
+    if let Some(draft_model_name) = args.draft_model_name {
+        let draft_repo = repository(&draft_model_name, None)?;
+        let draft = DartModelBuilder::new(&draft_repo, dtype, &device)
+            .with_flash_attn(args.use_flash_attn)
+            .with_attention_chunk_size(args.attention_chunk_size)
+            .build()?;
+        let mut target = DartModelBuilder::new(&repo, dtype, &device)
+            .with_flash_attn(args.use_flash_attn)
+            .with_attention_chunk_size(args.attention_chunk_size)
+            .build()?;
+        load_loras(&mut target, &args.lora, &args.lora_scale, args.merge_lora)?;
+        let mut model = SpeculativeGeneration::new(draft, target, args.num_draft_tokens)?;
+        println!("loaded the models in {:?}", start.elapsed());
+
+        let start_gen = std::time::Instant::now();
+        let output = model.generate(&mut generation_config)?;
+        println!("{output}");
+        println!(
+            "generated in {:?} (acceptance rate: {:.2})",
+            start_gen.elapsed(),
+            model.acceptance_rate()
+        );
+        return Ok(());
+    }
 
     match model_type {
         ModelType::Mistral => {
-            let mut model = MistralModelBuilder::load(&repo, dtype, &device)?;
+            let mut model = MistralModelBuilder::new(&repo, dtype, &device)
+                .with_flash_attn(args.use_flash_attn)
+                .with_attention_chunk_size(args.attention_chunk_size)
+                .build()?;
+            load_loras(&mut model, &args.lora, &args.lora_scale, args.merge_lora)?;
             println!("loaded the model in {:?}", start.elapsed());
 
             run!(model, generation_config);
         }
         ModelType::Mixtral => {
-            let mut model = MixtralModelBuilder::load(&repo, dtype, &device)?;
+            let mut model = MixtralModelBuilder::new(&repo, dtype, &device)
+                .with_flash_attn(args.use_flash_attn)
+                .with_attention_chunk_size(args.attention_chunk_size)
+                .build()?;
+            load_loras(&mut model, &args.lora, &args.lora_scale, args.merge_lora)?;
             println!("loaded the model in {:?}", start.elapsed());
 
             run!(model, generation_config);
         }
+        ModelType::Llama | ModelType::Opt => {
+            if !args.lora.is_empty() {
+                anyhow::bail!("--lora is only supported by mistral and mixtral");
+            }
+            if args.use_flash_attn || args.attention_chunk_size.is_some() {
+                anyhow::bail!(
+                    "--use-flash-attn and --attention-chunk-size are only supported by mistral and mixtral"
+                );
+            }
+            if let ModelType::Opt = model_type {
+                let mut model = OptModelBuilder::load(&repo, dtype, &device)?;
+                println!("loaded the model in {:?}", start.elapsed());
+
+                run!(model, generation_config);
+            } else {
+                let mut model = LlamaModelBuilder::load(&repo, dtype, &device)?;
+                println!("loaded the model in {:?}", start.elapsed());
+
+                run!(model, generation_config);
+            }
+        }
     }
 
     Ok(())
@@ -21,6 +21,7 @@ impl DartV2Mistral for mistral::Config {
             rope_theta: 1000.0,
             sliding_window: None,
             use_flash_attn,
+            attention_chunk_size: None,
         }
     }
 }
@@ -46,6 +47,7 @@ impl DartV2Mixtral for mixtral::Config {
             rope_theta: 1000.0,
             sliding_window: None,
             use_flash_attn,
+            attention_chunk_size: None,
         }
     }
 }
@@ -53,6 +55,7 @@ impl DartV2Mixtral for mixtral::Config {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::models::llama;
 
     #[test]
     fn test_mistral_100m() {
@@ -63,4 +66,42 @@ mod tests {
     fn test_mixtral_160m() {
         mixtral::Config::v2_160m(false);
     }
+
+    #[test]
+    fn test_llama_from_json() {
+        let config: llama::Config = serde_json::from_str(
+            r#"{
+                "architectures": ["LlamaForCausalLM"],
+                "vocab_size": 30649,
+                "hidden_size": 768,
+                "intermediate_size": 3072,
+                "num_hidden_layers": 8,
+                "num_attention_heads": 8,
+                "num_key_value_heads": 1,
+                "hidden_act": "silu",
+                "max_position_embeddings": 1024,
+                "rms_norm_eps": 1e-05,
+                "tie_word_embeddings": false
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(config.rope_theta, 10000.0);
+        assert!(!config.use_flash_attn);
+    }
+
+    #[test]
+    fn test_architecture_from_str() {
+        use crate::models::Architecture;
+        use std::str::FromStr;
+
+        assert_eq!(
+            Architecture::from_str("MixtralForCausalLM").unwrap(),
+            Architecture::Mixtral
+        );
+        assert_eq!(
+            Architecture::from_str("OPTForCausalLM").unwrap(),
+            Architecture::Opt
+        );
+        assert!(Architecture::from_str("GPT2LMHeadModel").is_err());
+    }
 }
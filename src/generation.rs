@@ -1,3 +1,10 @@
+pub mod attention_weights;
+pub mod expert_routing;
+pub mod speculative;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
 use anyhow::{Error as E, Result};
 
 use candle_core::{DType, Device, Tensor};
@@ -5,9 +12,13 @@ use candle_transformers::generation::Sampling;
 use rand::Rng;
 use tokenizers::Tokenizer;
 
+use crate::ban::{BanCache, BanList};
 use crate::logits_processor::DartLogitsProcessor;
-use crate::models::{mistral, mixtral};
-use crate::tags::{SpecialTag, Tag};
+use crate::models::CausalLM;
+use crate::tags::{is_special_tag, LengthTag, SpecialTag, Tag};
+
+/// The number of tokens generated at most when `max_new_tokens` is not given
+pub const DEFAULT_MAX_NEW_TOKENS: usize = 256;
 
 pub struct GenerationCache {
     pub input_tokens: Vec<u32>,
@@ -28,6 +39,60 @@ impl GenerationCache {
         self.output_tokens.clear();
         self.finished = false;
     }
+
+    /// Keeps the first `len` output tokens to regenerate the rest.
+    /// The next token reuses the kv cache of the kept tokens.
+    pub fn truncate_output_tokens(&mut self, len: usize) {
+        self.output_tokens.truncate(len);
+        self.finished = false;
+    }
+
+    /// Counts the general tags of both the prompt and the generated tokens.
+    pub fn general_tag_count(&self, tokenizer: &Tokenizer) -> Result<usize> {
+        let general_start = tokenizer
+            .token_to_id(&SpecialTag::GeneralStart.to_tag())
+            .ok_or(E::msg("tokenizer does not have the general tag"))?;
+
+        let mut count = 0;
+        let tokens = self.input_tokens.iter().chain(self.output_tokens.iter());
+        for &token in tokens.skip_while(|&&token| token != general_start).skip(1) {
+            let tag = tokenizer.decode(&[token], false).map_err(E::msg)?;
+            if tag == SpecialTag::GeneralEnd.to_tag() {
+                break;
+            }
+            if !tag.is_empty() && !is_special_tag(&tag) {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    /// The length bucket the prompt and the generated tokens actually fall into.
+    pub fn length_tag(&self, tokenizer: &Tokenizer) -> Result<LengthTag> {
+        let count = self.general_tag_count(tokenizer)?;
+        Ok(LengthTag::from_tag_count(count))
+    }
+}
+
+/// Stops the generations of the configs it is given to, from any thread.
+/// The generation loops check it before each step and return the tags generated so far.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
 }
 
 pub struct GenerationConfig {
@@ -37,6 +102,7 @@ pub struct GenerationConfig {
     eos_token: u32,
     max_new_tokens: usize,
     prompt: String,
+    cancellation: Option<CancellationToken>,
 }
 
 impl GenerationConfig {
@@ -90,7 +156,7 @@ impl GenerationConfig {
         let logits_processor = DartLogitsProcessor::from_sampling(seed, sampling, ban_token_ids);
 
         let eos_token = eos_token.unwrap_or_else(|| tokenizer.token_to_id("<|eos|>").unwrap());
-        let max_new_tokens = max_new_tokens.unwrap_or(256);
+        let max_new_tokens = max_new_tokens.unwrap_or(DEFAULT_MAX_NEW_TOKENS);
 
         Self {
             device,
@@ -99,6 +165,7 @@ impl GenerationConfig {
             eos_token: eos_token.clone(),
             max_new_tokens,
             prompt,
+            cancellation: None,
         }
     }
 
@@ -107,28 +174,216 @@ impl GenerationConfig {
             device, tokenizer, prompt, None, None, None, None, None, None, None,
         )
     }
+
+    /// `max_new_tokens` capped so that the prompt and the generated tokens fit in the context.
+    /// The last generated token is never fed to the model, so it may take one more position.
+    fn max_new_tokens_within(
+        &self,
+        prompt_len: usize,
+        max_position_embeddings: usize,
+    ) -> Result<usize> {
+        if prompt_len > max_position_embeddings {
+            return Err(context_length_error(prompt_len, max_position_embeddings));
+        }
+        Ok(self
+            .max_new_tokens
+            .min(max_position_embeddings - prompt_len + 1))
+    }
+
+    /// Stops the generation at the next step once `token` is cancelled.
+    pub fn with_cancellation(mut self, token: CancellationToken) -> Self {
+        self.cancellation = Some(token);
+        self
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancellation
+            .as_ref()
+            .is_some_and(|token| token.is_cancelled())
+    }
+
+    /// Bans every token matching the patterns of the ban list in addition to `ban_token_ids`.
+    /// The token ids are resolved once per ban list in `cache`, which must be kept with this tokenizer.
+    pub fn with_ban_list(mut self, ban_list: &BanList, cache: &BanCache) -> Result<Self> {
+        if !ban_list.is_empty() {
+            let ban_token_ids = cache.resolve(ban_list, &self.tokenizer)?;
+            self.logits_processor.extend_ban_token_ids(&ban_token_ids);
+        }
+        Ok(self)
+    }
 }
 
-pub trait TextGeneration {
+fn context_length_error(context_len: usize, max_position_embeddings: usize) -> E {
+    E::msg(format!(
+        "the context length {context_len} exceeds max_position_embeddings ({max_position_embeddings})"
+    ))
+}
+
+// joins the generated tags except the special ones
+fn join_tags(tokens: Vec<String>) -> String {
+    tokens
+        .into_iter()
+        .filter(|token| !SpecialTag::is_special(token))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+// keeps the kv cache of up to `max_len` leading tokens that the model has cached,
+// and returns the position to feed `tokens` from
+fn reuse_kv_cache<M: CausalLM + ?Sized>(
+    model: &mut M,
+    tokens: &[u32],
+    max_len: usize,
+) -> Result<usize> {
+    let cached = model.kv_cache_tokens();
+    let start_pos = cached
+        .iter()
+        .zip(tokens.iter())
+        .take_while(|(cached, token)| cached == token)
+        .count()
+        .min(max_len);
+    if start_pos == 0 {
+        model.clear_kv_cache();
+    } else if start_pos < cached.len() {
+        model.truncate_kv_cache(start_pos)?;
+    }
+    Ok(start_pos)
+}
+
+// generates like `generate_tokens`, calling `record` with each token and the position
+// it is predicted from, and keeps the kv cache for the caller to clear
+fn generate_recording<M: TextGeneration + ?Sized, T>(
+    model: &mut M,
+    config: &mut GenerationConfig,
+    mut record: impl FnMut(&mut M, &GenerationConfig, u32, usize) -> Result<T>,
+) -> Result<(GenerationCache, Vec<T>)> {
+    let tokens = config
+        .tokenizer
+        .encode(config.prompt.clone(), false)
+        .map_err(E::msg)?
+        .get_ids()
+        .to_vec();
+
+    let max_new_tokens =
+        config.max_new_tokens_within(tokens.len(), model.max_position_embeddings())?;
+    let mut cache = GenerationCache::new(tokens);
+    let mut records = Vec::new();
+    for _ in 0..max_new_tokens {
+        if config.is_cancelled() {
+            break;
+        }
+        let token = model.get_next_token(config, &mut cache)?;
+        // the token is predicted from the last position fed
+        let position = cache.input_tokens.len() + cache.output_tokens.len() - 2;
+        records.push(record(model, config, token, position)?);
+
+        if cache.finished {
+            break;
+        }
+    }
+    Ok((cache, records))
+}
+
+/// Generation on top of any [`CausalLM`], including `Box<dyn CausalLM>`.
+pub trait TextGeneration: CausalLM {
     fn get_next_token(
         &mut self,
         config: &mut GenerationConfig,
         cache: &mut GenerationCache,
-    ) -> Result<u32>;
+    ) -> Result<u32> {
+        // tokens = input_tokens + output_tokens
+        let tokens: Vec<u32> = cache
+            .input_tokens
+            .iter()
+            .chain(cache.output_tokens.iter())
+            .cloned()
+            .collect();
+        if tokens.len() > self.max_position_embeddings() {
+            return Err(context_length_error(
+                tokens.len(),
+                self.max_position_embeddings(),
+            ));
+        }
 
-    fn decode(&self, config: &mut GenerationConfig, tokens: &[u32]) -> Result<String>;
+        // feed at least one token
+        let start_pos = reuse_kv_cache(self, &tokens, tokens.len().saturating_sub(1))?;
+        let context = &tokens[start_pos..];
+        let input = Tensor::new(context, &config.device)?.unsqueeze(0)?;
+        let input = input.to_device(self.device())?;
+        let logits = self.forward(&input, start_pos)?;
+        let logits = logits.squeeze(0)?.squeeze(0)?.to_dtype(DType::F32)?;
 
-    fn generate_tokens(&mut self, config: &mut GenerationConfig) -> Result<Vec<String>>;
-    fn generate(&mut self, config: &mut GenerationConfig) -> Result<String> {
-        let tokens = self.generate_tokens(config)?;
+        let next_token = config.logits_processor.sample(&logits)?;
+        cache.output_tokens.push(next_token);
+
+        if next_token == config.eos_token {
+            cache.finished = true;
+        }
+
+        Ok(next_token)
+    }
+
+    fn decode(&self, config: &mut GenerationConfig, tokens: &[u32]) -> Result<String> {
+        config.tokenizer.decode(tokens, false).map_err(E::msg)
+    }
+
+    fn generate_tokens(&mut self, config: &mut GenerationConfig) -> Result<Vec<String>> {
+        self.generate_tokens_with(config, &mut |_| true)
+    }
+
+    /// Generates tags like `generate_tokens`, calling `on_token` with each token as it is sampled,
+    /// including the eos token. The generation stops early when `on_token` returns `false`,
+    /// keeping the tokens so far.
+    fn generate_tokens_with(
+        &mut self,
+        config: &mut GenerationConfig,
+        on_token: &mut dyn FnMut(u32) -> bool,
+    ) -> Result<Vec<String>> {
+        let tokens = config
+            .tokenizer
+            .encode(config.prompt.clone(), false)
+            .map_err(E::msg)?
+            .get_ids()
+            .to_vec();
 
-        let text = tokens
-            .into_iter()
-            .filter(|token| !SpecialTag::is_special(token))
-            .collect::<Vec<String>>()
-            .join(", ");
+        // sampling
+        let max_new_tokens =
+            config.max_new_tokens_within(tokens.len(), self.max_position_embeddings())?;
+        let mut cache = GenerationCache::new(tokens);
+        for _ in 0..max_new_tokens {
+            if config.is_cancelled() {
+                break;
+            }
+            let token = self.get_next_token(config, &mut cache)?;
 
-        Ok(text)
+            if !on_token(token) || cache.finished {
+                break;
+            }
+        }
+
+        // clear kv cache
+        self.clear_kv_cache();
+
+        // decode the tokens
+        cache
+            .output_tokens
+            .iter()
+            .map(|&token| self.decode(config, &[token]))
+            .collect::<Result<Vec<String>>>()
+    }
+
+    fn generate(&mut self, config: &mut GenerationConfig) -> Result<String> {
+        self.generate_with(config, &mut |_| true)
+    }
+
+    /// Generates like `generate`, calling `on_token` with each token as `generate_tokens_with`.
+    fn generate_with(
+        &mut self,
+        config: &mut GenerationConfig,
+        on_token: &mut dyn FnMut(u32) -> bool,
+    ) -> Result<String> {
+        let tokens = self.generate_tokens_with(config, on_token)?;
+        Ok(join_tags(tokens))
     }
 
     fn run(&mut self, config: &mut GenerationConfig) -> Result<()> {
@@ -151,8 +406,14 @@ pub trait TextGeneration {
 
         let start_gen = std::time::Instant::now();
         // sampling
+        let max_new_tokens =
+            config.max_new_tokens_within(tokens.len(), self.max_position_embeddings())?;
         let mut cache = GenerationCache::new(tokens);
-        for _ in 0..config.max_new_tokens {
+        for _ in 0..max_new_tokens {
+            if config.is_cancelled() {
+                println!("\ncancelled");
+                break;
+            }
             let token = self.get_next_token(config, &mut cache)?;
             if let Ok(tag) = self.decode(config, &[token]) {
                 print!("{tag}, ");
@@ -165,147 +426,301 @@ pub trait TextGeneration {
         let dt = start_gen.elapsed(); // finish
 
         let generated_tokens = cache.output_tokens.len();
+        let length = cache.length_tag(&config.tokenizer)?;
 
         std::io::stdout().flush()?;
         println!(
             "\n{generated_tokens} tokens generated ({:.2} token/s)",
             generated_tokens as f64 / dt.as_secs_f64(),
         );
+        println!("output length: {}", length.name());
         Ok(())
     }
 }
 
-macro_rules! get_next_token {
-    ($self:ident, $config:ident, $cache:ident) => {{
-        // skip the last token due to the input_end token
-        let context_size = if $cache.output_tokens.is_empty() {
-            $cache.input_tokens.len()
-        } else {
-            1
-        };
-        // tokens = input_tokens + output_tokens
-        let tokens: Vec<u32> = $cache
-            .input_tokens
+impl<T: CausalLM + ?Sized> TextGeneration for T {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::kv_cache::KvCacheTokens;
+    use std::collections::HashMap;
+    use tokenizers::models::wordlevel::WordLevel;
+
+    pub(super) fn tokenizer() -> Tokenizer {
+        let vocab = ["<|bos|>", "1girl", "solo", "cat ears", "<|eos|>"]
             .iter()
-            .chain($cache.output_tokens.iter())
-            .cloned()
-            .collect();
+            .enumerate()
+            .map(|(id, token)| (token.to_string(), id as u32))
+            .collect::<HashMap<_, _>>();
+        let model = WordLevel::builder()
+            .vocab(vocab)
+            .unk_token("<|bos|>".to_string())
+            .build()
+            .unwrap();
+        Tokenizer::new(model)
+    }
 
-        let start_pos = tokens.len().saturating_sub(context_size);
-        let context = &tokens[start_pos..];
-        let input = Tensor::new(context, &$config.device)?.unsqueeze(0)?;
-        let input = input.to_device($self.device())?;
-        let logits = $self.forward(&input, start_pos)?;
-        let logits = logits.squeeze(0)?.squeeze(0)?.to_dtype(DType::F32)?;
+    /// Always predicts `next(last input token)`.
+    pub(super) struct TableModel {
+        pub(super) next: fn(u32) -> u32,
+        pub(super) vocab_size: usize,
+        pub(super) max_position_embeddings: usize,
+        pub(super) device: Device,
+        pub(super) kv_cache_tokens: KvCacheTokens,
+    }
 
-        let next_token = &$config.logits_processor.sample(&logits)?;
-        let next_token = next_token.clone();
-        $cache.output_tokens.push(next_token);
+    impl CausalLM for TableModel {
+        fn forward(
+            &mut self,
+            input_ids: &Tensor,
+            seqlen_offset: usize,
+        ) -> candle_core::Result<Tensor> {
+            let (_b_size, seq_len) = input_ids.dims2()?;
+            self.forward_all(input_ids, seqlen_offset)?
+                .narrow(1, seq_len - 1, 1)
+        }
 
-        if &next_token == &$config.eos_token {
-            $cache.finished = true;
+        fn forward_all(
+            &mut self,
+            input_ids: &Tensor,
+            seqlen_offset: usize,
+        ) -> candle_core::Result<Tensor> {
+            self.kv_cache_tokens.append(input_ids, seqlen_offset)?;
+            let input_ids = input_ids.squeeze(0)?.to_vec1::<u32>()?;
+            let logits = input_ids
+                .iter()
+                .flat_map(|&id| {
+                    let next = (self.next)(id) as usize;
+                    (0..self.vocab_size).map(move |i| if i == next { 1f32 } else { 0f32 })
+                })
+                .collect::<Vec<_>>();
+            Tensor::from_vec(logits, (1, input_ids.len(), self.vocab_size), &self.device)
         }
 
-        Ok(next_token)
-    }};
-}
+        fn clear_kv_cache(&mut self) {
+            self.kv_cache_tokens.clear()
+        }
 
-macro_rules! decode_tokens {
-    ($config:ident, $tokens:ident) => {
-        if let Ok(text) = $config.tokenizer.decode(&$tokens, false) {
-            Ok(text)
-        } else {
-            Err(E::msg("Error in decoding"))
+        fn truncate_kv_cache(&mut self, len: usize) -> candle_core::Result<()> {
+            self.kv_cache_tokens.truncate(len);
+            Ok(())
         }
-    };
-}
 
-impl TextGeneration for mistral::Model {
-    fn get_next_token(
-        &mut self,
-        config: &mut GenerationConfig,
-        cache: &mut GenerationCache,
-    ) -> Result<u32> {
-        get_next_token!(self, config, cache)
+        fn kv_cache_tokens(&self) -> &[u32] {
+            self.kv_cache_tokens.tokens()
+        }
+
+        fn device(&self) -> &Device {
+            &self.device
+        }
+
+        fn dtype(&self) -> DType {
+            DType::F32
+        }
+
+        fn max_position_embeddings(&self) -> usize {
+            self.max_position_embeddings
+        }
     }
 
-    fn decode(&self, config: &mut GenerationConfig, tokens: &[u32]) -> Result<String> {
-        decode_tokens!(config, tokens)
+    #[test]
+    fn test_generate_with_dyn_model() {
+        let mut model: Box<dyn TextGeneration> = Box::new(TableModel {
+            next: |id| id + 1,
+            vocab_size: 5,
+            max_position_embeddings: 1024,
+            device: Device::Cpu,
+            kv_cache_tokens: KvCacheTokens::default(),
+        });
+        let mut config = GenerationConfig::default(Device::Cpu, tokenizer(), "1girl".to_string());
+
+        let output = model.generate(&mut config).unwrap();
+        assert_eq!(output, "solo, cat ears");
     }
 
-    fn generate_tokens(&mut self, config: &mut GenerationConfig) -> Result<Vec<String>> {
-        let tokens = config
-            .tokenizer
-            .encode(config.prompt.clone(), false)
-            .map_err(E::msg)?
-            .get_ids()
-            .to_vec();
+    /// Records what is fed to the model
+    struct RecordingModel {
+        model: TableModel,
+        fed: Vec<(usize, usize)>,
+        truncated: Vec<usize>,
+    }
 
-        // sampling
-        let mut cache = GenerationCache::new(tokens);
-        for _ in 0..config.max_new_tokens {
-            self.get_next_token(config, &mut cache)?;
+    impl CausalLM for RecordingModel {
+        fn forward(
+            &mut self,
+            input_ids: &Tensor,
+            seqlen_offset: usize,
+        ) -> candle_core::Result<Tensor> {
+            self.fed.push((seqlen_offset, input_ids.dim(1)?));
+            self.model.forward(input_ids, seqlen_offset)
+        }
 
-            if cache.finished {
-                break;
-            }
+        fn forward_all(
+            &mut self,
+            input_ids: &Tensor,
+            seqlen_offset: usize,
+        ) -> candle_core::Result<Tensor> {
+            self.model.forward_all(input_ids, seqlen_offset)
         }
 
-        // clear kv cache
-        self.clear_kv_cache();
+        fn clear_kv_cache(&mut self) {
+            self.model.clear_kv_cache()
+        }
 
-        // decode the tokens
-        let decoded = &cache
-            .output_tokens
-            .iter()
-            .map(|&token| self.decode(config, &[token]))
-            .collect::<Result<Vec<String>>>()?;
+        fn truncate_kv_cache(&mut self, len: usize) -> candle_core::Result<()> {
+            self.truncated.push(len);
+            self.model.truncate_kv_cache(len)
+        }
 
-        Ok(decoded.clone())
-    }
-}
+        fn kv_cache_tokens(&self) -> &[u32] {
+            self.model.kv_cache_tokens()
+        }
 
-impl TextGeneration for mixtral::Model {
-    fn get_next_token(
-        &mut self,
-        config: &mut GenerationConfig,
-        cache: &mut GenerationCache,
-    ) -> Result<u32> {
-        get_next_token!(self, config, cache)
+        fn device(&self) -> &Device {
+            self.model.device()
+        }
+
+        fn dtype(&self) -> DType {
+            DType::F32
+        }
+
+        fn max_position_embeddings(&self) -> usize {
+            self.model.max_position_embeddings()
+        }
     }
 
-    fn decode(&self, config: &mut GenerationConfig, tokens: &[u32]) -> Result<String> {
-        decode_tokens!(config, tokens)
+    #[test]
+    fn test_regenerate_reuses_kv_cache() {
+        let mut model = RecordingModel {
+            model: TableModel {
+                next: |id| id + 1,
+                vocab_size: 5,
+                max_position_embeddings: 1024,
+                device: Device::Cpu,
+                kv_cache_tokens: KvCacheTokens::default(),
+            },
+            fed: Vec::new(),
+            truncated: Vec::new(),
+        };
+        let mut config = GenerationConfig::default(Device::Cpu, tokenizer(), "1girl".to_string());
+        let mut cache = GenerationCache::new(vec![0, 1]);
+
+        model.get_next_token(&mut config, &mut cache).unwrap();
+        model.get_next_token(&mut config, &mut cache).unwrap();
+        assert_eq!(cache.output_tokens, vec![2, 3]);
+
+        // delete the last tag and regenerate it
+        cache.truncate_output_tokens(1);
+        let token = model.get_next_token(&mut config, &mut cache).unwrap();
+
+        assert_eq!(token, 3);
+        assert_eq!(model.fed, vec![(0, 2), (2, 1), (2, 1)]);
+        assert_eq!(model.truncated, vec![2]);
     }
 
-    fn generate_tokens(&mut self, config: &mut GenerationConfig) -> Result<Vec<String>> {
-        let tokens = config
-            .tokenizer
-            .encode(config.prompt.clone(), false)
-            .map_err(E::msg)?
-            .get_ids()
-            .to_vec();
+    #[test]
+    fn test_kv_cache_of_another_generation() {
+        let mut model = RecordingModel {
+            model: TableModel {
+                next: |id| id + 1,
+                vocab_size: 5,
+                max_position_embeddings: 1024,
+                device: Device::Cpu,
+                kv_cache_tokens: KvCacheTokens::default(),
+            },
+            fed: Vec::new(),
+            truncated: Vec::new(),
+        };
+        let mut config = GenerationConfig::default(Device::Cpu, tokenizer(), "1girl".to_string());
+        let mut first = GenerationCache::new(vec![0, 1]);
+        let mut second = GenerationCache::new(vec![0, 2]);
 
-        // sampling
-        let mut cache = GenerationCache::new(tokens);
-        for _ in 0..config.max_new_tokens {
-            self.get_next_token(config, &mut cache)?;
+        // the generations only share the first token
+        model.get_next_token(&mut config, &mut first).unwrap();
+        model.get_next_token(&mut config, &mut second).unwrap();
+        model.get_next_token(&mut config, &mut first).unwrap();
+        assert_eq!(model.truncated, vec![1, 1]);
 
-            if cache.finished {
-                break;
-            }
-        }
+        model.clear_kv_cache();
+        model.get_next_token(&mut config, &mut first).unwrap();
 
-        // clear kv cache
-        self.clear_kv_cache();
+        assert_eq!(first.output_tokens, vec![2, 3, 4]);
+        assert_eq!(model.fed, vec![(0, 2), (1, 1), (1, 2), (0, 4)]);
+    }
 
-        // decode the tokens
-        let decoded = &cache
-            .output_tokens
-            .iter()
-            .map(|&token| self.decode(config, &[token]))
-            .collect::<Result<Vec<String>>>()?;
+    #[test]
+    fn test_generate_with() {
+        let mut model = TableModel {
+            next: |id| id + 1,
+            vocab_size: 5,
+            max_position_embeddings: 16,
+            device: Device::Cpu,
+            kv_cache_tokens: KvCacheTokens::default(),
+        };
+        let mut config = GenerationConfig::default(Device::Cpu, tokenizer(), "1girl".to_string());
+
+        let mut tokens = vec![];
+        let output = model
+            .generate_with(&mut config, &mut |token| {
+                tokens.push(token);
+                true
+            })
+            .unwrap();
+        assert_eq!(output, "solo, cat ears");
+        assert_eq!(tokens, vec![2, 3, 4]);
+
+        // stops after the first token
+        let output = model.generate_with(&mut config, &mut |_| false).unwrap();
+        assert_eq!(output, "solo");
+    }
+
+    #[test]
+    fn test_cancellation() {
+        let mut model = TableModel {
+            next: |id| id + 1,
+            vocab_size: 5,
+            max_position_embeddings: 16,
+            device: Device::Cpu,
+            kv_cache_tokens: KvCacheTokens::default(),
+        };
+        let token = CancellationToken::new();
+        let mut config = GenerationConfig::default(Device::Cpu, tokenizer(), "1girl".to_string())
+            .with_cancellation(token.clone());
+
+        // cancelled after the first token, from another thread in practice
+        let output = model
+            .generate_with(&mut config, &mut |_| {
+                token.cancel();
+                true
+            })
+            .unwrap();
+        assert_eq!(output, "solo");
+
+        // stays cancelled
+        assert_eq!(model.generate(&mut config).unwrap(), "");
+    }
+
+    #[test]
+    fn test_max_position_embeddings() {
+        let mut model = TableModel {
+            next: |id| id + 1,
+            vocab_size: 5,
+            max_position_embeddings: 2,
+            device: Device::Cpu,
+            kv_cache_tokens: KvCacheTokens::default(),
+        };
+        let mut config = GenerationConfig::default(Device::Cpu, tokenizer(), "1girl".to_string());
+
+        // the eos token would need the third position
+        let tokens = model.generate_tokens(&mut config).unwrap();
+        assert_eq!(tokens, vec!["solo", "cat ears"]);
 
-        Ok(decoded.clone())
+        let mut cache = GenerationCache::new(vec![0, 1, 2]);
+        let error = model.get_next_token(&mut config, &mut cache).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "the context length 3 exceeds max_position_embeddings (2)"
+        );
     }
 }
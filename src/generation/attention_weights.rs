@@ -0,0 +1,185 @@
+/// Attention weights of the generated tags
+///
+/// Records which prompt and generated tokens each generated tag attended to,
+/// in every layer and head, to explain why a tag was added.
+use anyhow::{Error as E, Result};
+
+use candle_core::Tensor;
+
+use super::{generate_recording, join_tags, GenerationConfig, TextGeneration};
+use crate::models::attention_weights::TokenAttention;
+use crate::models::{mistral, mixtral, DartModel};
+
+/// The attention weights of the forward pass that generated `tag`.
+#[derive(Debug, Clone)]
+pub struct TagAttention {
+    pub tag: String,
+    pub token: u32,
+    /// `(num_layers, num_heads, context_len)`, where the context is the prompt
+    /// followed by the tags generated before this one
+    pub weights: Tensor,
+}
+
+pub trait AttentionWeightsGeneration: TextGeneration {
+    /// Starts or stops recording the attention weights of each token.
+    fn record_attention_weights(&mut self, enabled: bool) -> Result<()>;
+
+    /// Returns the attention weights recorded since the last call.
+    fn take_attention_weights(&mut self) -> Vec<TokenAttention>;
+
+    /// Generates tags like `generate`, returning the attention weights of each generated tag too.
+    /// The tokens are the prompt followed by the generated tags, the positions the weights refer to.
+    fn generate_with_attention_weights(
+        &mut self,
+        config: &mut GenerationConfig,
+    ) -> Result<(String, Vec<String>, Vec<TagAttention>)> {
+        self.record_attention_weights(true)?;
+        let attention = generate_recording(self, config, |model, config, token, position| {
+            let weights = model
+                .take_attention_weights()
+                .into_iter()
+                .rev()
+                .find(|attention| attention.position == position)
+                .map(|attention| attention.weights)
+                .ok_or(E::msg("the attention weights were not recorded"))?;
+            Ok(TagAttention {
+                tag: config.tokenizer.decode(&[token], false).map_err(E::msg)?,
+                token,
+                weights,
+            })
+        });
+        self.record_attention_weights(false)?;
+        self.clear_kv_cache();
+
+        let (cache, attention) = attention?;
+        let tokens = cache
+            .input_tokens
+            .iter()
+            .chain(cache.output_tokens.iter())
+            .map(|&token| config.tokenizer.decode(&[token], false).map_err(E::msg))
+            .collect::<Result<Vec<_>>>()?;
+        let tags = attention
+            .iter()
+            .map(|attention| attention.tag.clone())
+            .collect();
+        Ok((join_tags(tags), tokens, attention))
+    }
+}
+
+impl AttentionWeightsGeneration for mistral::Model {
+    fn record_attention_weights(&mut self, enabled: bool) -> Result<()> {
+        Ok(mistral::Model::record_attention_weights(self, enabled)?)
+    }
+
+    fn take_attention_weights(&mut self) -> Vec<TokenAttention> {
+        mistral::Model::take_attention_weights(self)
+    }
+}
+
+impl AttentionWeightsGeneration for mixtral::Model {
+    fn record_attention_weights(&mut self, enabled: bool) -> Result<()> {
+        Ok(mixtral::Model::record_attention_weights(self, enabled)?)
+    }
+
+    fn take_attention_weights(&mut self) -> Vec<TokenAttention> {
+        mixtral::Model::take_attention_weights(self)
+    }
+}
+
+impl AttentionWeightsGeneration for DartModel {
+    fn record_attention_weights(&mut self, enabled: bool) -> Result<()> {
+        match self {
+            DartModel::Mistral(model) => Ok(model.record_attention_weights(enabled)?),
+            DartModel::Mixtral(model) => Ok(model.record_attention_weights(enabled)?),
+            _ => Err(E::msg(format!(
+                "{:?} does not support recording the attention weights",
+                self.architecture()
+            ))),
+        }
+    }
+
+    fn take_attention_weights(&mut self) -> Vec<TokenAttention> {
+        match self {
+            DartModel::Mistral(model) => model.take_attention_weights(),
+            DartModel::Mixtral(model) => model.take_attention_weights(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generation::tests::tokenizer;
+    use candle_core::{DType, Device, D};
+    use candle_nn::{Activation, VarBuilder, VarMap};
+
+    fn model(sliding_window: Option<usize>) -> mistral::Model {
+        let cfg = mistral::Config {
+            vocab_size: 5,
+            hidden_size: 8,
+            intermediate_size: 16,
+            num_hidden_layers: 2,
+            num_attention_heads: 2,
+            num_key_value_heads: 1,
+            hidden_act: Activation::Silu,
+            max_position_embeddings: 16,
+            rms_norm_eps: 1e-5,
+            rope_theta: 10000.,
+            sliding_window,
+            use_flash_attn: false,
+            attention_chunk_size: None,
+        };
+        let varmap = VarMap::new();
+        let vb = VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu);
+        mistral::Model::new(&cfg, vb).unwrap()
+    }
+
+    #[test]
+    fn test_record_attention_weights() {
+        let mut model = model(Some(2));
+        model.record_attention_weights(true).unwrap();
+        let input = Tensor::new(&[[0u32, 1, 2]], &Device::Cpu).unwrap();
+        model.forward(&input, 0).unwrap();
+        let input = Tensor::new(&[[3u32]], &Device::Cpu).unwrap();
+        model.forward(&input, 3).unwrap();
+
+        let attention = model.take_attention_weights();
+        assert_eq!(attention.len(), 4);
+        for (position, attention) in attention.iter().enumerate() {
+            assert_eq!(attention.position, position);
+            assert_eq!(attention.weights.dims(), &[2, 2, position + 1]);
+            let sums = attention
+                .weights
+                .sum(D::Minus1)
+                .unwrap()
+                .flatten_all()
+                .unwrap();
+            for sum in sums.to_vec1::<f32>().unwrap() {
+                assert!((sum - 1.0).abs() < 1e-5);
+            }
+        }
+        // the position 3 does not attend to the position 0 out of the sliding window
+        let first = attention[3].weights.narrow(2, 0, 1).unwrap();
+        assert_eq!(first.sum_all().unwrap().to_scalar::<f32>().unwrap(), 0.0);
+    }
+
+    #[test]
+    fn test_generate_with_attention_weights() {
+        let mut model = model(None);
+        let mut config = GenerationConfig::default(Device::Cpu, tokenizer(), "1girl".to_string());
+        let expected = model.generate(&mut config).unwrap();
+
+        let mut config = GenerationConfig::default(Device::Cpu, tokenizer(), "1girl".to_string());
+        let (output, tokens, attention) =
+            model.generate_with_attention_weights(&mut config).unwrap();
+
+        assert_eq!(output, expected);
+        assert_eq!(tokens.len(), 1 + attention.len());
+        for (i, attention) in attention.iter().enumerate() {
+            assert_eq!(attention.tag, tokens[i + 1]);
+            assert_eq!(attention.weights.dims(), &[2, 2, i + 1]);
+        }
+        assert!(model.take_attention_weights().is_empty());
+    }
+}
@@ -0,0 +1,159 @@
+/// Expert routing statistics of mixture of experts models
+///
+/// Records which experts were selected in every layer, and with what weight,
+/// for the forward pass that generated each tag.
+use anyhow::{Error as E, Result};
+
+use super::{generate_recording, join_tags, GenerationConfig, TextGeneration};
+use crate::models::mixtral::{self, LayerRouting, TokenRouting};
+use crate::models::DartModel;
+
+/// The routing of the forward pass that generated `tag`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TagRouting {
+    pub tag: String,
+    pub token: u32,
+    /// `layers[i]` is the routing in the i-th layer
+    pub layers: Vec<LayerRouting>,
+}
+
+pub trait ExpertRoutingGeneration: TextGeneration {
+    /// Starts or stops recording the experts each token is routed to.
+    fn record_expert_routing(&mut self, enabled: bool) -> Result<()>;
+
+    /// Returns the routing recorded since the last call.
+    fn take_expert_routing(&mut self) -> Vec<TokenRouting>;
+
+    /// Generates tags like `generate`, returning the routing of each generated tag too.
+    fn generate_with_expert_routing(
+        &mut self,
+        config: &mut GenerationConfig,
+    ) -> Result<(String, Vec<TagRouting>)> {
+        self.record_expert_routing(true)?;
+        let routing = generate_recording(self, config, |model, config, token, position| {
+            let layers = model
+                .take_expert_routing()
+                .into_iter()
+                .rev()
+                .find(|routing| routing.position == position)
+                .map(|routing| routing.layers)
+                .ok_or(E::msg("the expert routing was not recorded"))?;
+            Ok(TagRouting {
+                tag: config.tokenizer.decode(&[token], false).map_err(E::msg)?,
+                token,
+                layers,
+            })
+        });
+        self.record_expert_routing(false)?;
+        self.clear_kv_cache();
+
+        let (_cache, routing) = routing?;
+        let tags = routing.iter().map(|routing| routing.tag.clone()).collect();
+        Ok((join_tags(tags), routing))
+    }
+}
+
+impl ExpertRoutingGeneration for mixtral::Model {
+    fn record_expert_routing(&mut self, enabled: bool) -> Result<()> {
+        mixtral::Model::record_expert_routing(self, enabled);
+        Ok(())
+    }
+
+    fn take_expert_routing(&mut self) -> Vec<TokenRouting> {
+        mixtral::Model::take_expert_routing(self)
+    }
+}
+
+impl ExpertRoutingGeneration for DartModel {
+    fn record_expert_routing(&mut self, enabled: bool) -> Result<()> {
+        match self {
+            DartModel::Mixtral(model) => {
+                model.record_expert_routing(enabled);
+                Ok(())
+            }
+            _ => Err(E::msg(format!(
+                "{:?} is not a mixture of experts model",
+                self.architecture()
+            ))),
+        }
+    }
+
+    fn take_expert_routing(&mut self) -> Vec<TokenRouting> {
+        match self {
+            DartModel::Mixtral(model) => model.take_expert_routing(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generation::tests::tokenizer;
+    use candle_core::{DType, Device, Tensor};
+    use candle_nn::{Activation, VarBuilder, VarMap};
+
+    fn model() -> mixtral::Model {
+        let cfg = mixtral::Config {
+            vocab_size: 5,
+            hidden_size: 8,
+            intermediate_size: 16,
+            num_hidden_layers: 2,
+            num_attention_heads: 2,
+            num_key_value_heads: 1,
+            hidden_act: Activation::Silu,
+            max_position_embeddings: 16,
+            rms_norm_eps: 1e-5,
+            rope_theta: 10000.,
+            sliding_window: None,
+            num_experts_per_tok: 2,
+            num_local_experts: 4,
+            use_flash_attn: false,
+            attention_chunk_size: None,
+        };
+        let varmap = VarMap::new();
+        let vb = VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu);
+        mixtral::Model::new(&cfg, vb).unwrap()
+    }
+
+    #[test]
+    fn test_record_expert_routing() {
+        let mut model = model();
+        let input = Tensor::new(&[[0u32, 1, 2]], &Device::Cpu).unwrap();
+        model.forward(&input, 0).unwrap();
+        assert!(model.take_expert_routing().is_empty());
+
+        model.clear_kv_cache();
+        model.record_expert_routing(true);
+        model.forward(&input, 0).unwrap();
+        let input = Tensor::new(&[[3u32]], &Device::Cpu).unwrap();
+        model.forward(&input, 3).unwrap();
+
+        let routing = model.take_expert_routing();
+        let positions = routing.iter().map(|r| r.position).collect::<Vec<_>>();
+        assert_eq!(positions, vec![0, 1, 2, 3]);
+        for layer in routing.iter().flat_map(|r| r.layers.iter()) {
+            assert_eq!(layer.experts.len(), 2);
+            assert_ne!(layer.experts[0], layer.experts[1]);
+            assert!((layer.weights.iter().sum::<f32>() - 1.0).abs() < 1e-5);
+        }
+        assert!(model.take_expert_routing().is_empty());
+    }
+
+    #[test]
+    fn test_generate_with_expert_routing() {
+        let mut model = model();
+        let mut config = GenerationConfig::default(Device::Cpu, tokenizer(), "1girl".to_string());
+        let expected = model.generate(&mut config).unwrap();
+
+        let mut config = GenerationConfig::default(Device::Cpu, tokenizer(), "1girl".to_string());
+        let (output, routing) = model.generate_with_expert_routing(&mut config).unwrap();
+
+        assert_eq!(output, expected);
+        assert!(!routing.is_empty());
+        assert!(routing.iter().all(|r| r.layers.len() == 2));
+        // recording stops after generation
+        model.generate(&mut config).unwrap();
+        assert!(model.take_expert_routing().is_empty());
+    }
+}
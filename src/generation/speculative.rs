@@ -0,0 +1,386 @@
+/// Speculative decoding
+/// https://arxiv.org/abs/2211.17192
+///
+/// A small draft model proposes a few tags and the target model verifies all of them
+/// in one forward pass. Drafted tokens are accepted with probability `min(1, p / q)`
+/// and a rejected token is resampled from `max(0, p - q)`, so the output follows
+/// the same distribution as sampling from the target model alone.
+use anyhow::{Error as E, Result};
+
+use candle_core::{DType, Tensor};
+
+use super::{context_length_error, join_tags, reuse_kv_cache, GenerationCache, GenerationConfig};
+use crate::logits_processor::DartLogitsProcessor;
+use crate::models::CausalLM;
+
+pub struct SpeculativeGeneration<D, T> {
+    draft: D,
+    target: T,
+    num_draft_tokens: usize,
+    drafted_tokens: usize,
+    accepted_tokens: usize,
+}
+
+impl<D: CausalLM, T: CausalLM> SpeculativeGeneration<D, T> {
+    /// `num_draft_tokens` is the number of tokens the draft model proposes at once.
+    pub fn new(draft: D, target: T, num_draft_tokens: usize) -> Result<Self> {
+        if num_draft_tokens == 0 {
+            return Err(E::msg("num_draft_tokens must be at least 1"));
+        }
+        Ok(Self {
+            draft,
+            target,
+            num_draft_tokens,
+            drafted_tokens: 0,
+            accepted_tokens: 0,
+        })
+    }
+
+    pub fn draft(&self) -> &D {
+        &self.draft
+    }
+
+    pub fn target(&self) -> &T {
+        &self.target
+    }
+
+    /// The ratio of drafted tokens accepted by the target model.
+    pub fn acceptance_rate(&self) -> f64 {
+        if self.drafted_tokens == 0 {
+            0.0
+        } else {
+            self.accepted_tokens as f64 / self.drafted_tokens as f64
+        }
+    }
+
+    /// The positions both models can be fed.
+    pub fn max_position_embeddings(&self) -> usize {
+        self.draft
+            .max_position_embeddings()
+            .min(self.target.max_position_embeddings())
+    }
+
+    pub fn clear_kv_cache(&mut self) {
+        self.draft.clear_kv_cache();
+        self.target.clear_kv_cache();
+    }
+
+    /// Drafts and verifies tokens once, returning the 1 to `num_draft_tokens + 1` accepted tokens.
+    pub fn get_next_tokens(
+        &mut self,
+        config: &mut GenerationConfig,
+        cache: &mut GenerationCache,
+    ) -> Result<Vec<u32>> {
+        let remaining = config
+            .max_new_tokens
+            .saturating_sub(cache.output_tokens.len());
+        if cache.finished || remaining == 0 {
+            return Ok(Vec::new());
+        }
+
+        // tokens = input_tokens + output_tokens + drafted tokens
+        let mut tokens: Vec<u32> = cache
+            .input_tokens
+            .iter()
+            .chain(cache.output_tokens.iter())
+            .cloned()
+            .collect();
+        let context_len = tokens.len();
+        let max_position_embeddings = self.max_position_embeddings();
+        if context_len > max_position_embeddings {
+            return Err(context_length_error(context_len, max_position_embeddings));
+        }
+
+        // draft, the target model is fed the drafted tokens too
+        let num_draft_tokens = self
+            .num_draft_tokens
+            .min(remaining)
+            .min(max_position_embeddings - context_len);
+        let mut draft_prs = Vec::new();
+        for _ in 0..num_draft_tokens {
+            let (_, logits) = forward(
+                &mut self.draft,
+                config,
+                &tokens,
+                tokens.len().saturating_sub(1),
+                false,
+            )?;
+            let prs = config.logits_processor.probabilities(&logits.squeeze(0)?)?;
+            let token = config.logits_processor.sample_probabilities(&prs)?;
+            tokens.push(token);
+            draft_prs.push(prs);
+
+            if token == config.eos_token {
+                break;
+            }
+        }
+        let drafted = tokens[context_len..].to_vec();
+
+        // verify all drafted tokens at once, the logits predicting the first drafted token
+        // come from the last context token
+        let (start_pos, logits) = forward(
+            &mut self.target,
+            config,
+            &tokens,
+            context_len.saturating_sub(1),
+            true,
+        )?;
+        let offset = context_len - start_pos - 1;
+
+        let mut next_tokens = Vec::new();
+        let mut all_accepted = true;
+        for (i, (&token, q)) in drafted.iter().zip(draft_prs.iter()).enumerate() {
+            let p = config
+                .logits_processor
+                .probabilities(&logits.get(offset + i)?)?;
+            let (token, accepted) = accept_or_resample(&mut config.logits_processor, &p, q, token)?;
+            next_tokens.push(token);
+            if !accepted {
+                all_accepted = false;
+                break;
+            }
+            self.accepted_tokens += 1;
+        }
+        self.drafted_tokens += drafted.len();
+
+        // every drafted token is accepted, so the target model gives one more token
+        if all_accepted && drafted.last() != Some(&config.eos_token) {
+            let p = config
+                .logits_processor
+                .probabilities(&logits.get(offset + drafted.len())?)?;
+            next_tokens.push(config.logits_processor.sample_probabilities(&p)?);
+        }
+
+        next_tokens.truncate(remaining);
+        if let Some(eos) = next_tokens.iter().position(|&t| t == config.eos_token) {
+            next_tokens.truncate(eos + 1);
+            cache.finished = true;
+        }
+        cache.output_tokens.extend_from_slice(&next_tokens);
+
+        // the kv cache of the rejected tokens is rolled back when the next tokens are fed
+        Ok(next_tokens)
+    }
+
+    pub fn generate_tokens(&mut self, config: &mut GenerationConfig) -> Result<Vec<String>> {
+        self.generate_tokens_with(config, &mut |_| true)
+    }
+
+    /// Generates tags like `generate_tokens`, calling `on_token` with each accepted token.
+    /// The generation stops early when `on_token` returns `false`, keeping the tokens so far.
+    pub fn generate_tokens_with(
+        &mut self,
+        config: &mut GenerationConfig,
+        on_token: &mut dyn FnMut(u32) -> bool,
+    ) -> Result<Vec<String>> {
+        let tokens = config
+            .tokenizer
+            .encode(config.prompt.clone(), false)
+            .map_err(E::msg)?
+            .get_ids()
+            .to_vec();
+
+        let max_new_tokens =
+            config.max_new_tokens_within(tokens.len(), self.max_position_embeddings())?;
+        let mut cache = GenerationCache::new(tokens);
+        while !cache.finished && cache.output_tokens.len() < max_new_tokens {
+            if config.is_cancelled() {
+                break;
+            }
+            let start = cache.output_tokens.len();
+            let next_tokens = self.get_next_tokens(config, &mut cache)?;
+            if let Some(i) = next_tokens.iter().position(|&token| !on_token(token)) {
+                cache.output_tokens.truncate(start + i + 1);
+                break;
+            }
+        }
+
+        // clear kv cache
+        self.clear_kv_cache();
+
+        cache
+            .output_tokens
+            .iter()
+            .map(|&token| config.tokenizer.decode(&[token], false).map_err(E::msg))
+            .collect::<Result<Vec<String>>>()
+    }
+
+    pub fn generate(&mut self, config: &mut GenerationConfig) -> Result<String> {
+        self.generate_with(config, &mut |_| true)
+    }
+
+    /// Generates like `generate`, calling `on_token` with each token as `generate_tokens_with`.
+    pub fn generate_with(
+        &mut self,
+        config: &mut GenerationConfig,
+        on_token: &mut dyn FnMut(u32) -> bool,
+    ) -> Result<String> {
+        let tokens = self.generate_tokens_with(config, on_token)?;
+        Ok(join_tags(tokens))
+    }
+}
+
+// feeds the tokens the model has not cached, reusing the kv cache of up to `max_cached` tokens.
+// returns the position fed from and the f32 logits of the last position,
+// or of every position fed when `all` is set
+fn forward<M: CausalLM>(
+    model: &mut M,
+    config: &GenerationConfig,
+    tokens: &[u32],
+    max_cached: usize,
+    all: bool,
+) -> Result<(usize, Tensor)> {
+    let start_pos = reuse_kv_cache(model, tokens, max_cached)?;
+    let input = Tensor::new(&tokens[start_pos..], &config.device)?.unsqueeze(0)?;
+    let input = input.to_device(model.device())?;
+    let logits = if all {
+        model.forward_all(&input, start_pos)?
+    } else {
+        model.forward(&input, start_pos)?
+    };
+    Ok((start_pos, logits.squeeze(0)?.to_dtype(DType::F32)?))
+}
+
+// accepts the drafted token with probability min(1, p / q), otherwise resamples from max(0, p - q)
+fn accept_or_resample(
+    logits_processor: &mut DartLogitsProcessor,
+    p: &[f32],
+    q: &[f32],
+    token: u32,
+) -> Result<(u32, bool)> {
+    if p.len() != q.len() {
+        return Err(E::msg(format!(
+            "the draft model has a vocabulary of {} tokens but the target model has {}, \
+             both models must share a tokenizer",
+            q.len(),
+            p.len()
+        )));
+    }
+    let (p_token, q_token) = (p[token as usize], q[token as usize]);
+    if logits_processor.uniform() * q_token < p_token {
+        return Ok((token, true));
+    }
+
+    let residual = p
+        .iter()
+        .zip(q.iter())
+        .map(|(p, q)| (p - q).max(0.0))
+        .collect::<Vec<_>>();
+    let token = if residual.iter().sum::<f32>() > 0.0 {
+        logits_processor.sample_probabilities(&residual)?
+    } else {
+        logits_processor.sample_probabilities(p)?
+    };
+    Ok((token, false))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generation::tests::{tokenizer, TableModel};
+    use crate::generation::{CancellationToken, TextGeneration};
+    use crate::models::kv_cache::KvCacheTokens;
+    use candle_core::Device;
+    use candle_transformers::generation::Sampling;
+
+    fn model(next: fn(u32) -> u32) -> TableModel {
+        TableModel {
+            next,
+            vocab_size: 5,
+            max_position_embeddings: 1024,
+            device: Device::Cpu,
+            kv_cache_tokens: KvCacheTokens::default(),
+        }
+    }
+
+    #[test]
+    fn test_same_output_as_target() {
+        // the target skips "cat ears" after "solo" but the draft does not
+        let target_next = |id| if id == 2 { 4 } else { id + 1 };
+        let mut target = model(target_next);
+        let mut speculative =
+            SpeculativeGeneration::new(model(|id| id + 1), model(target_next), 3).unwrap();
+
+        let prompt = "1girl".to_string();
+        let mut config = GenerationConfig::default(Device::Cpu, tokenizer(), prompt.clone());
+        let expected = target.generate(&mut config).unwrap();
+        let mut config = GenerationConfig::default(Device::Cpu, tokenizer(), prompt);
+        let output = speculative.generate(&mut config).unwrap();
+
+        assert_eq!(output, "solo");
+        assert_eq!(output, expected);
+        assert_eq!(speculative.acceptance_rate(), 1.0 / 3.0);
+    }
+
+    #[test]
+    fn test_generate_with() {
+        let mut speculative =
+            SpeculativeGeneration::new(model(|id| id + 1), model(|id| id + 1), 3).unwrap();
+        let mut config = GenerationConfig::default(Device::Cpu, tokenizer(), "1girl".to_string());
+
+        let mut tokens = vec![];
+        let output = speculative
+            .generate_with(&mut config, &mut |token| {
+                tokens.push(token);
+                true
+            })
+            .unwrap();
+        assert_eq!(output, "solo, cat ears");
+        assert_eq!(tokens, vec![2, 3, 4]);
+
+        // the tokens accepted with the first one are dropped
+        let output = speculative
+            .generate_with(&mut config, &mut |_| false)
+            .unwrap();
+        assert_eq!(output, "solo");
+    }
+
+    #[test]
+    fn test_cancellation() {
+        let mut speculative =
+            SpeculativeGeneration::new(model(|id| id + 1), model(|id| id + 1), 1).unwrap();
+        let token = CancellationToken::new();
+        let mut config = GenerationConfig::default(Device::Cpu, tokenizer(), "1girl".to_string())
+            .with_cancellation(token.clone());
+
+        // the tokens accepted at the cancelled step are kept
+        let output = speculative
+            .generate_with(&mut config, &mut |_| {
+                token.cancel();
+                true
+            })
+            .unwrap();
+        assert_eq!(output, "solo, cat ears");
+    }
+
+    #[test]
+    fn test_vocab_size_mismatch() {
+        let mut target = model(|id| id + 1);
+        target.vocab_size = 4;
+        let mut speculative = SpeculativeGeneration::new(model(|id| id + 1), target, 3).unwrap();
+        let mut config = GenerationConfig::default(Device::Cpu, tokenizer(), "1girl".to_string());
+
+        let err = speculative.generate(&mut config).unwrap_err();
+        assert!(err.to_string().contains("vocabulary"));
+    }
+
+    #[test]
+    fn test_accept_or_resample_distribution() {
+        let sampling = Sampling::All { temperature: 1.0 };
+        let mut logits_processor = DartLogitsProcessor::from_sampling(42, sampling, None);
+        let p = [0.5, 0.3, 0.2];
+        let q = [0.2, 0.2, 0.6];
+
+        let n = 20000;
+        let mut counts = [0; 3];
+        for _ in 0..n {
+            let token = logits_processor.sample_probabilities(&q).unwrap();
+            let (token, _) = accept_or_resample(&mut logits_processor, &p, &q, token).unwrap();
+            counts[token as usize] += 1;
+        }
+
+        for (count, p) in counts.iter().zip(p.iter()) {
+            assert!((*count as f32 / n as f32 - p).abs() < 0.02);
+        }
+    }
+}
@@ -1,3 +1,5 @@
+pub mod ban;
+#[cfg(feature = "python")]
 pub mod bindings;
 pub mod configs;
 pub mod generation;
@@ -6,27 +8,39 @@ pub mod models;
 pub mod prompt;
 pub mod tags;
 
-use bindings::generation::*;
-use bindings::models::*;
-use bindings::prompt::*;
-use bindings::tags::*;
+#[cfg(feature = "python")]
+use bindings::{generation::*, models::*, prompt::*, tags::*};
 
+#[cfg(feature = "python")]
 use pyo3::prelude::*;
 
+#[cfg(feature = "python")]
 #[pymodule]
 fn dartrs(m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<DartDType>()?;
     m.add_class::<DartDevice>()?;
+    m.add_class::<DartEmbedding>()?;
+    m.add_class::<DartModel>()?;
+    m.add_class::<DartSpeculativeModel>()?;
     m.add_class::<DartV2Mistral>()?;
     m.add_class::<DartV2Mixtral>()?;
+    m.add_class::<DartV2Llama>()?;
+    m.add_class::<DartV1Opt>()?;
     m.add_class::<DartTokenizer>()?;
     m.add_class::<DartGenerationConfig>()?;
     m.add_class::<DartGenerationCache>()?;
+    m.add_class::<DartCancellationToken>()?;
+    m.add_class::<DartGenerationTask>()?;
+    m.add_class::<DartTagRouting>()?;
+    m.add_class::<DartTagAttention>()?;
     m.add_class::<DartLengthTag>()?;
     m.add_class::<DartAspectRatioTag>()?;
     m.add_class::<DartRatingTag>()?;
     m.add_class::<DartIdentityTag>()?;
     m.add_class::<DartSpecialTag>()?;
+    m.add_class::<DartRatingTagV1>()?;
+    m.add_class::<DartLengthTagV1>()?;
+    m.add_function(wrap_pyfunction!(dart_compose_prompt_v1, m)?)?;
     m.add_function(wrap_pyfunction!(dart_compose_prompt_v2, m)?)?;
 
     Ok(())
@@ -1,16 +1,23 @@
-use candle_core::{Result, Tensor};
+use candle_core::{DType, Error, Result, Tensor};
 use candle_transformers::generation::{LogitsProcessor, Sampling};
+use rand::{distributions::Distribution, rngs::StdRng, Rng, SeedableRng};
 
 pub struct DartLogitsProcessor {
     logits_processor: LogitsProcessor,
+    sampling: Sampling,
+    // 投機的デコーディングの受理判定とサンプリングに使う乱数生成器
+    rng: StdRng,
     // 出現を禁止するトークンのID (つまり、インデックス) の配列
     ban_token_ids: Vec<u32>,
 }
 
-pub fn ban_tokens(prs: &mut [f32], ban_token_ids: Vec<u32>) {
+pub fn ban_tokens(prs: &mut [f32], ban_token_ids: &[u32]) {
     // トークンのインデックスにある確率を 0 にする
-    for token_id in ban_token_ids {
-        prs[token_id as usize] = 0.0;
+    // 語彙の外の ID (別のトークナイザーのものなど) は無視する
+    for &token_id in ban_token_ids {
+        if let Some(pr) = prs.get_mut(token_id as usize) {
+            *pr = 0.0;
+        }
     }
 }
 
@@ -20,15 +27,134 @@ impl DartLogitsProcessor {
             Some(ban_token_ids) => ban_token_ids,
             None => Vec::new(),
         };
-        let logits_processor = LogitsProcessor::from_sampling(seed, sampling);
+        let logits_processor = LogitsProcessor::from_sampling(seed, sampling.clone());
         Self {
             logits_processor,
+            sampling,
+            rng: StdRng::seed_from_u64(seed),
             ban_token_ids,
         }
     }
 
+    pub fn extend_ban_token_ids(&mut self, ban_token_ids: &[u32]) {
+        self.ban_token_ids.extend_from_slice(ban_token_ids);
+    }
+
     pub fn sample(&mut self, logits: &Tensor) -> Result<u32> {
         self.logits_processor
-            .sample_f(logits, |prs| ban_tokens(prs, self.ban_token_ids.clone()))
+            .sample_f(logits, |prs| ban_tokens(prs, &self.ban_token_ids))
+    }
+
+    /// The normalized distribution `sample` draws from, after the temperature,
+    /// the banned tokens and top-k/top-p are applied.
+    pub fn probabilities(&self, logits: &Tensor) -> Result<Vec<f32>> {
+        let logits = logits.to_dtype(DType::F32)?;
+        let (temperature, top_k, top_p) = match self.sampling {
+            Sampling::ArgMax => {
+                let mut logits = logits.to_vec1::<f32>()?;
+                for &token_id in self.ban_token_ids.iter() {
+                    logits[token_id as usize] = f32::NEG_INFINITY;
+                }
+                let argmax = logits
+                    .iter()
+                    .enumerate()
+                    .max_by(|(_, a), (_, b)| a.total_cmp(b))
+                    .map(|(index, _)| index)
+                    .unwrap_or_default();
+                let mut prs = vec![0.0; logits.len()];
+                prs[argmax] = 1.0;
+                return Ok(prs);
+            }
+            Sampling::All { temperature } => (temperature, None, None),
+            Sampling::TopK { k, temperature } => (temperature, Some(k), None),
+            Sampling::TopP { p, temperature } => (temperature, None, Some(p)),
+            Sampling::TopKThenTopP { k, p, temperature } => (temperature, Some(k), Some(p)),
+            #[allow(unreachable_patterns)]
+            _ => Err(Error::Msg("unsupported sampling method".to_string()))?,
+        };
+
+        let logits = (logits / temperature)?;
+        let mut prs = candle_nn::ops::softmax_last_dim(&logits)?.to_vec1::<f32>()?;
+        ban_tokens(&mut prs, &self.ban_token_ids);
+
+        // LogitsProcessor と同じ順序で top-k、top-p の順に確率を 0 にする
+        if let Some(k) = top_k.filter(|&k| k < prs.len()) {
+            let mut indices = (0..prs.len()).collect::<Vec<_>>();
+            indices.select_nth_unstable_by(k, |&i, &j| prs[j].total_cmp(&prs[i]));
+            for &index in indices[k..].iter() {
+                prs[index] = 0.0;
+            }
+        }
+        if let Some(p) = top_p.filter(|&p| 0.0 < p && p < prs.iter().sum::<f32>() as f64) {
+            let mut indices = (0..prs.len()).collect::<Vec<_>>();
+            indices.sort_by(|&i, &j| prs[j].total_cmp(&prs[i]));
+            let mut cumsum = 0.0;
+            for index in indices {
+                if cumsum >= p as f32 {
+                    prs[index] = 0.0;
+                } else {
+                    cumsum += prs[index];
+                }
+            }
+        }
+
+        let sum = prs.iter().sum::<f32>();
+        if sum <= 0.0 {
+            return Err(Error::Msg("every token is banned".to_string()));
+        }
+        Ok(prs.into_iter().map(|pr| pr / sum).collect())
+    }
+
+    /// Samples a token from a distribution made by `probabilities`.
+    pub fn sample_probabilities(&mut self, prs: &[f32]) -> Result<u32> {
+        let distr = rand::distributions::WeightedIndex::new(prs).map_err(Error::wrap)?;
+        Ok(distr.sample(&mut self.rng) as u32)
+    }
+
+    /// A uniform random number in `[0, 1)`.
+    pub fn uniform(&mut self) -> f32 {
+        self.rng.gen()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::Device;
+
+    #[test]
+    fn test_probabilities_top_k() {
+        let logits = Tensor::new(&[1f32, 4., 3., 2.], &Device::Cpu).unwrap();
+        let sampling = Sampling::TopK {
+            k: 2,
+            temperature: 1.0,
+        };
+        let processor = DartLogitsProcessor::from_sampling(0, sampling, Some(vec![1]));
+
+        let prs = processor.probabilities(&logits).unwrap();
+        assert_eq!(prs[0], 0.0);
+        assert_eq!(prs[1], 0.0);
+        assert!(prs[2] > prs[3] && prs[3] > 0.0);
+        assert!((prs.iter().sum::<f32>() - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_probabilities_argmax() {
+        let logits = Tensor::new(&[1f32, 4., 3., 2.], &Device::Cpu).unwrap();
+        let processor = DartLogitsProcessor::from_sampling(0, Sampling::ArgMax, None);
+
+        let prs = processor.probabilities(&logits).unwrap();
+        assert_eq!(prs, vec![0.0, 1.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn test_ban_tokens_out_of_vocabulary() {
+        let mut prs = vec![0.25f32; 4];
+        ban_tokens(&mut prs, &[1, 4, u32::MAX]);
+        assert_eq!(prs, vec![0.25, 0.0, 0.25, 0.25]);
+
+        let logits = Tensor::new(&[1f32, 4., 3., 2.], &Device::Cpu).unwrap();
+        let mut processor = DartLogitsProcessor::from_sampling(0, Sampling::ArgMax, Some(vec![10]));
+        assert_eq!(processor.sample(&logits).unwrap(), 1);
     }
 }
@@ -1,13 +1,22 @@
+pub mod attention_weights;
+pub mod chunked_attention;
+pub mod kv_cache;
+pub mod llama;
+pub mod lora;
 pub mod mistral;
 pub mod mixtral;
+pub mod opt;
+#[cfg(test)]
+mod test_utils;
 
 use anyhow::{Error as E, Result};
+use std::path::PathBuf;
+use std::str::FromStr;
 
-use crate::configs::*;
-use candle_core::{DType, Device};
+use candle_core::{DType, Device, Tensor};
 use candle_nn::VarBuilder;
-use hf_hub::api::sync::{Api, ApiRepo};
-use hf_hub::{Repo, RepoType};
+#[cfg(feature = "hub")]
+use hf_hub::{api::sync::Api, Repo, RepoType};
 use tokenizers::Tokenizer;
 
 pub trait ModelBuilder<T> {
@@ -16,18 +25,156 @@ pub trait ModelBuilder<T> {
     fn load(repo: &ModelRepositoy, dtype: DType, device: &Device) -> Result<T>;
 }
 
+/// A causal language model that generation can run on.
+///
+/// `forward` returns the logits of the last position and keeps the kv cache
+/// for the next call, `seqlen_offset` being the number of tokens already cached.
+/// `forward_all` returns the logits of every input position instead.
+pub trait CausalLM {
+    fn forward(&mut self, input_ids: &Tensor, seqlen_offset: usize) -> candle_core::Result<Tensor>;
+    fn forward_all(
+        &mut self,
+        input_ids: &Tensor,
+        seqlen_offset: usize,
+    ) -> candle_core::Result<Tensor>;
+    fn clear_kv_cache(&mut self);
+    fn truncate_kv_cache(&mut self, len: usize) -> candle_core::Result<()>;
+    /// The tokens whose keys and values are in the kv cache, empty when they are unknown.
+    /// The generations reuse the kv cache of the leading tokens they share with it.
+    fn kv_cache_tokens(&self) -> &[u32];
+    fn device(&self) -> &Device;
+    fn dtype(&self) -> DType;
+    /// The maximum number of positions the model can be fed.
+    fn max_position_embeddings(&self) -> usize;
+}
+
+macro_rules! impl_causal_lm {
+    ($model:ty) => {
+        impl CausalLM for $model {
+            fn forward(
+                &mut self,
+                input_ids: &Tensor,
+                seqlen_offset: usize,
+            ) -> candle_core::Result<Tensor> {
+                <$model>::forward(self, input_ids, seqlen_offset)
+            }
+
+            fn forward_all(
+                &mut self,
+                input_ids: &Tensor,
+                seqlen_offset: usize,
+            ) -> candle_core::Result<Tensor> {
+                <$model>::forward_all(self, input_ids, seqlen_offset)
+            }
+
+            fn clear_kv_cache(&mut self) {
+                <$model>::clear_kv_cache(self)
+            }
+
+            fn truncate_kv_cache(&mut self, len: usize) -> candle_core::Result<()> {
+                <$model>::truncate_kv_cache(self, len)
+            }
+
+            fn kv_cache_tokens(&self) -> &[u32] {
+                <$model>::kv_cache_tokens(self)
+            }
+
+            fn device(&self) -> &Device {
+                <$model>::device(self)
+            }
+
+            fn dtype(&self) -> DType {
+                <$model>::dtype(self)
+            }
+
+            fn max_position_embeddings(&self) -> usize {
+                <$model>::max_position_embeddings(self)
+            }
+        }
+    };
+}
+
+impl_causal_lm!(mistral::Model);
+impl_causal_lm!(mixtral::Model);
+impl_causal_lm!(llama::Model);
+impl_causal_lm!(opt::Model);
+
+impl<T: CausalLM + ?Sized> CausalLM for Box<T> {
+    fn forward(&mut self, input_ids: &Tensor, seqlen_offset: usize) -> candle_core::Result<Tensor> {
+        (**self).forward(input_ids, seqlen_offset)
+    }
+
+    fn forward_all(
+        &mut self,
+        input_ids: &Tensor,
+        seqlen_offset: usize,
+    ) -> candle_core::Result<Tensor> {
+        (**self).forward_all(input_ids, seqlen_offset)
+    }
+
+    fn clear_kv_cache(&mut self) {
+        (**self).clear_kv_cache()
+    }
+
+    fn truncate_kv_cache(&mut self, len: usize) -> candle_core::Result<()> {
+        (**self).truncate_kv_cache(len)
+    }
+
+    fn kv_cache_tokens(&self) -> &[u32] {
+        (**self).kv_cache_tokens()
+    }
+
+    fn device(&self) -> &Device {
+        (**self).device()
+    }
+
+    fn dtype(&self) -> DType {
+        (**self).dtype()
+    }
+
+    fn max_position_embeddings(&self) -> usize {
+        (**self).max_position_embeddings()
+    }
+}
+
+/// Where the model files are read from.
+///
+/// A local directory never makes network requests. The hub needs the `hub` feature.
+#[derive(Clone)]
 pub struct ModelRepositoy {
     hub_name: String,
-    api: Api,
-    revision: String,
+    source: RepositorySource,
+}
+
+#[derive(Clone)]
+enum RepositorySource {
+    #[cfg(feature = "hub")]
+    Hub {
+        api: Box<Api>,
+        revision: String,
+    },
+    Local(PathBuf),
 }
 
 impl ModelRepositoy {
+    /// The repository `hub_name` on the hub, downloaded on first use and cached.
+    #[cfg(feature = "hub")]
     pub fn new(hub_name: String, api: Api, revision: Option<String>) -> Self {
         Self {
             hub_name,
-            api,
-            revision: revision.unwrap_or("main".to_string()),
+            source: RepositorySource::Hub {
+                api: Box::new(api),
+                revision: revision.unwrap_or("main".to_string()),
+            },
+        }
+    }
+
+    /// A local directory with the same files as the repository on the hub.
+    pub fn local<P: Into<PathBuf>>(dir: P) -> Self {
+        let dir = dir.into();
+        Self {
+            hub_name: dir.display().to_string(),
+            source: RepositorySource::Local(dir),
         }
     }
 
@@ -35,50 +182,101 @@ impl ModelRepositoy {
         self.hub_name.clone()
     }
 
+    /// Returns the path of `filename`, downloading it from the hub if needed.
+    pub fn get(&self, filename: &str) -> Result<PathBuf> {
+        match &self.source {
+            #[cfg(feature = "hub")]
+            RepositorySource::Hub { api, revision } => {
+                let repo = api.repo(Repo::with_revision(
+                    self.hub_name.clone(),
+                    RepoType::Model,
+                    revision.clone(),
+                ));
+                Ok(repo.get(filename)?)
+            }
+            RepositorySource::Local(dir) => {
+                let path = dir.join(filename);
+                if !path.is_file() {
+                    return Err(E::msg(format!("{} does not exist", path.display())));
+                }
+                Ok(path)
+            }
+        }
+    }
+
     pub fn load_tokenizer(&self) -> Result<Tokenizer> {
-        let repo = &self.api.repo(Repo::with_revision(
-            self.hub_name().clone(),
-            RepoType::Model,
-            self.revision.clone(),
-        ));
-        let tokenizer_json = repo.get("tokenizer.json")?;
+        let tokenizer_json = self.get("tokenizer.json")?;
         let tokenizer = Tokenizer::from_file(tokenizer_json).map_err(E::msg)?;
         Ok(tokenizer)
     }
 
-    fn api_repo(&self) -> ApiRepo {
-        self.api.repo(Repo::with_revision(
-            self.hub_name.clone(),
-            RepoType::Model,
-            self.revision.clone(),
-        ))
+    /// Reads `config.json` into the config of a model.
+    pub fn load_config<C: serde::de::DeserializeOwned>(&self) -> Result<C> {
+        let config_path = self.get("config.json")?;
+        let config = std::fs::read_to_string(config_path)?;
+        let config = serde_json::from_str(&config)?;
+        Ok(config)
+    }
+
+    /// Reads the model architecture from `architectures` in `config.json`.
+    pub fn load_architecture(&self) -> Result<Architecture> {
+        #[derive(serde::Deserialize)]
+        struct ArchitectureConfig {
+            architectures: Vec<String>,
+        }
+
+        let config: ArchitectureConfig = self.load_config()?;
+        match config.architectures.first() {
+            Some(architecture) => Architecture::from_str(architecture),
+            None => Err(E::msg("config.json has no architectures")),
+        }
     }
 }
 
-pub struct MistralModelBuilder<T> {
-    repo: ApiRepo,
+pub struct MistralModelBuilder {
+    repo: ModelRepositoy,
     dtype: DType,
     device: Device,
-    config: T,
+    use_flash_attn: bool,
+    attention_chunk_size: Option<usize>,
+}
+
+impl MistralModelBuilder {
+    /// Uses flash-attn, which needs the `flash-attn` feature and a CUDA device.
+    pub fn with_flash_attn(mut self, use_flash_attn: bool) -> Self {
+        self.use_flash_attn = use_flash_attn;
+        self
+    }
+
+    /// Computes the attention over chunks of `chunk_size` keys, see `chunked_attention`.
+    pub fn with_attention_chunk_size(mut self, chunk_size: Option<usize>) -> Self {
+        self.attention_chunk_size = chunk_size;
+        self
+    }
 }
 
-impl ModelBuilder<mistral::Model> for MistralModelBuilder<mistral::Config> {
+impl ModelBuilder<mistral::Model> for MistralModelBuilder {
     fn build(&self) -> Result<mistral::Model> {
+        let config = mistral::Config {
+            use_flash_attn: self.use_flash_attn,
+            attention_chunk_size: self.attention_chunk_size,
+            ..self.repo.load_config()?
+        };
         let model_path = self.repo.get("model.safetensors")?;
         let var_builder = unsafe {
             VarBuilder::from_mmaped_safetensors(&[model_path], self.dtype, &self.device)?
         };
-        let model = mistral::Model::new(&self.config, var_builder)?;
+        let model = mistral::Model::new(&config, var_builder)?;
         Ok(model)
     }
 
     fn new(repo: &ModelRepositoy, dtype: DType, device: &Device) -> Self {
-        let config = mistral::Config::v2_100m(false);
         Self {
-            repo: repo.api_repo(),
+            repo: repo.clone(),
             dtype,
             device: device.clone(),
-            config,
+            use_flash_attn: false,
+            attention_chunk_size: None,
         }
     }
 
@@ -88,30 +286,50 @@ impl ModelBuilder<mistral::Model> for MistralModelBuilder<mistral::Config> {
     }
 }
 
-pub struct MixtralModelBuilder<T> {
-    repo: ApiRepo,
+pub struct MixtralModelBuilder {
+    repo: ModelRepositoy,
     dtype: DType,
     device: Device,
-    config: T,
+    use_flash_attn: bool,
+    attention_chunk_size: Option<usize>,
+}
+
+impl MixtralModelBuilder {
+    /// Uses flash-attn, which needs the `flash-attn` feature and a CUDA device.
+    pub fn with_flash_attn(mut self, use_flash_attn: bool) -> Self {
+        self.use_flash_attn = use_flash_attn;
+        self
+    }
+
+    /// Computes the attention over chunks of `chunk_size` keys, see `chunked_attention`.
+    pub fn with_attention_chunk_size(mut self, chunk_size: Option<usize>) -> Self {
+        self.attention_chunk_size = chunk_size;
+        self
+    }
 }
 
-impl ModelBuilder<mixtral::Model> for MixtralModelBuilder<mixtral::Config> {
+impl ModelBuilder<mixtral::Model> for MixtralModelBuilder {
     fn build(&self) -> Result<mixtral::Model> {
+        let config = mixtral::Config {
+            use_flash_attn: self.use_flash_attn,
+            attention_chunk_size: self.attention_chunk_size,
+            ..self.repo.load_config()?
+        };
         let model_path = self.repo.get("model.safetensors")?;
         let var_builder = unsafe {
             VarBuilder::from_mmaped_safetensors(&[model_path], self.dtype, &self.device)?
         };
-        let model = mixtral::Model::new(&self.config, var_builder)?;
+        let model = mixtral::Model::new(&config, var_builder)?;
         Ok(model)
     }
 
     fn new(repo: &ModelRepositoy, dtype: DType, device: &Device) -> Self {
-        let config = mixtral::Config::v2_160m(false);
         Self {
-            repo: repo.api_repo(),
+            repo: repo.clone(),
             dtype,
             device: device.clone(),
-            config,
+            use_flash_attn: false,
+            attention_chunk_size: None,
         }
     }
 
@@ -120,3 +338,364 @@ impl ModelBuilder<mixtral::Model> for MixtralModelBuilder<mixtral::Config> {
         builder.build()
     }
 }
+
+pub struct LlamaModelBuilder {
+    repo: ModelRepositoy,
+    dtype: DType,
+    device: Device,
+}
+
+impl ModelBuilder<llama::Model> for LlamaModelBuilder {
+    fn build(&self) -> Result<llama::Model> {
+        let config: llama::Config = self.repo.load_config()?;
+        let model_path = self.repo.get("model.safetensors")?;
+        let var_builder = unsafe {
+            VarBuilder::from_mmaped_safetensors(&[model_path], self.dtype, &self.device)?
+        };
+        let model = llama::Model::new(&config, var_builder)?;
+        Ok(model)
+    }
+
+    fn new(repo: &ModelRepositoy, dtype: DType, device: &Device) -> Self {
+        Self {
+            repo: repo.clone(),
+            dtype,
+            device: device.clone(),
+        }
+    }
+
+    fn load(repo: &ModelRepositoy, dtype: DType, device: &Device) -> Result<llama::Model> {
+        let builder = LlamaModelBuilder::new(repo, dtype, device);
+        builder.build()
+    }
+}
+
+pub struct OptModelBuilder {
+    repo: ModelRepositoy,
+    dtype: DType,
+    device: Device,
+}
+
+impl ModelBuilder<opt::Model> for OptModelBuilder {
+    fn build(&self) -> Result<opt::Model> {
+        let config: opt::Config = self.repo.load_config()?;
+        let model_path = self.repo.get("model.safetensors")?;
+        let var_builder = unsafe {
+            VarBuilder::from_mmaped_safetensors(&[model_path], self.dtype, &self.device)?
+        };
+        let model = opt::Model::new(&config, var_builder)?;
+        Ok(model)
+    }
+
+    fn new(repo: &ModelRepositoy, dtype: DType, device: &Device) -> Self {
+        Self {
+            repo: repo.clone(),
+            dtype,
+            device: device.clone(),
+        }
+    }
+
+    fn load(repo: &ModelRepositoy, dtype: DType, device: &Device) -> Result<opt::Model> {
+        let builder = OptModelBuilder::new(repo, dtype, device);
+        builder.build()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Architecture {
+    Mistral,
+    Mixtral,
+    Llama,
+    Opt,
+}
+
+impl FromStr for Architecture {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "MistralForCausalLM" => Ok(Self::Mistral),
+            "MixtralForCausalLM" => Ok(Self::Mixtral),
+            "LlamaForCausalLM" => Ok(Self::Llama),
+            "OPTForCausalLM" => Ok(Self::Opt),
+            _ => Err(E::msg(format!("unsupported architecture: {s}"))),
+        }
+    }
+}
+
+/// A model of any supported architecture.
+#[derive(Debug)]
+pub enum DartModel {
+    Mistral(mistral::Model),
+    Mixtral(mixtral::Model),
+    Llama(llama::Model),
+    Opt(opt::Model),
+}
+
+impl DartModel {
+    pub fn architecture(&self) -> Architecture {
+        match self {
+            Self::Mistral(_) => Architecture::Mistral,
+            Self::Mixtral(_) => Architecture::Mixtral,
+            Self::Llama(_) => Architecture::Llama,
+            Self::Opt(_) => Architecture::Opt,
+        }
+    }
+
+    /// Copies the model with its own kv cache, the weights are shared.
+    pub fn try_clone(&self) -> candle_core::Result<Self> {
+        Ok(match self {
+            Self::Mistral(model) => Self::Mistral(model.try_clone()?),
+            Self::Mixtral(model) => Self::Mixtral(model.try_clone()?),
+            Self::Llama(model) => Self::Llama(model.try_clone()?),
+            Self::Opt(model) => Self::Opt(model.try_clone()?),
+        })
+    }
+
+    /// The final normed hidden states, see [`mistral::Model::hidden_states`].
+    pub fn hidden_states(&mut self, input_ids: &Tensor) -> candle_core::Result<Tensor> {
+        match self {
+            Self::Mistral(model) => model.hidden_states(input_ids),
+            Self::Mixtral(model) => model.hidden_states(input_ids),
+            _ => Err(self.hidden_states_unsupported()),
+        }
+    }
+
+    /// The mean of `hidden_states` over the positions.
+    pub fn pooled_hidden_states(&mut self, input_ids: &Tensor) -> candle_core::Result<Tensor> {
+        match self {
+            Self::Mistral(model) => model.pooled_hidden_states(input_ids),
+            Self::Mixtral(model) => model.pooled_hidden_states(input_ids),
+            _ => Err(self.hidden_states_unsupported()),
+        }
+    }
+
+    /// The input embeddings of the tokens from `embed_tokens`.
+    pub fn token_embeddings(&self, input_ids: &Tensor) -> candle_core::Result<Tensor> {
+        match self {
+            Self::Mistral(model) => model.token_embeddings(input_ids),
+            Self::Mixtral(model) => model.token_embeddings(input_ids),
+            _ => Err(self.hidden_states_unsupported()),
+        }
+    }
+
+    fn hidden_states_unsupported(&self) -> candle_core::Error {
+        candle_core::Error::Msg(format!(
+            "{:?} does not support extracting hidden states",
+            self.architecture()
+        ))
+    }
+
+    fn as_causal_lm(&self) -> &dyn CausalLM {
+        match self {
+            Self::Mistral(model) => model,
+            Self::Mixtral(model) => model,
+            Self::Llama(model) => model,
+            Self::Opt(model) => model,
+        }
+    }
+
+    fn as_causal_lm_mut(&mut self) -> &mut dyn CausalLM {
+        match self {
+            Self::Mistral(model) => model,
+            Self::Mixtral(model) => model,
+            Self::Llama(model) => model,
+            Self::Opt(model) => model,
+        }
+    }
+}
+
+impl CausalLM for DartModel {
+    fn forward(&mut self, input_ids: &Tensor, seqlen_offset: usize) -> candle_core::Result<Tensor> {
+        self.as_causal_lm_mut().forward(input_ids, seqlen_offset)
+    }
+
+    fn forward_all(
+        &mut self,
+        input_ids: &Tensor,
+        seqlen_offset: usize,
+    ) -> candle_core::Result<Tensor> {
+        self.as_causal_lm_mut()
+            .forward_all(input_ids, seqlen_offset)
+    }
+
+    fn clear_kv_cache(&mut self) {
+        self.as_causal_lm_mut().clear_kv_cache()
+    }
+
+    fn truncate_kv_cache(&mut self, len: usize) -> candle_core::Result<()> {
+        self.as_causal_lm_mut().truncate_kv_cache(len)
+    }
+
+    fn kv_cache_tokens(&self) -> &[u32] {
+        self.as_causal_lm().kv_cache_tokens()
+    }
+
+    fn device(&self) -> &Device {
+        self.as_causal_lm().device()
+    }
+
+    fn dtype(&self) -> DType {
+        self.as_causal_lm().dtype()
+    }
+
+    fn max_position_embeddings(&self) -> usize {
+        self.as_causal_lm().max_position_embeddings()
+    }
+}
+
+impl lora::LoraModel for DartModel {
+    fn lora_linears(&mut self) -> Result<Vec<(String, &mut lora::LoraLinear)>> {
+        match self {
+            Self::Mistral(model) => model.lora_linears(),
+            Self::Mixtral(model) => model.lora_linears(),
+            _ => Err(E::msg(format!(
+                "{:?} does not support lora",
+                self.architecture()
+            ))),
+        }
+    }
+}
+
+/// Chooses the model builder from the architecture in `config.json`.
+pub struct DartModelBuilder {
+    repo: ModelRepositoy,
+    dtype: DType,
+    device: Device,
+    use_flash_attn: bool,
+    attention_chunk_size: Option<usize>,
+}
+
+impl DartModelBuilder {
+    /// Uses flash-attn in the mistral and mixtral models,
+    /// which needs the `flash-attn` feature and a CUDA device.
+    pub fn with_flash_attn(mut self, use_flash_attn: bool) -> Self {
+        self.use_flash_attn = use_flash_attn;
+        self
+    }
+
+    /// Computes the attention of the mistral and mixtral models over chunks of `chunk_size` keys,
+    /// see `chunked_attention`.
+    pub fn with_attention_chunk_size(mut self, chunk_size: Option<usize>) -> Self {
+        self.attention_chunk_size = chunk_size;
+        self
+    }
+}
+
+impl ModelBuilder<DartModel> for DartModelBuilder {
+    fn build(&self) -> Result<DartModel> {
+        let (repo, dtype, device) = (&self.repo, self.dtype, &self.device);
+        let model = match repo.load_architecture()? {
+            Architecture::Mistral => DartModel::Mistral(
+                MistralModelBuilder::new(repo, dtype, device)
+                    .with_flash_attn(self.use_flash_attn)
+                    .with_attention_chunk_size(self.attention_chunk_size)
+                    .build()?,
+            ),
+            Architecture::Mixtral => DartModel::Mixtral(
+                MixtralModelBuilder::new(repo, dtype, device)
+                    .with_flash_attn(self.use_flash_attn)
+                    .with_attention_chunk_size(self.attention_chunk_size)
+                    .build()?,
+            ),
+            Architecture::Llama => DartModel::Llama(LlamaModelBuilder::load(repo, dtype, device)?),
+            Architecture::Opt => DartModel::Opt(OptModelBuilder::load(repo, dtype, device)?),
+        };
+        Ok(model)
+    }
+
+    fn new(repo: &ModelRepositoy, dtype: DType, device: &Device) -> Self {
+        Self {
+            repo: repo.clone(),
+            dtype,
+            device: device.clone(),
+            use_flash_attn: false,
+            attention_chunk_size: None,
+        }
+    }
+
+    fn load(repo: &ModelRepositoy, dtype: DType, device: &Device) -> Result<DartModel> {
+        let builder = DartModelBuilder::new(repo, dtype, device);
+        builder.build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_nn::VarMap;
+
+    const LLAMA_CONFIG: &str = r#"{
+        "architectures": ["LlamaForCausalLM"],
+        "vocab_size": 8,
+        "hidden_size": 8,
+        "intermediate_size": 16,
+        "num_hidden_layers": 1,
+        "num_attention_heads": 2,
+        "num_key_value_heads": 1,
+        "hidden_act": "silu",
+        "max_position_embeddings": 16,
+        "rms_norm_eps": 1e-5
+    }"#;
+
+    #[test]
+    fn test_local_repository() {
+        // unique to the process, test runs can share the temp dir
+        let dir = std::env::temp_dir().join(format!(
+            "dartrs_test_local_repository_{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("config.json"), LLAMA_CONFIG).unwrap();
+        let config: llama::Config = serde_json::from_str(LLAMA_CONFIG).unwrap();
+        let varmap = VarMap::new();
+        let vb = VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu);
+        let mut expected = llama::Model::new(&config, vb).unwrap();
+        varmap.save(dir.join("model.safetensors")).unwrap();
+
+        let repo = ModelRepositoy::local(&dir);
+        assert_eq!(repo.load_architecture().unwrap(), Architecture::Llama);
+        assert!(repo.get("tokenizer.json").is_err());
+
+        let mut model = DartModelBuilder::load(&repo, DType::F32, &Device::Cpu).unwrap();
+        assert_eq!(model.architecture(), Architecture::Llama);
+        let input = Tensor::new(&[[1u32, 2, 3]], &Device::Cpu).unwrap();
+        let logits = CausalLM::forward(&mut model, &input, 0).unwrap();
+        let expected = expected.forward(&input, 0).unwrap();
+        assert_eq!(
+            logits.flatten_all().unwrap().to_vec1::<f32>().unwrap(),
+            expected.flatten_all().unwrap().to_vec1::<f32>().unwrap()
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_mixtral_config_from_repository() {
+        let dir = std::env::temp_dir().join(format!(
+            "dartrs_test_mixtral_config_from_repository_{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut config: serde_json::Value = test_utils::tiny_config();
+        config["architectures"] = serde_json::json!(["MixtralForCausalLM"]);
+        config.as_object_mut().unwrap().remove("use_flash_attn");
+        std::fs::write(dir.join("config.json"), config.to_string()).unwrap();
+        let varmap = VarMap::new();
+        let vb = VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu);
+        let config = test_utils::tiny_config();
+        let mut expected = mixtral::Model::new(&config, vb).unwrap();
+        varmap.save(dir.join("model.safetensors")).unwrap();
+
+        let repo = ModelRepositoy::local(&dir);
+        assert_eq!(repo.load_config::<mixtral::Config>().unwrap(), config);
+        let mut model = MixtralModelBuilder::new(&repo, DType::F32, &Device::Cpu)
+            .with_attention_chunk_size(Some(2))
+            .build()
+            .unwrap();
+        let input = Tensor::new(&[[1u32, 2, 3]], &Device::Cpu).unwrap();
+        let logits = model.forward(&input, 0).unwrap();
+        let expected = expected.forward(&input, 0).unwrap();
+        test_utils::assert_close(&test_utils::values(&logits), &test_utils::values(&expected));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
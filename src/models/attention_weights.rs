@@ -0,0 +1,59 @@
+/// Attention weights recorded for explainability.
+use candle_core::{Result, Tensor};
+
+/// The attention weights of the token at `position`, `(num_layers, num_heads, position + 1)`.
+/// `weights[l][h][j]` is how much the token attends to the position `j` in the head `h` of the layer `l`.
+#[derive(Debug, Clone)]
+pub struct TokenAttention {
+    pub position: usize,
+    pub weights: Tensor,
+}
+
+/// Splits the attention weights of every layer, `(b_size, num_heads, q_len, kv_len)`,
+/// into the tokens of the first sequence.
+pub fn split_by_token(layers: &[Tensor], seqlen_offset: usize) -> Result<Vec<TokenAttention>> {
+    let layers = layers
+        .iter()
+        .map(|weights| weights.get(0))
+        .collect::<Result<Vec<_>>>()?;
+    let weights = Tensor::stack(&layers, 0)?;
+    let (_num_layers, _num_heads, q_len, kv_len) = weights.dims4()?;
+    // the keys before `start` are out of the sliding window
+    let start = seqlen_offset + q_len - kv_len;
+
+    (0..q_len)
+        .map(|i| {
+            let position = seqlen_offset + i;
+            let weights = weights
+                .narrow(2, i, 1)?
+                .squeeze(2)?
+                .narrow(2, 0, position + 1 - start)?
+                .pad_with_zeros(2, start, 0)?;
+            Ok(TokenAttention { position, weights })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::Device;
+
+    #[test]
+    fn test_split_by_token() {
+        // 2 queries at the positions 3 and 4, attending to the positions 2 to 4
+        let weights = Tensor::arange(0f32, 6., &Device::Cpu)
+            .unwrap()
+            .reshape((1, 1, 2, 3))
+            .unwrap();
+        let tokens = split_by_token(&[weights.clone(), weights], 3).unwrap();
+
+        assert_eq!(tokens.len(), 2);
+        assert_eq!(tokens[0].position, 3);
+        assert_eq!(tokens[0].weights.dims(), &[2, 1, 4]);
+        let first = tokens[0].weights.get(0).unwrap().flatten_all().unwrap();
+        assert_eq!(first.to_vec1::<f32>().unwrap(), vec![0., 0., 0., 1.]);
+        let second = tokens[1].weights.get(1).unwrap().flatten_all().unwrap();
+        assert_eq!(second.to_vec1::<f32>().unwrap(), vec![0., 0., 3., 4., 5.]);
+    }
+}
@@ -0,0 +1,149 @@
+/// Memory efficient attention
+/// https://arxiv.org/abs/2112.05682
+///
+/// The keys and values are visited in chunks with an online softmax, so only
+/// `(q_len, chunk_size)` scores are held at once instead of `(q_len, kv_len)`.
+use candle_core::{DType, Result, Tensor, D};
+
+/// Computes `softmax(q k^T * scale + mask) v` over chunks of `chunk_size` keys.
+///
+/// `q` is `(b_sz, num_heads, q_len, head_dim)`, `k` and `v` are `(b_sz, num_heads, kv_len, head_dim)`
+/// and `mask` is broadcastable to `(b_sz, num_heads, q_len, kv_len)`.
+/// The scores are accumulated in f32 and the output has the dtype of `v`.
+pub fn chunked_attention(
+    q: &Tensor,
+    k: &Tensor,
+    v: &Tensor,
+    mask: Option<&Tensor>,
+    scale: f64,
+    chunk_size: usize,
+) -> Result<Tensor> {
+    if chunk_size == 0 {
+        candle_core::bail!("the attention chunk size must be at least 1")
+    }
+    let (b_sz, num_heads, q_len, _head_dim) = q.dims4()?;
+    let (_, _, kv_len, head_dim) = v.dims4()?;
+    let q = (q.to_dtype(DType::F32)? * scale)?;
+
+    // the running max, the running sum of exp and the unnormalized output of each query.
+    // the max starts finite so that a fully masked chunk gives exp(-inf) = 0 instead of NaN
+    let mut max = Tensor::full(f32::MIN, (b_sz, num_heads, q_len, 1), q.device())?;
+    let mut sum = Tensor::zeros((b_sz, num_heads, q_len, 1), DType::F32, q.device())?;
+    let mut output = Tensor::zeros((b_sz, num_heads, q_len, head_dim), DType::F32, q.device())?;
+
+    for start in (0..kv_len).step_by(chunk_size) {
+        let len = chunk_size.min(kv_len - start);
+        let k = k.narrow(2, start, len)?.to_dtype(DType::F32)?;
+        let v = v.narrow(2, start, len)?.to_dtype(DType::F32)?;
+
+        let scores = q.matmul(&k.t()?.contiguous()?)?;
+        let scores = match mask {
+            None => scores,
+            Some(mask) => {
+                scores.broadcast_add(&mask.narrow(D::Minus1, start, len)?.to_dtype(DType::F32)?)?
+            }
+        };
+
+        let new_max = max.maximum(&scores.max_keepdim(D::Minus1)?)?;
+        let exp = scores.broadcast_sub(&new_max)?.exp()?;
+        // rescales what was accumulated with the previous max
+        let correction = (max - &new_max)?.exp()?;
+        sum = ((sum * &correction)? + exp.sum_keepdim(D::Minus1)?)?;
+        output = (output.broadcast_mul(&correction)? + exp.matmul(&v)?)?;
+        max = new_max;
+    }
+
+    output.broadcast_div(&sum)?.to_dtype(v.dtype())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::Device;
+
+    // the reference attention of the models
+    fn attention(q: &Tensor, k: &Tensor, v: &Tensor, mask: Option<&Tensor>, scale: f64) -> Tensor {
+        let weights = (q.matmul(&k.t().unwrap()).unwrap() * scale).unwrap();
+        let weights = match mask {
+            None => weights,
+            Some(mask) => weights.broadcast_add(mask).unwrap(),
+        };
+        let weights = candle_nn::ops::softmax_last_dim(&weights).unwrap();
+        weights.matmul(v).unwrap()
+    }
+
+    fn qkv(q_len: usize, kv_len: usize) -> (Tensor, Tensor, Tensor) {
+        let device = Device::Cpu;
+        let q = Tensor::randn(0f32, 1., (1, 2, q_len, 4), &device).unwrap();
+        let k = Tensor::randn(0f32, 1., (1, 2, kv_len, 4), &device).unwrap();
+        let v = Tensor::randn(0f32, 1., (1, 2, kv_len, 4), &device).unwrap();
+        (q, k, v)
+    }
+
+    // the causal mask of `q_len` queries after `offset` cached tokens, within `sliding_window`
+    fn mask(q_len: usize, offset: usize, sliding_window: usize) -> Tensor {
+        let kv_len = q_len + offset;
+        let mask: Vec<_> = (0..q_len)
+            .flat_map(|i| {
+                (0..kv_len).map(move |j| {
+                    let i = i + offset;
+                    if i < j || j + sliding_window < i {
+                        f32::NEG_INFINITY
+                    } else {
+                        0.
+                    }
+                })
+            })
+            .collect();
+        Tensor::from_slice(&mask, (1, 1, q_len, kv_len), &Device::Cpu).unwrap()
+    }
+
+    fn assert_close(a: &Tensor, b: &Tensor) {
+        let diff = (a - b)
+            .unwrap()
+            .abs()
+            .unwrap()
+            .max_all()
+            .unwrap()
+            .to_scalar::<f32>()
+            .unwrap();
+        assert!(diff < 1e-5, "the max difference is {diff}");
+    }
+
+    #[test]
+    fn test_same_as_reference() {
+        let (q, k, v) = qkv(7, 7);
+        let mask = mask(7, 0, 7);
+        let expected = attention(&q, &k, &v, Some(&mask), 0.5);
+        for chunk_size in [1, 2, 3, 7, 16] {
+            let output = chunked_attention(&q, &k, &v, Some(&mask), 0.5, chunk_size).unwrap();
+            assert_eq!(output.dims(), &[1, 2, 7, 4]);
+            assert_close(&output, &expected);
+        }
+    }
+
+    #[test]
+    fn test_same_as_reference_without_mask() {
+        // a single query attending to the kv cache
+        let (q, k, v) = qkv(1, 9);
+        let expected = attention(&q, &k, &v, None, 0.5);
+        let output = chunked_attention(&q, &k, &v, None, 0.5, 4).unwrap();
+        assert_close(&output, &expected);
+    }
+
+    #[test]
+    fn test_same_as_reference_with_sliding_window() {
+        // the first chunks are fully masked for the last queries
+        let (q, k, v) = qkv(3, 10);
+        let mask = mask(3, 7, 2);
+        let expected = attention(&q, &k, &v, Some(&mask), 0.5);
+        let output = chunked_attention(&q, &k, &v, Some(&mask), 0.5, 2).unwrap();
+        assert_close(&output, &expected);
+    }
+
+    #[test]
+    fn test_zero_chunk_size() {
+        let (q, k, v) = qkv(1, 1);
+        assert!(chunked_attention(&q, &k, &v, None, 0.5, 0).is_err());
+    }
+}
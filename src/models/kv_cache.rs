@@ -0,0 +1,258 @@
+/// KV cache preallocated to the maximum sequence length.
+/// New keys and values are written into the buffer in place, instead of concatenating
+/// the whole cache with `Tensor::cat` every step.
+use candle_core::{Device, Result, Tensor};
+
+#[derive(Debug)]
+pub struct KvCache {
+    dim: usize,
+    max_seq_len: usize,
+    sliding_window: Option<usize>,
+    seq_len: usize,
+    buffers: Option<(Tensor, Tensor)>,
+}
+
+impl KvCache {
+    /// `dim` is the sequence dimension of the keys and values.
+    pub fn new(dim: usize, max_seq_len: usize) -> Self {
+        Self {
+            dim,
+            max_seq_len,
+            sliding_window: None,
+            seq_len: 0,
+            buffers: None,
+        }
+    }
+
+    /// Only returns the keys and values a query can attend to within the sliding window,
+    /// the same as the attention mask of the models.
+    pub fn with_sliding_window(mut self, sliding_window: Option<usize>) -> Self {
+        self.sliding_window = sliding_window;
+        self
+    }
+
+    /// The first position `append` returns when `seq_len` positions are already cached.
+    pub fn window_start(&self, seq_len: usize) -> usize {
+        match self.sliding_window {
+            Some(sliding_window) => seq_len.saturating_sub(sliding_window),
+            None => 0,
+        }
+    }
+
+    pub fn seq_len(&self) -> usize {
+        self.seq_len
+    }
+
+    pub fn max_seq_len(&self) -> usize {
+        self.max_seq_len
+    }
+
+    /// Appends the keys and values and returns the cached ones from `window_start`.
+    pub fn append(&mut self, k: &Tensor, v: &Tensor) -> Result<(Tensor, Tensor)> {
+        let k = k.contiguous()?;
+        let v = v.contiguous()?;
+        let seq_len = self.seq_len + k.dim(self.dim)?;
+        if seq_len > self.max_seq_len {
+            candle_core::bail!(
+                "kv cache overflow: {seq_len} positions exceed the maximum of {}",
+                self.max_seq_len
+            )
+        }
+
+        let start = self.window_start(self.seq_len);
+        let buffers = match self.buffers.take() {
+            // the buffers are reused unless the batch size or the dtype has changed
+            Some((k_buffer, v_buffer))
+                if self.seq_len > 0 || self.fits(&k_buffer, &k) && self.fits(&v_buffer, &v) =>
+            {
+                (k_buffer, v_buffer)
+            }
+            _ => (self.allocate(&k)?, self.allocate(&v)?),
+        };
+        let (k_buffer, v_buffer) = self.buffers.insert(buffers);
+        k_buffer.slice_set(&k, self.dim, self.seq_len)?;
+        v_buffer.slice_set(&v, self.dim, self.seq_len)?;
+
+        self.seq_len = seq_len;
+
+        let k = k_buffer.narrow(self.dim, start, seq_len - start)?;
+        let v = v_buffer.narrow(self.dim, start, seq_len - start)?;
+        Ok((k, v))
+    }
+
+    /// Swaps in an empty cache of up to `max_seq_len` positions and returns the current one,
+    /// to run a forward pass without touching the cached keys and values.
+    pub fn replace_with_empty(&mut self, max_seq_len: usize) -> KvCache {
+        let empty = KvCache::new(self.dim, max_seq_len).with_sliding_window(self.sliding_window);
+        std::mem::replace(self, empty)
+    }
+
+    /// Drops the keys and values after the first `len` positions.
+    pub fn truncate(&mut self, len: usize) {
+        self.seq_len = self.seq_len.min(len)
+    }
+
+    /// Empties the cache but keeps the buffers for the next generation.
+    pub fn reset(&mut self) {
+        self.seq_len = 0
+    }
+
+    /// Copies the cache. The buffers are written in place, so the copy must not share them.
+    pub fn try_clone(&self) -> Result<Self> {
+        let buffers = match &self.buffers {
+            Some((k, v)) => Some((k.copy()?, v.copy()?)),
+            None => None,
+        };
+        Ok(Self {
+            dim: self.dim,
+            max_seq_len: self.max_seq_len,
+            sliding_window: self.sliding_window,
+            seq_len: self.seq_len,
+            buffers,
+        })
+    }
+
+    fn allocate(&self, xs: &Tensor) -> Result<Tensor> {
+        let mut shape = xs.dims().to_vec();
+        shape[self.dim] = self.max_seq_len;
+        Tensor::zeros(shape, xs.dtype(), xs.device())
+    }
+
+    fn fits(&self, buffer: &Tensor, xs: &Tensor) -> bool {
+        buffer.dtype() == xs.dtype()
+            && buffer.device().same_device(xs.device())
+            && buffer.rank() == xs.rank()
+            && (0..xs.rank())
+                .filter(|&dim| dim != self.dim)
+                .all(|dim| buffer.dims()[dim] == xs.dims()[dim])
+    }
+}
+
+/// The tokens whose keys and values are in the kv caches of a model, so that a generation
+/// knows which of its tokens the model can reuse. Empty when they are unknown.
+#[derive(Debug, Clone, Default)]
+pub struct KvCacheTokens {
+    tokens: Vec<u32>,
+}
+
+impl KvCacheTokens {
+    pub fn tokens(&self) -> &[u32] {
+        &self.tokens
+    }
+
+    /// Records the `input_ids` fed from `seqlen_offset`. Only a batch of one is recorded,
+    /// and nothing is while the earlier positions are unknown.
+    pub fn append(&mut self, input_ids: &Tensor, seqlen_offset: usize) -> Result<()> {
+        let (b_size, _seq_len) = input_ids.dims2()?;
+        if b_size != 1 || self.tokens.len() < seqlen_offset {
+            self.tokens.clear();
+            return Ok(());
+        }
+        let input_ids = input_ids
+            .to_device(&Device::Cpu)?
+            .squeeze(0)?
+            .to_vec1::<u32>()?;
+        self.tokens.truncate(seqlen_offset);
+        self.tokens.extend_from_slice(&input_ids);
+        Ok(())
+    }
+
+    pub fn truncate(&mut self, len: usize) {
+        self.tokens.truncate(len)
+    }
+
+    pub fn clear(&mut self) {
+        self.tokens.clear()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::{DType, Device};
+
+    fn states(start: usize, len: usize) -> Tensor {
+        Tensor::arange(start as u32, (start + len) as u32, &Device::Cpu)
+            .unwrap()
+            .to_dtype(DType::F32)
+            .unwrap()
+            .reshape((1, 1, len, 1))
+            .unwrap()
+    }
+
+    fn values(xs: &Tensor) -> Vec<f32> {
+        xs.flatten_all().unwrap().to_vec1().unwrap()
+    }
+
+    #[test]
+    fn test_append() {
+        let mut cache = KvCache::new(2, 8);
+        cache.append(&states(0, 3), &states(0, 3)).unwrap();
+        let (k, v) = cache.append(&states(3, 1), &states(3, 1)).unwrap();
+
+        assert_eq!(k.dims(), &[1, 1, 4, 1]);
+        assert_eq!(values(&k), vec![0., 1., 2., 3.]);
+        assert_eq!(values(&v), vec![0., 1., 2., 3.]);
+    }
+
+    #[test]
+    fn test_truncate() {
+        let mut cache = KvCache::new(2, 8);
+        cache.append(&states(0, 4), &states(0, 4)).unwrap();
+        cache.truncate(2);
+        let (k, _v) = cache.append(&states(5, 1), &states(5, 1)).unwrap();
+
+        assert_eq!(cache.seq_len(), 3);
+        assert_eq!(values(&k), vec![0., 1., 5.]);
+    }
+
+    #[test]
+    fn test_sliding_window() {
+        let mut cache = KvCache::new(2, 8).with_sliding_window(Some(2));
+        let (k, _v) = cache.append(&states(0, 4), &states(0, 4)).unwrap();
+        assert_eq!(values(&k), vec![0., 1., 2., 3.]);
+
+        // the query at position 4 attends to the positions 2 to 4
+        let (k, _v) = cache.append(&states(4, 1), &states(4, 1)).unwrap();
+        assert_eq!(values(&k), vec![2., 3., 4.]);
+    }
+
+    #[test]
+    fn test_overflow() {
+        let mut cache = KvCache::new(2, 4);
+        cache.append(&states(0, 4), &states(0, 4)).unwrap();
+
+        assert!(cache.append(&states(4, 1), &states(4, 1)).is_err());
+    }
+
+    #[test]
+    fn test_clone_does_not_share_buffers() {
+        let mut cache = KvCache::new(2, 4);
+        cache.append(&states(0, 2), &states(0, 2)).unwrap();
+        let mut cloned = cache.try_clone().unwrap();
+        cloned.truncate(1);
+        cloned.append(&states(7, 1), &states(7, 1)).unwrap();
+
+        let (k, _v) = cache.append(&states(2, 1), &states(2, 1)).unwrap();
+        assert_eq!(values(&k), vec![0., 1., 2.]);
+    }
+
+    #[test]
+    fn test_kv_cache_tokens() {
+        let input_ids = |ids: &[u32]| {
+            Tensor::new(ids, &Device::Cpu)
+                .unwrap()
+                .unsqueeze(0)
+                .unwrap()
+        };
+        let mut tokens = KvCacheTokens::default();
+        tokens.append(&input_ids(&[0, 1, 2]), 0).unwrap();
+        tokens.append(&input_ids(&[3]), 2).unwrap();
+        assert_eq!(tokens.tokens(), &[0, 1, 3]);
+
+        // the positions before the offset are unknown
+        tokens.clear();
+        tokens.append(&input_ids(&[4]), 1).unwrap();
+        assert!(tokens.tokens().is_empty());
+    }
+}
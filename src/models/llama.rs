@@ -0,0 +1,540 @@
+// copied and modified from https://github.com/huggingface/candle/blob/3ad4770eb61be34e6d2a7914a935b007d8dee49f/candle-transformers/src/models/mistral.rs
+
+/// Llama Model
+/// https://github.com/huggingface/transformers/blob/main/src/transformers/models/llama/modeling_llama.py
+use candle_core::{DType, Device, Module, Result, Tensor, D};
+use candle_nn::{Activation, VarBuilder};
+use candle_transformers::models::with_tracing::{linear_no_bias, Linear, RmsNorm};
+use std::sync::Arc;
+
+use crate::models::kv_cache::{KvCache, KvCacheTokens};
+
+fn default_rope_theta() -> f64 {
+    10000.0
+}
+
+/// https://github.com/huggingface/transformers/blob/main/src/transformers/models/llama/configuration_llama.py
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+pub struct Config {
+    pub vocab_size: usize,
+    pub hidden_size: usize,
+    pub intermediate_size: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    pub num_key_value_heads: usize,
+    pub hidden_act: Activation,
+    pub max_position_embeddings: usize,
+    pub rms_norm_eps: f64,
+    #[serde(default = "default_rope_theta")]
+    pub rope_theta: f64,
+    #[serde(default)]
+    pub tie_word_embeddings: bool,
+    #[serde(default)]
+    pub use_flash_attn: bool,
+}
+
+#[derive(Debug, Clone)]
+struct RotaryEmbedding {
+    sin: Tensor,
+    cos: Tensor,
+}
+
+impl RotaryEmbedding {
+    fn new(dtype: DType, cfg: &Config, dev: &Device) -> Result<Self> {
+        let rope_theta = cfg.rope_theta as f32;
+        let dim = cfg.hidden_size / cfg.num_attention_heads;
+        let max_seq_len = cfg.max_position_embeddings;
+        let inv_freq: Vec<_> = (0..dim)
+            .step_by(2)
+            .map(|i| 1f32 / rope_theta.powf(i as f32 / dim as f32))
+            .collect();
+        let inv_freq_len = inv_freq.len();
+        let inv_freq = Tensor::from_vec(inv_freq, (1, inv_freq_len), dev)?.to_dtype(dtype)?;
+        let t = Tensor::arange(0u32, max_seq_len as u32, dev)?
+            .to_dtype(dtype)?
+            .reshape((max_seq_len, 1))?;
+        let freqs = t.matmul(&inv_freq)?;
+        Ok(Self {
+            sin: freqs.sin()?,
+            cos: freqs.cos()?,
+        })
+    }
+
+    fn apply_rotary_emb_qkv(
+        &self,
+        q: &Tensor,
+        k: &Tensor,
+        seqlen_offset: usize,
+    ) -> Result<(Tensor, Tensor)> {
+        let (_b_sz, _h, seq_len, _n_embd) = q.dims4()?;
+        let cos = self.cos.narrow(0, seqlen_offset, seq_len)?;
+        let sin = self.sin.narrow(0, seqlen_offset, seq_len)?;
+        let q_embed = candle_nn::rotary_emb::rope(q, &cos, &sin)?;
+        let k_embed = candle_nn::rotary_emb::rope(k, &cos, &sin)?;
+        Ok((q_embed, k_embed))
+    }
+}
+
+#[derive(Debug, Clone)]
+#[allow(clippy::upper_case_acronyms)]
+struct MLP {
+    gate_proj: Linear,
+    up_proj: Linear,
+    down_proj: Linear,
+    act_fn: Activation,
+}
+
+impl MLP {
+    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let hidden_sz = cfg.hidden_size;
+        let intermediate_sz = cfg.intermediate_size;
+        let gate_proj = linear_no_bias(hidden_sz, intermediate_sz, vb.pp("gate_proj"))?;
+        let up_proj = linear_no_bias(hidden_sz, intermediate_sz, vb.pp("up_proj"))?;
+        let down_proj = linear_no_bias(intermediate_sz, hidden_sz, vb.pp("down_proj"))?;
+        Ok(Self {
+            gate_proj,
+            up_proj,
+            down_proj,
+            act_fn: cfg.hidden_act,
+        })
+    }
+}
+
+impl Module for MLP {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let lhs = xs.apply(&self.gate_proj)?.apply(&self.act_fn)?;
+        let rhs = xs.apply(&self.up_proj)?;
+        (lhs * rhs)?.apply(&self.down_proj)
+    }
+}
+
+#[cfg(feature = "flash-attn")]
+fn flash_attn(
+    q: &Tensor,
+    k: &Tensor,
+    v: &Tensor,
+    softmax_scale: f32,
+    causal: bool,
+) -> Result<Tensor> {
+    candle_flash_attn::flash_attn(q, k, v, softmax_scale, causal)
+}
+
+#[cfg(not(feature = "flash-attn"))]
+fn flash_attn(_: &Tensor, _: &Tensor, _: &Tensor, _: f32, _: bool) -> Result<Tensor> {
+    candle_core::bail!("compile with '--features flash-attn'")
+}
+
+#[derive(Debug)]
+struct Attention {
+    q_proj: Linear,
+    k_proj: Linear,
+    v_proj: Linear,
+    o_proj: Linear,
+    num_heads: usize,
+    num_kv_heads: usize,
+    num_kv_groups: usize,
+    head_dim: usize,
+    hidden_size: usize,
+    rotary_emb: Arc<RotaryEmbedding>,
+    kv_cache: KvCache,
+    use_flash_attn: bool,
+}
+
+impl Attention {
+    fn new(rotary_emb: Arc<RotaryEmbedding>, cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let hidden_sz = cfg.hidden_size;
+        let num_heads = cfg.num_attention_heads;
+        let num_kv_heads = cfg.num_key_value_heads;
+        let num_kv_groups = num_heads / num_kv_heads;
+        let head_dim = hidden_sz / num_heads;
+        let q_proj = linear_no_bias(hidden_sz, num_heads * head_dim, vb.pp("q_proj"))?;
+        let k_proj = linear_no_bias(hidden_sz, num_kv_heads * head_dim, vb.pp("k_proj"))?;
+        let v_proj = linear_no_bias(hidden_sz, num_kv_heads * head_dim, vb.pp("v_proj"))?;
+        let o_proj = linear_no_bias(num_heads * head_dim, hidden_sz, vb.pp("o_proj"))?;
+        Ok(Self {
+            q_proj,
+            k_proj,
+            v_proj,
+            o_proj,
+            num_heads,
+            num_kv_heads,
+            num_kv_groups,
+            head_dim,
+            hidden_size: hidden_sz,
+            rotary_emb,
+            kv_cache: KvCache::new(2, cfg.max_position_embeddings),
+            use_flash_attn: cfg.use_flash_attn,
+        })
+    }
+
+    fn try_clone(&self) -> Result<Self> {
+        Ok(Self {
+            q_proj: self.q_proj.clone(),
+            k_proj: self.k_proj.clone(),
+            v_proj: self.v_proj.clone(),
+            o_proj: self.o_proj.clone(),
+            num_heads: self.num_heads,
+            num_kv_heads: self.num_kv_heads,
+            num_kv_groups: self.num_kv_groups,
+            head_dim: self.head_dim,
+            hidden_size: self.hidden_size,
+            rotary_emb: self.rotary_emb.clone(),
+            kv_cache: self.kv_cache.try_clone()?,
+            use_flash_attn: self.use_flash_attn,
+        })
+    }
+
+    fn forward(
+        &mut self,
+        xs: &Tensor,
+        attention_mask: Option<&Tensor>,
+        seqlen_offset: usize,
+    ) -> Result<Tensor> {
+        let (b_sz, q_len, _) = xs.dims3()?;
+
+        let query_states = self.q_proj.forward(xs)?;
+        let key_states = self.k_proj.forward(xs)?;
+        let value_states = self.v_proj.forward(xs)?;
+
+        let query_states = query_states
+            .reshape((b_sz, q_len, self.num_heads, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+        let key_states = key_states
+            .reshape((b_sz, q_len, self.num_kv_heads, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+        let value_states = value_states
+            .reshape((b_sz, q_len, self.num_kv_heads, self.head_dim))?
+            .transpose(1, 2)?;
+
+        let (query_states, key_states) =
+            self.rotary_emb
+                .apply_rotary_emb_qkv(&query_states, &key_states, seqlen_offset)?;
+
+        let (key_states, value_states) = self.kv_cache.append(&key_states, &value_states)?;
+
+        let key_states = candle_transformers::utils::repeat_kv(key_states, self.num_kv_groups)?;
+        let value_states = candle_transformers::utils::repeat_kv(value_states, self.num_kv_groups)?;
+
+        let attn_output = if self.use_flash_attn {
+            // flash-attn expects (b_sz, seq_len, nheads, head_dim)
+            let q = query_states.transpose(1, 2)?;
+            let k = key_states.transpose(1, 2)?;
+            let v = value_states.transpose(1, 2)?;
+            let softmax_scale = 1f32 / (self.head_dim as f32).sqrt();
+            flash_attn(&q, &k, &v, softmax_scale, q_len > 1)?.transpose(1, 2)?
+        } else {
+            let scale = 1f64 / f64::sqrt(self.head_dim as f64);
+            let attn_weights = (query_states.matmul(&key_states.transpose(2, 3)?)? * scale)?;
+
+            let attn_weights = match attention_mask {
+                None => attn_weights,
+                Some(mask) => attn_weights.broadcast_add(mask)?,
+            };
+            let attn_weights = candle_nn::ops::softmax_last_dim(&attn_weights)?;
+            attn_weights.matmul(&value_states)?
+        };
+        attn_output
+            .transpose(1, 2)?
+            .reshape((b_sz, q_len, self.hidden_size))?
+            .apply(&self.o_proj)
+    }
+
+    fn clear_kv_cache(&mut self) {
+        self.kv_cache.reset()
+    }
+
+    fn truncate_kv_cache(&mut self, len: usize) -> Result<()> {
+        self.kv_cache.truncate(len);
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct DecoderLayer {
+    self_attn: Attention,
+    mlp: MLP,
+    input_layernorm: RmsNorm,
+    post_attention_layernorm: RmsNorm,
+}
+
+impl DecoderLayer {
+    fn new(rotary_emb: Arc<RotaryEmbedding>, cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let self_attn = Attention::new(rotary_emb, cfg, vb.pp("self_attn"))?;
+        let mlp = MLP::new(cfg, vb.pp("mlp"))?;
+        let input_layernorm =
+            RmsNorm::new(cfg.hidden_size, cfg.rms_norm_eps, vb.pp("input_layernorm"))?;
+        let post_attention_layernorm = RmsNorm::new(
+            cfg.hidden_size,
+            cfg.rms_norm_eps,
+            vb.pp("post_attention_layernorm"),
+        )?;
+        Ok(Self {
+            self_attn,
+            mlp,
+            input_layernorm,
+            post_attention_layernorm,
+        })
+    }
+
+    fn try_clone(&self) -> Result<Self> {
+        Ok(Self {
+            self_attn: self.self_attn.try_clone()?,
+            mlp: self.mlp.clone(),
+            input_layernorm: self.input_layernorm.clone(),
+            post_attention_layernorm: self.post_attention_layernorm.clone(),
+        })
+    }
+
+    fn forward(
+        &mut self,
+        xs: &Tensor,
+        attention_mask: Option<&Tensor>,
+        seqlen_offset: usize,
+    ) -> Result<Tensor> {
+        let residual = xs;
+        let xs = self.input_layernorm.forward(xs)?;
+        let xs = self.self_attn.forward(&xs, attention_mask, seqlen_offset)?;
+        let xs = (xs + residual)?;
+        let residual = &xs;
+        let xs = xs.apply(&self.post_attention_layernorm)?.apply(&self.mlp)?;
+        residual + xs
+    }
+
+    fn clear_kv_cache(&mut self) {
+        self.self_attn.clear_kv_cache()
+    }
+
+    fn truncate_kv_cache(&mut self, len: usize) -> Result<()> {
+        self.self_attn.truncate_kv_cache(len)
+    }
+}
+
+#[derive(Debug)]
+pub struct Model {
+    embed_tokens: candle_nn::Embedding,
+    layers: Vec<DecoderLayer>,
+    norm: RmsNorm,
+    lm_head: Linear,
+    max_position_embeddings: usize,
+    kv_cache_tokens: KvCacheTokens,
+    device: Device,
+    dtype: DType,
+}
+
+impl Model {
+    pub fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let vb_m = vb.pp("model");
+        let embed_tokens =
+            candle_nn::embedding(cfg.vocab_size, cfg.hidden_size, vb_m.pp("embed_tokens"))?;
+        let rotary_emb = Arc::new(RotaryEmbedding::new(vb.dtype(), cfg, vb_m.device())?);
+        let mut layers = Vec::with_capacity(cfg.num_hidden_layers);
+        let vb_l = vb_m.pp("layers");
+        for layer_idx in 0..cfg.num_hidden_layers {
+            let layer = DecoderLayer::new(rotary_emb.clone(), cfg, vb_l.pp(layer_idx))?;
+            layers.push(layer)
+        }
+        let norm = RmsNorm::new(cfg.hidden_size, cfg.rms_norm_eps, vb_m.pp("norm"))?;
+        let lm_head = if cfg.tie_word_embeddings {
+            Linear::from_weights(embed_tokens.embeddings().clone(), None)
+        } else {
+            linear_no_bias(cfg.hidden_size, cfg.vocab_size, vb.pp("lm_head"))?
+        };
+        Ok(Self {
+            embed_tokens,
+            layers,
+            norm,
+            lm_head,
+            max_position_embeddings: cfg.max_position_embeddings,
+            kv_cache_tokens: KvCacheTokens::default(),
+            device: vb.device().clone(),
+            dtype: vb.dtype(),
+        })
+    }
+
+    /// Copies the model with its own kv cache, the weights are shared.
+    pub fn try_clone(&self) -> Result<Self> {
+        Ok(Self {
+            embed_tokens: self.embed_tokens.clone(),
+            layers: self
+                .layers
+                .iter()
+                .map(DecoderLayer::try_clone)
+                .collect::<Result<_>>()?,
+            norm: self.norm.clone(),
+            lm_head: self.lm_head.clone(),
+            max_position_embeddings: self.max_position_embeddings,
+            kv_cache_tokens: self.kv_cache_tokens.clone(),
+            device: self.device.clone(),
+            dtype: self.dtype,
+        })
+    }
+
+    fn prepare_decoder_attention_mask(
+        &self,
+        tgt_len: usize,
+        seqlen_offset: usize,
+    ) -> Result<Tensor> {
+        let mask: Vec<_> = (0..tgt_len)
+            .flat_map(|i| (0..tgt_len).map(move |j| if i < j { f32::NEG_INFINITY } else { 0. }))
+            .collect();
+        let mask = Tensor::from_slice(&mask, (tgt_len, tgt_len), &self.device)?;
+        let mask = if seqlen_offset > 0 {
+            let mask0 = Tensor::zeros((tgt_len, seqlen_offset), DType::F32, &self.device)?;
+            Tensor::cat(&[&mask0, &mask], D::Minus1)?
+        } else {
+            mask
+        };
+        mask.expand((1, 1, tgt_len, tgt_len + seqlen_offset))?
+            .to_dtype(self.dtype)
+    }
+
+    fn forward_hidden_states(
+        &mut self,
+        input_ids: &Tensor,
+        seqlen_offset: usize,
+    ) -> Result<Tensor> {
+        // left empty when the forward fails, since some layers may have cached the input
+        let mut kv_cache_tokens = std::mem::take(&mut self.kv_cache_tokens);
+        let (_b_size, seq_len) = input_ids.dims2()?;
+        let attention_mask = if seq_len <= 1 {
+            None
+        } else {
+            let mask = self.prepare_decoder_attention_mask(seq_len, seqlen_offset)?;
+            Some(mask)
+        };
+        let mut xs = self.embed_tokens.forward(input_ids)?;
+        for layer in self.layers.iter_mut() {
+            xs = layer.forward(&xs, attention_mask.as_ref(), seqlen_offset)?
+        }
+        kv_cache_tokens.append(input_ids, seqlen_offset)?;
+        self.kv_cache_tokens = kv_cache_tokens;
+        Ok(xs)
+    }
+
+    pub fn forward(&mut self, input_ids: &Tensor, seqlen_offset: usize) -> Result<Tensor> {
+        let (_b_size, seq_len) = input_ids.dims2()?;
+        self.forward_hidden_states(input_ids, seqlen_offset)?
+            .narrow(1, seq_len - 1, 1)?
+            .apply(&self.norm)?
+            .apply(&self.lm_head)
+    }
+
+    /// Returns the logits of every input position instead of only the last one.
+    pub fn forward_all(&mut self, input_ids: &Tensor, seqlen_offset: usize) -> Result<Tensor> {
+        self.forward_hidden_states(input_ids, seqlen_offset)?
+            .apply(&self.norm)?
+            .apply(&self.lm_head)
+    }
+
+    pub fn clear_kv_cache(&mut self) {
+        self.kv_cache_tokens.clear();
+        for layer in self.layers.iter_mut() {
+            layer.clear_kv_cache()
+        }
+    }
+
+    /// Drops the cached keys and values after the first `len` positions.
+    pub fn truncate_kv_cache(&mut self, len: usize) -> Result<()> {
+        self.kv_cache_tokens.truncate(len);
+        for layer in self.layers.iter_mut() {
+            layer.truncate_kv_cache(len)?
+        }
+        Ok(())
+    }
+
+    /// The tokens whose keys and values are in the kv cache.
+    pub fn kv_cache_tokens(&self) -> &[u32] {
+        self.kv_cache_tokens.tokens()
+    }
+
+    pub fn device(&self) -> &Device {
+        &self.device
+    }
+
+    pub fn dtype(&self) -> DType {
+        self.dtype
+    }
+
+    pub fn max_position_embeddings(&self) -> usize {
+        self.max_position_embeddings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::test_utils::{assert_close, tiny_config, values, var_builder};
+
+    fn config() -> Config {
+        tiny_config()
+    }
+
+    fn model(cfg: &Config) -> Model {
+        Model::new(cfg, var_builder()).unwrap()
+    }
+
+    #[test]
+    fn test_forward() {
+        // with and without the lm head tied to the embeddings
+        let configs = [
+            config(),
+            Config {
+                tie_word_embeddings: true,
+                ..config()
+            },
+        ];
+        for cfg in configs {
+            let mut model = model(&cfg);
+            let input = Tensor::new(&[[1u32, 2, 3]], &Device::Cpu).unwrap();
+            let logits = model.forward_all(&input, 0).unwrap();
+            assert_eq!(logits.dims(), &[1, 3, 8]);
+
+            // feeding the tokens one by one reuses the kv cache
+            model.clear_kv_cache();
+            let mut steps = Vec::new();
+            for (position, token) in [1u32, 2, 3].into_iter().enumerate() {
+                let input = Tensor::new(&[[token]], &Device::Cpu).unwrap();
+                steps.extend(values(&model.forward(&input, position).unwrap()));
+            }
+            assert_close(&steps, &values(&logits));
+        }
+    }
+
+    #[test]
+    fn test_truncate_kv_cache() {
+        let mut model = model(&config());
+        let input = Tensor::new(&[[1u32, 2, 3, 4]], &Device::Cpu).unwrap();
+        model.forward(&input, 0).unwrap();
+        model.truncate_kv_cache(2).unwrap();
+        let next = Tensor::new(&[[5u32]], &Device::Cpu).unwrap();
+        let truncated = values(&model.forward(&next, 2).unwrap());
+
+        model.clear_kv_cache();
+        let input = Tensor::new(&[[1u32, 2, 5]], &Device::Cpu).unwrap();
+        let expected = values(&model.forward(&input, 0).unwrap());
+        assert_close(&truncated, &expected);
+    }
+
+    #[test]
+    fn test_try_clone() {
+        let mut model = model(&config());
+        let input = Tensor::new(&[[1u32, 2, 3]], &Device::Cpu).unwrap();
+        let expected = values(&model.forward(&input, 0).unwrap());
+
+        model.clear_kv_cache();
+        let input = Tensor::new(&[[1u32, 2]], &Device::Cpu).unwrap();
+        model.forward(&input, 0).unwrap();
+        let mut cloned = model.try_clone().unwrap();
+        assert_eq!(cloned.kv_cache_tokens(), &[1, 2]);
+
+        // the clone overwrites the second position of its own kv cache only
+        cloned.truncate_kv_cache(1).unwrap();
+        let input = Tensor::new(&[[5u32]], &Device::Cpu).unwrap();
+        cloned.forward(&input, 1).unwrap();
+
+        let next = Tensor::new(&[[3u32]], &Device::Cpu).unwrap();
+        assert_close(&values(&model.forward(&next, 2).unwrap()), &expected);
+    }
+}
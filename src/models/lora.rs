@@ -0,0 +1,354 @@
+/// LoRA adapters in the PEFT format
+/// https://arxiv.org/abs/2106.09685
+///
+/// Adapters are either applied at runtime, where each one can be scaled or removed,
+/// or merged into the base weights.
+use anyhow::{Error as E, Result};
+use std::collections::HashMap;
+use std::path::Path;
+
+use candle_core::{Device, Module, Tensor};
+use candle_nn::VarBuilder;
+use serde::Deserialize;
+
+use crate::models::ModelRepositoy;
+
+/// https://github.com/huggingface/peft/blob/main/src/peft/tuners/lora/config.py
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct LoraConfig {
+    pub r: usize,
+    pub lora_alpha: f64,
+    #[serde(default)]
+    pub use_rslora: bool,
+}
+
+impl LoraConfig {
+    /// `lora_alpha / r`, or `lora_alpha / sqrt(r)` with rsLoRA.
+    pub fn scaling(&self) -> f64 {
+        if self.use_rslora {
+            self.lora_alpha / (self.r as f64).sqrt()
+        } else {
+            self.lora_alpha / self.r as f64
+        }
+    }
+}
+
+/// The weights of a LoRA adapter, keyed by the module path (`model.layers.0.self_attn.q_proj`).
+#[derive(Debug, Clone)]
+pub struct LoraAdapter {
+    name: String,
+    config: LoraConfig,
+    // (lora_A (r, in_dim), lora_B (out_dim, r))
+    weights: HashMap<String, (Tensor, Tensor)>,
+}
+
+impl LoraAdapter {
+    /// `weights` maps the module paths to `(lora_A, lora_B)`, `(r, in_dim)` and `(out_dim, r)`.
+    pub fn new(name: &str, config: LoraConfig, weights: HashMap<String, (Tensor, Tensor)>) -> Self {
+        Self {
+            name: name.to_string(),
+            config,
+            weights,
+        }
+    }
+
+    /// Loads `adapter_config.json` and `adapter_model.safetensors`.
+    pub fn from_files<P: AsRef<Path>>(name: &str, config_file: P, weights_file: P) -> Result<Self> {
+        let config = std::fs::read_to_string(config_file)?;
+        let config: LoraConfig = serde_json::from_str(&config)?;
+        let tensors = candle_core::safetensors::load(weights_file, &Device::Cpu)?;
+
+        let mut lora_a = HashMap::new();
+        let mut lora_b = HashMap::new();
+        for (key, tensor) in tensors {
+            // base_model.model.{module}.lora_A(.{adapter_name}).weight
+            let key = key.strip_prefix("base_model.model.").unwrap_or(&key);
+            if let Some((module, rest)) = key.split_once(".lora_A.") {
+                if rest.ends_with("weight") {
+                    lora_a.insert(module.to_string(), tensor);
+                }
+            } else if let Some((module, rest)) = key.split_once(".lora_B.") {
+                if rest.ends_with("weight") {
+                    lora_b.insert(module.to_string(), tensor);
+                }
+            }
+        }
+
+        let mut weights = HashMap::new();
+        for (module, a) in lora_a {
+            let b = lora_b
+                .remove(&module)
+                .ok_or(E::msg(format!("lora_B of {module} is missing")))?;
+            weights.insert(module, (a, b));
+        }
+        if let Some(module) = lora_b.keys().next() {
+            return Err(E::msg(format!("lora_A of {module} is missing")));
+        }
+
+        Ok(Self::new(name, config, weights))
+    }
+
+    /// Loads the adapter saved by `save_pretrained` in `dir`.
+    pub fn from_dir<P: AsRef<Path>>(name: &str, dir: P) -> Result<Self> {
+        let dir = dir.as_ref();
+        Self::from_files(
+            name,
+            dir.join("adapter_config.json"),
+            dir.join("adapter_model.safetensors"),
+        )
+    }
+
+    /// Reads the adapter from a repository, downloading it from the hub if needed.
+    pub fn from_repo(name: &str, repo: &ModelRepositoy) -> Result<Self> {
+        let config_file = repo.get("adapter_config.json")?;
+        let weights_file = repo.get("adapter_model.safetensors")?;
+        Self::from_files(name, config_file, weights_file)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn config(&self) -> &LoraConfig {
+        &self.config
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Lora {
+    name: String,
+    a: candle_nn::Linear,
+    b: candle_nn::Linear,
+    // the scale set by the user
+    scale: f64,
+    // `lora_alpha / r` of the config
+    scaling: f64,
+}
+
+/// A linear layer without bias that LoRA adapters can be attached to.
+#[derive(Debug, Clone)]
+pub struct LoraLinear {
+    base: candle_nn::Linear,
+    adapters: Vec<Lora>,
+}
+
+impl LoraLinear {
+    pub fn new(in_dim: usize, out_dim: usize, vb: VarBuilder) -> candle_core::Result<Self> {
+        let base = candle_nn::linear_no_bias(in_dim, out_dim, vb)?;
+        Ok(Self {
+            base,
+            adapters: Vec::new(),
+        })
+    }
+
+    fn check_shapes(&self, module: &str, a: &Tensor, b: &Tensor) -> Result<()> {
+        let (out_dim, in_dim) = self.base.weight().dims2()?;
+        let (r, a_in_dim) = a.dims2()?;
+        let (b_out_dim, b_r) = b.dims2()?;
+        if a_in_dim != in_dim || b_out_dim != out_dim || b_r != r {
+            return Err(E::msg(format!(
+                "lora shapes of {module} do not match: A {:?}, B {:?} for a weight of {:?}",
+                a.dims(),
+                b.dims(),
+                self.base.weight().dims()
+            )));
+        }
+        Ok(())
+    }
+
+    fn add_adapter(
+        &mut self,
+        name: &str,
+        a: &Tensor,
+        b: &Tensor,
+        scaling: f64,
+        scale: f64,
+    ) -> Result<()> {
+        let weight = self.base.weight();
+        let a = a.to_device(weight.device())?.to_dtype(weight.dtype())?;
+        let b = b.to_device(weight.device())?.to_dtype(weight.dtype())?;
+        self.adapters.push(Lora {
+            name: name.to_string(),
+            a: candle_nn::Linear::new(a, None),
+            b: candle_nn::Linear::new(b, None),
+            scale,
+            scaling,
+        });
+        Ok(())
+    }
+
+    // W' = W + scaling * scale * B A
+    fn merge_adapter(&mut self, name: &str) -> Result<()> {
+        if let Some(i) = self.adapters.iter().position(|lora| lora.name == name) {
+            let lora = self.adapters.remove(i);
+            let delta = lora.b.weight().matmul(lora.a.weight())?;
+            let weight = (self.base.weight() + (delta * (lora.scaling * lora.scale))?)?;
+            self.base = candle_nn::Linear::new(weight, None);
+        }
+        Ok(())
+    }
+}
+
+impl Module for LoraLinear {
+    fn forward(&self, xs: &Tensor) -> candle_core::Result<Tensor> {
+        let mut ys = self.base.forward(xs)?;
+        for lora in self.adapters.iter().filter(|lora| lora.scale != 0.0) {
+            let delta = xs.apply(&lora.a)?.apply(&lora.b)?;
+            ys = (ys + (delta * (lora.scaling * lora.scale))?)?;
+        }
+        Ok(ys)
+    }
+}
+
+/// A model whose linear layers LoRA adapters can be attached to.
+pub trait LoraModel {
+    /// Every linear an adapter can target, with its module path.
+    fn lora_linears(&mut self) -> Result<Vec<(String, &mut LoraLinear)>>;
+
+    /// Attaches the adapter, applied at runtime with `scale` until it is merged or removed.
+    fn load_lora(&mut self, adapter: &LoraAdapter, scale: f64) -> Result<()> {
+        if self
+            .lora_adapters()?
+            .iter()
+            .any(|(name, _)| name == adapter.name())
+        {
+            return Err(E::msg(format!("lora {} is already loaded", adapter.name())));
+        }
+        let mut linears = self.lora_linears()?.into_iter().collect::<HashMap<_, _>>();
+        // check every module first not to load the adapter partially
+        for (module, (a, b)) in adapter.weights.iter() {
+            match linears.get(module) {
+                Some(linear) => linear.check_shapes(module, a, b)?,
+                None => return Err(E::msg(format!("unsupported lora module: {module}"))),
+            }
+        }
+        for (module, (a, b)) in adapter.weights.iter() {
+            if let Some(linear) = linears.get_mut(module) {
+                linear.add_adapter(adapter.name(), a, b, adapter.config.scaling(), scale)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Changes the scale of a loaded adapter, `0.0` disables it.
+    fn set_lora_scale(&mut self, name: &str, scale: f64) -> Result<()> {
+        let mut found = false;
+        for (_, linear) in self.lora_linears()? {
+            for lora in linear.adapters.iter_mut().filter(|lora| lora.name == name) {
+                lora.scale = scale;
+                found = true;
+            }
+        }
+        match found {
+            true => Ok(()),
+            false => Err(E::msg(format!("lora {name} is not loaded"))),
+        }
+    }
+
+    fn remove_lora(&mut self, name: &str) -> Result<()> {
+        for (_, linear) in self.lora_linears()? {
+            linear.adapters.retain(|lora| lora.name != name);
+        }
+        Ok(())
+    }
+
+    /// Merges the adapter into the base weights with its current scale.
+    /// It can not be scaled or removed afterwards, but costs nothing at runtime.
+    fn merge_lora(&mut self, name: &str) -> Result<()> {
+        if !self
+            .lora_adapters()?
+            .iter()
+            .any(|(loaded, _)| loaded == name)
+        {
+            return Err(E::msg(format!("lora {name} is not loaded")));
+        }
+        for (_, linear) in self.lora_linears()? {
+            linear.merge_adapter(name)?;
+        }
+        Ok(())
+    }
+
+    /// The names and the scales of the loaded adapters, in the order they were loaded.
+    fn lora_adapters(&mut self) -> Result<Vec<(String, f64)>> {
+        let mut adapters: Vec<(String, f64)> = Vec::new();
+        for (_, linear) in self.lora_linears()? {
+            for lora in linear.adapters.iter() {
+                if !adapters.iter().any(|(name, _)| name == &lora.name) {
+                    adapters.push((lora.name.clone(), lora.scale));
+                }
+            }
+        }
+        Ok(adapters)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::DType;
+    use candle_nn::VarMap;
+
+    fn values(xs: &Tensor) -> Vec<f32> {
+        xs.flatten_all().unwrap().to_vec1().unwrap()
+    }
+
+    fn assert_close(xs: &Tensor, ys: &Tensor) {
+        for (x, y) in values(xs).iter().zip(values(ys).iter()) {
+            assert!((x - y).abs() < 1e-4, "{x} != {y}");
+        }
+    }
+
+    #[test]
+    fn test_from_files() {
+        // unique to the process, test runs can share the temp dir
+        let dir = std::env::temp_dir().join(format!(
+            "dartrs_test_lora_from_files_{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("adapter_config.json"),
+            r#"{"r": 4, "lora_alpha": 8, "target_modules": ["q_proj"]}"#,
+        )
+        .unwrap();
+        let a = Tensor::zeros((4, 8), DType::F32, &Device::Cpu).unwrap();
+        let b = Tensor::zeros((16, 4), DType::F32, &Device::Cpu).unwrap();
+        let module = "base_model.model.model.layers.0.self_attn.q_proj";
+        let tensors = HashMap::from([
+            (format!("{module}.lora_A.weight"), a),
+            (format!("{module}.lora_B.weight"), b),
+        ]);
+        candle_core::safetensors::save(&tensors, dir.join("adapter_model.safetensors")).unwrap();
+
+        let adapter = LoraAdapter::from_dir("style", &dir).unwrap();
+        assert_eq!(adapter.name(), "style");
+        assert_eq!(adapter.config().scaling(), 2.0);
+        let (a, b) = &adapter.weights["model.layers.0.self_attn.q_proj"];
+        assert_eq!(a.dims(), &[4, 8]);
+        assert_eq!(b.dims(), &[16, 4]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_merge_matches_runtime() {
+        let varmap = VarMap::new();
+        let vb = VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu);
+        let mut linear = LoraLinear::new(8, 16, vb).unwrap();
+        let xs = Tensor::randn(0f32, 1., (1, 3, 8), &Device::Cpu).unwrap();
+        let base = linear.forward(&xs).unwrap();
+
+        let a = Tensor::randn(0f32, 1., (2, 8), &Device::Cpu).unwrap();
+        let b = Tensor::randn(0f32, 1., (16, 2), &Device::Cpu).unwrap();
+        linear.add_adapter("style", &a, &b, 0.5, 0.0).unwrap();
+        assert_close(&linear.forward(&xs).unwrap(), &base);
+
+        linear.adapters[0].scale = 2.0;
+        let runtime = linear.forward(&xs).unwrap();
+        let delta = xs.matmul(&b.matmul(&a).unwrap().t().unwrap().unsqueeze(0).unwrap());
+        assert_close(&runtime, &(base + delta.unwrap()).unwrap());
+
+        linear.merge_adapter("style").unwrap();
+        assert!(linear.adapters.is_empty());
+        assert_close(&linear.forward(&xs).unwrap(), &runtime);
+    }
+}
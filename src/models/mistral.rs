@@ -1,11 +1,16 @@
 // copied and modified from https://github.com/huggingface/candle/blob/3ad4770eb61be34e6d2a7914a935b007d8dee49f/candle-transformers/src/models/mistral.rs
 
 /// Mistral LLM, https://github.com/mistralai/mistral-src
-use candle_core::{DType, Device, Module, Result, Tensor, D};
+use candle_core::{DType, Device, Module, Result, Tensor};
 use candle_nn::{Activation, VarBuilder};
 use candle_transformers::models::with_tracing::{linear_no_bias, Linear, RmsNorm};
 use std::sync::Arc;
 
+use crate::models::attention_weights::{split_by_token, TokenAttention};
+use crate::models::chunked_attention::chunked_attention;
+use crate::models::kv_cache::{KvCache, KvCacheTokens};
+use crate::models::lora::{LoraLinear, LoraModel};
+
 #[derive(Debug, Clone, PartialEq, serde::Deserialize)]
 pub struct Config {
     pub vocab_size: usize,
@@ -19,7 +24,12 @@ pub struct Config {
     pub rms_norm_eps: f64,
     pub rope_theta: f64,
     pub sliding_window: Option<usize>,
+    #[serde(default)]
     pub use_flash_attn: bool,
+    /// Computes the attention over chunks of this many keys instead of all at once, to save memory on CPU.
+    /// flash-attn takes precedence when both are set.
+    #[serde(default)]
+    pub attention_chunk_size: Option<usize>,
 }
 
 impl Config {
@@ -71,9 +81,9 @@ impl RotaryEmbedding {
 #[derive(Debug, Clone)]
 #[allow(clippy::upper_case_acronyms)]
 struct MLP {
-    gate_proj: Linear,
-    up_proj: Linear,
-    down_proj: Linear,
+    gate_proj: LoraLinear,
+    up_proj: LoraLinear,
+    down_proj: LoraLinear,
     act_fn: Activation,
 }
 
@@ -81,9 +91,9 @@ impl MLP {
     fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
         let hidden_sz = cfg.hidden_size;
         let intermediate_sz = cfg.intermediate_size;
-        let gate_proj = linear_no_bias(hidden_sz, intermediate_sz, vb.pp("gate_proj"))?;
-        let up_proj = linear_no_bias(hidden_sz, intermediate_sz, vb.pp("up_proj"))?;
-        let down_proj = linear_no_bias(intermediate_sz, hidden_sz, vb.pp("down_proj"))?;
+        let gate_proj = LoraLinear::new(hidden_sz, intermediate_sz, vb.pp("gate_proj"))?;
+        let up_proj = LoraLinear::new(hidden_sz, intermediate_sz, vb.pp("up_proj"))?;
+        let down_proj = LoraLinear::new(intermediate_sz, hidden_sz, vb.pp("down_proj"))?;
         Ok(Self {
             gate_proj,
             up_proj,
@@ -114,23 +124,24 @@ fn flash_attn(
 
 #[cfg(not(feature = "flash-attn"))]
 fn flash_attn(_: &Tensor, _: &Tensor, _: &Tensor, _: f32, _: bool) -> Result<Tensor> {
-    unimplemented!("compile with '--features flash-attn'")
+    candle_core::bail!("compile with '--features flash-attn'")
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 struct Attention {
-    q_proj: Linear,
-    k_proj: Linear,
-    v_proj: Linear,
-    o_proj: Linear,
+    q_proj: LoraLinear,
+    k_proj: LoraLinear,
+    v_proj: LoraLinear,
+    o_proj: LoraLinear,
     num_heads: usize,
     num_kv_heads: usize,
     num_kv_groups: usize,
     head_dim: usize,
     hidden_size: usize,
     rotary_emb: Arc<RotaryEmbedding>,
-    kv_cache: Option<(Tensor, Tensor)>,
+    kv_cache: KvCache,
     use_flash_attn: bool,
+    attention_chunk_size: Option<usize>,
 }
 
 impl Attention {
@@ -140,10 +151,10 @@ impl Attention {
         let num_kv_heads = cfg.num_key_value_heads;
         let num_kv_groups = num_heads / num_kv_heads;
         let head_dim = hidden_sz / num_heads;
-        let q_proj = linear_no_bias(hidden_sz, num_heads * head_dim, vb.pp("q_proj"))?;
-        let k_proj = linear_no_bias(hidden_sz, num_kv_heads * head_dim, vb.pp("k_proj"))?;
-        let v_proj = linear_no_bias(hidden_sz, num_kv_heads * head_dim, vb.pp("v_proj"))?;
-        let o_proj = linear_no_bias(num_heads * head_dim, hidden_sz, vb.pp("o_proj"))?;
+        let q_proj = LoraLinear::new(hidden_sz, num_heads * head_dim, vb.pp("q_proj"))?;
+        let k_proj = LoraLinear::new(hidden_sz, num_kv_heads * head_dim, vb.pp("k_proj"))?;
+        let v_proj = LoraLinear::new(hidden_sz, num_kv_heads * head_dim, vb.pp("v_proj"))?;
+        let o_proj = LoraLinear::new(num_heads * head_dim, hidden_sz, vb.pp("o_proj"))?;
         Ok(Self {
             q_proj,
             k_proj,
@@ -155,16 +166,39 @@ impl Attention {
             head_dim,
             hidden_size: hidden_sz,
             rotary_emb,
-            kv_cache: None,
+            kv_cache: KvCache::new(2, cfg.max_position_embeddings)
+                .with_sliding_window(cfg.sliding_window),
             use_flash_attn: cfg.use_flash_attn,
+            attention_chunk_size: cfg.attention_chunk_size,
+        })
+    }
+
+    fn try_clone(&self) -> Result<Self> {
+        Ok(Self {
+            q_proj: self.q_proj.clone(),
+            k_proj: self.k_proj.clone(),
+            v_proj: self.v_proj.clone(),
+            o_proj: self.o_proj.clone(),
+            num_heads: self.num_heads,
+            num_kv_heads: self.num_kv_heads,
+            num_kv_groups: self.num_kv_groups,
+            head_dim: self.head_dim,
+            hidden_size: self.hidden_size,
+            rotary_emb: self.rotary_emb.clone(),
+            kv_cache: self.kv_cache.try_clone()?,
+            use_flash_attn: self.use_flash_attn,
+            attention_chunk_size: self.attention_chunk_size,
         })
     }
 
+    /// Pushes the attention weights to `attention_weights` when it is given,
+    /// except with flash-attn or the chunked attention.
     fn forward(
         &mut self,
         xs: &Tensor,
         attention_mask: Option<&Tensor>,
         seqlen_offset: usize,
+        attention_weights: Option<&mut Vec<Tensor>>,
     ) -> Result<Tensor> {
         let (b_sz, q_len, _) = xs.dims3()?;
 
@@ -188,15 +222,7 @@ impl Attention {
             self.rotary_emb
                 .apply_rotary_emb_qkv(&query_states, &key_states, seqlen_offset)?;
 
-        let (key_states, value_states) = match &self.kv_cache {
-            None => (key_states, value_states),
-            Some((prev_k, prev_v)) => {
-                let key_states = Tensor::cat(&[prev_k, &key_states], 2)?;
-                let value_states = Tensor::cat(&[prev_v, &value_states], 2)?;
-                (key_states, value_states)
-            }
-        };
-        self.kv_cache = Some((key_states.clone(), value_states.clone()));
+        let (key_states, value_states) = self.kv_cache.append(&key_states, &value_states)?;
 
         let key_states = candle_transformers::utils::repeat_kv(key_states, self.num_kv_groups)?;
         let value_states = candle_transformers::utils::repeat_kv(value_states, self.num_kv_groups)?;
@@ -208,6 +234,16 @@ impl Attention {
             let v = value_states.transpose(1, 2)?;
             let softmax_scale = 1f32 / (self.head_dim as f32).sqrt();
             flash_attn(&q, &k, &v, softmax_scale, q_len > 1)?.transpose(1, 2)?
+        } else if let Some(chunk_size) = self.attention_chunk_size {
+            let scale = 1f64 / f64::sqrt(self.head_dim as f64);
+            chunked_attention(
+                &query_states,
+                &key_states,
+                &value_states,
+                attention_mask,
+                scale,
+                chunk_size,
+            )?
         } else {
             let scale = 1f64 / f64::sqrt(self.head_dim as f64);
             let attn_weights = (query_states.matmul(&key_states.transpose(2, 3)?)? * scale)?;
@@ -217,6 +253,9 @@ impl Attention {
                 Some(mask) => attn_weights.broadcast_add(mask)?,
             };
             let attn_weights = candle_nn::ops::softmax_last_dim(&attn_weights)?;
+            if let Some(attention_weights) = attention_weights {
+                attention_weights.push(attn_weights.to_dtype(DType::F32)?);
+            }
             attn_weights.matmul(&value_states)?
         };
         attn_output
@@ -226,11 +265,16 @@ impl Attention {
     }
 
     fn clear_kv_cache(&mut self) {
-        self.kv_cache = None
+        self.kv_cache.reset()
+    }
+
+    fn truncate_kv_cache(&mut self, len: usize) -> Result<()> {
+        self.kv_cache.truncate(len);
+        Ok(())
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 struct DecoderLayer {
     self_attn: Attention,
     mlp: MLP,
@@ -257,15 +301,27 @@ impl DecoderLayer {
         })
     }
 
+    fn try_clone(&self) -> Result<Self> {
+        Ok(Self {
+            self_attn: self.self_attn.try_clone()?,
+            mlp: self.mlp.clone(),
+            input_layernorm: self.input_layernorm.clone(),
+            post_attention_layernorm: self.post_attention_layernorm.clone(),
+        })
+    }
+
     fn forward(
         &mut self,
         xs: &Tensor,
         attention_mask: Option<&Tensor>,
         seqlen_offset: usize,
+        attention_weights: Option<&mut Vec<Tensor>>,
     ) -> Result<Tensor> {
         let residual = xs;
         let xs = self.input_layernorm.forward(xs)?;
-        let xs = self.self_attn.forward(&xs, attention_mask, seqlen_offset)?;
+        let xs = self
+            .self_attn
+            .forward(&xs, attention_mask, seqlen_offset, attention_weights)?;
         let xs = (xs + residual)?;
         let residual = &xs;
         let xs = xs.apply(&self.post_attention_layernorm)?.apply(&self.mlp)?;
@@ -275,15 +331,23 @@ impl DecoderLayer {
     fn clear_kv_cache(&mut self) {
         self.self_attn.clear_kv_cache()
     }
+
+    fn truncate_kv_cache(&mut self, len: usize) -> Result<()> {
+        self.self_attn.truncate_kv_cache(len)
+    }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct Model {
     embed_tokens: candle_nn::Embedding,
     layers: Vec<DecoderLayer>,
     norm: RmsNorm,
     lm_head: Linear,
     sliding_window: Option<usize>,
+    max_position_embeddings: usize,
+    // `Some` while the attention weights are recorded
+    attention_weights: Option<Vec<TokenAttention>>,
+    kv_cache_tokens: KvCacheTokens,
     device: Device,
     dtype: DType,
 }
@@ -308,20 +372,47 @@ impl Model {
             norm,
             lm_head,
             sliding_window: cfg.sliding_window,
+            max_position_embeddings: cfg.max_position_embeddings,
+            attention_weights: None,
+            kv_cache_tokens: KvCacheTokens::default(),
             device: vb.device().clone(),
             dtype: vb.dtype(),
         })
     }
 
+    /// Copies the model with its own kv cache, the weights are shared.
+    pub fn try_clone(&self) -> Result<Self> {
+        Ok(Self {
+            embed_tokens: self.embed_tokens.clone(),
+            layers: self
+                .layers
+                .iter()
+                .map(DecoderLayer::try_clone)
+                .collect::<Result<_>>()?,
+            norm: self.norm.clone(),
+            lm_head: self.lm_head.clone(),
+            sliding_window: self.sliding_window,
+            max_position_embeddings: self.max_position_embeddings,
+            attention_weights: self.attention_weights.clone(),
+            kv_cache_tokens: self.kv_cache_tokens.clone(),
+            device: self.device.clone(),
+            dtype: self.dtype,
+        })
+    }
+
     fn prepare_decoder_attention_mask(
         &self,
         tgt_len: usize,
         seqlen_offset: usize,
     ) -> Result<Tensor> {
-        let sliding_window = self.sliding_window.unwrap_or(tgt_len + 1);
+        let sliding_window = self.sliding_window.unwrap_or(tgt_len + seqlen_offset + 1);
+        // the kv cache only returns the keys within the sliding window of the first query
+        let start = seqlen_offset.saturating_sub(sliding_window);
+        let src_len = seqlen_offset + tgt_len - start;
         let mask: Vec<_> = (0..tgt_len)
             .flat_map(|i| {
-                (0..tgt_len).map(move |j| {
+                (0..src_len).map(move |j| {
+                    let (i, j) = (i + seqlen_offset, j + start);
                     if i < j || j + sliding_window < i {
                         f32::NEG_INFINITY
                     } else {
@@ -330,18 +421,17 @@ impl Model {
                 })
             })
             .collect();
-        let mask = Tensor::from_slice(&mask, (tgt_len, tgt_len), &self.device)?;
-        let mask = if seqlen_offset > 0 {
-            let mask0 = Tensor::zeros((tgt_len, seqlen_offset), DType::F32, &self.device)?;
-            Tensor::cat(&[&mask0, &mask], D::Minus1)?
-        } else {
-            mask
-        };
-        mask.expand((1, 1, tgt_len, tgt_len + seqlen_offset))?
-            .to_dtype(self.dtype)
+        let mask = Tensor::from_slice(&mask, (tgt_len, src_len), &self.device)?;
+        mask.expand((1, 1, tgt_len, src_len))?.to_dtype(self.dtype)
     }
 
-    pub fn forward(&mut self, input_ids: &Tensor, seqlen_offset: usize) -> Result<Tensor> {
+    fn forward_hidden_states(
+        &mut self,
+        input_ids: &Tensor,
+        seqlen_offset: usize,
+    ) -> Result<Tensor> {
+        // left empty when the forward fails, since some layers may have cached the input
+        let mut kv_cache_tokens = std::mem::take(&mut self.kv_cache_tokens);
         let (_b_size, seq_len) = input_ids.dims2()?;
         let attention_mask = if seq_len <= 1 {
             None
@@ -350,20 +440,99 @@ impl Model {
             Some(mask)
         };
         let mut xs = self.embed_tokens.forward(input_ids)?;
+        let record_attention = self.attention_weights.is_some();
+        let mut attention_weights = Vec::new();
         for layer in self.layers.iter_mut() {
-            xs = layer.forward(&xs, attention_mask.as_ref(), seqlen_offset)?
+            let attention_weights_mut = if record_attention {
+                Some(&mut attention_weights)
+            } else {
+                None
+            };
+            xs = layer.forward(
+                &xs,
+                attention_mask.as_ref(),
+                seqlen_offset,
+                attention_weights_mut,
+            )?
         }
-        xs.narrow(1, seq_len - 1, 1)?
+        if let Some(recorded) = self.attention_weights.as_mut() {
+            recorded.extend(split_by_token(&attention_weights, seqlen_offset)?);
+        }
+        kv_cache_tokens.append(input_ids, seqlen_offset)?;
+        self.kv_cache_tokens = kv_cache_tokens;
+        Ok(xs)
+    }
+
+    pub fn forward(&mut self, input_ids: &Tensor, seqlen_offset: usize) -> Result<Tensor> {
+        let (_b_size, seq_len) = input_ids.dims2()?;
+        self.forward_hidden_states(input_ids, seqlen_offset)?
+            .narrow(1, seq_len - 1, 1)?
             .apply(&self.norm)?
             .apply(&self.lm_head)
     }
 
+    /// Returns the logits of every input position instead of only the last one.
+    pub fn forward_all(&mut self, input_ids: &Tensor, seqlen_offset: usize) -> Result<Tensor> {
+        self.forward_hidden_states(input_ids, seqlen_offset)?
+            .apply(&self.norm)?
+            .apply(&self.lm_head)
+    }
+
+    /// Returns the final normed hidden states of every input position, `(b_size, seq_len, hidden_size)`.
+    /// The input is fed from the position 0 and the kv cache of the generation is kept as is,
+    /// the attention weights are not recorded.
+    pub fn hidden_states(&mut self, input_ids: &Tensor) -> Result<Tensor> {
+        let (_b_size, seq_len) = input_ids.dims2()?;
+        let kv_caches = self
+            .layers
+            .iter_mut()
+            .map(|layer| layer.self_attn.kv_cache.replace_with_empty(seq_len))
+            .collect::<Vec<_>>();
+        let kv_cache_tokens = std::mem::take(&mut self.kv_cache_tokens);
+        // not a generation step, nothing is recorded
+        let attention_weights = self.attention_weights.take();
+        let hidden_states = self
+            .forward_hidden_states(input_ids, 0)
+            .and_then(|xs| xs.apply(&self.norm));
+        for (layer, kv_cache) in self.layers.iter_mut().zip(kv_caches) {
+            layer.self_attn.kv_cache = kv_cache;
+        }
+        self.kv_cache_tokens = kv_cache_tokens;
+        self.attention_weights = attention_weights;
+        hidden_states
+    }
+
+    /// The mean of `hidden_states` over the positions, `(b_size, hidden_size)`.
+    pub fn pooled_hidden_states(&mut self, input_ids: &Tensor) -> Result<Tensor> {
+        self.hidden_states(input_ids)?.mean(1)
+    }
+
+    /// The input embeddings of the tokens from `embed_tokens`, `(b_size, seq_len, hidden_size)`.
+    pub fn token_embeddings(&self, input_ids: &Tensor) -> Result<Tensor> {
+        self.embed_tokens.forward(input_ids)
+    }
+
     pub fn clear_kv_cache(&mut self) {
+        self.kv_cache_tokens.clear();
         for layer in self.layers.iter_mut() {
             layer.clear_kv_cache()
         }
     }
 
+    /// Drops the cached keys and values after the first `len` positions.
+    pub fn truncate_kv_cache(&mut self, len: usize) -> Result<()> {
+        self.kv_cache_tokens.truncate(len);
+        for layer in self.layers.iter_mut() {
+            layer.truncate_kv_cache(len)?
+        }
+        Ok(())
+    }
+
+    /// The tokens whose keys and values are in the kv cache.
+    pub fn kv_cache_tokens(&self) -> &[u32] {
+        self.kv_cache_tokens.tokens()
+    }
+
     pub fn device(&self) -> &Device {
         &self.device
     }
@@ -371,4 +540,87 @@ impl Model {
     pub fn dtype(&self) -> DType {
         self.dtype
     }
+
+    pub fn max_position_embeddings(&self) -> usize {
+        self.max_position_embeddings
+    }
+
+    /// Starts or stops recording the attention weights of each token.
+    /// Stopping drops the weights not taken yet.
+    /// The weights are not computed with flash-attn or the chunked attention, so it can not be recorded.
+    pub fn record_attention_weights(&mut self, enabled: bool) -> Result<()> {
+        if enabled
+            && self.layers.iter().any(|layer| {
+                layer.self_attn.use_flash_attn || layer.self_attn.attention_chunk_size.is_some()
+            })
+        {
+            candle_core::bail!(
+                "the attention weights can not be recorded with flash-attn or the chunked attention"
+            )
+        }
+        self.attention_weights = if enabled { Some(Vec::new()) } else { None };
+        Ok(())
+    }
+
+    /// Returns the attention weights recorded since the last call, in the order the tokens were fed.
+    pub fn take_attention_weights(&mut self) -> Vec<TokenAttention> {
+        self.attention_weights
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+}
+
+impl LoraModel for Model {
+    fn lora_linears(&mut self) -> anyhow::Result<Vec<(String, &mut LoraLinear)>> {
+        let mut linears = Vec::new();
+        for (i, layer) in self.layers.iter_mut().enumerate() {
+            let prefix = format!("model.layers.{i}");
+            let Attention {
+                q_proj,
+                k_proj,
+                v_proj,
+                o_proj,
+                ..
+            } = &mut layer.self_attn;
+            for (name, linear) in [
+                ("q_proj", q_proj),
+                ("k_proj", k_proj),
+                ("v_proj", v_proj),
+                ("o_proj", o_proj),
+            ] {
+                linears.push((format!("{prefix}.self_attn.{name}"), linear));
+            }
+            let MLP {
+                gate_proj,
+                up_proj,
+                down_proj,
+                ..
+            } = &mut layer.mlp;
+            for (name, linear) in [
+                ("gate_proj", gate_proj),
+                ("up_proj", up_proj),
+                ("down_proj", down_proj),
+            ] {
+                linears.push((format!("{prefix}.mlp.{name}"), linear));
+            }
+        }
+        Ok(linears)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::test_utils::{
+        chunked_attention_tests, hidden_states_tests, lora_tests, tiny_config, values, var_builder,
+    };
+
+    fn model() -> Model {
+        Model::new(&tiny_config(), var_builder()).unwrap()
+    }
+
+    hidden_states_tests!();
+    lora_tests!("model.layers.1.mlp.down_proj");
+    chunked_attention_tests!();
 }
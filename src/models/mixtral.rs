@@ -9,6 +9,11 @@ use candle_transformers::models::with_tracing::{linear_no_bias, Linear, RmsNorm}
 use serde::Deserialize;
 use std::sync::Arc;
 
+use crate::models::attention_weights::{split_by_token, TokenAttention};
+use crate::models::chunked_attention::chunked_attention;
+use crate::models::kv_cache::{KvCache, KvCacheTokens};
+use crate::models::lora::{LoraLinear, LoraModel};
+
 /// https://github.com/huggingface/transformers/blob/1a585c1222a56bcaecc070966d558d4a9d862e83/src/transformers/models/mixtral/configuration_mixtral.py#L113
 #[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct Config {
@@ -25,7 +30,12 @@ pub struct Config {
     pub sliding_window: Option<usize>,
     pub num_experts_per_tok: usize,
     pub num_local_experts: usize,
+    #[serde(default)]
     pub use_flash_attn: bool,
+    /// Computes the attention over chunks of this many keys instead of all at once, to save memory on CPU.
+    /// flash-attn takes precedence when both are set.
+    #[serde(default)]
+    pub attention_chunk_size: Option<usize>,
 }
 
 impl Config {
@@ -96,23 +106,24 @@ fn flash_attn(
 
 #[cfg(not(feature = "flash-attn"))]
 fn flash_attn(_: &Tensor, _: &Tensor, _: &Tensor, _: f32, _: bool) -> Result<Tensor> {
-    unimplemented!("compile with '--features flash-attn'")
+    candle_core::bail!("compile with '--features flash-attn'")
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 struct Attention {
-    q_proj: Linear,
-    k_proj: Linear,
-    v_proj: Linear,
-    o_proj: Linear,
+    q_proj: LoraLinear,
+    k_proj: LoraLinear,
+    v_proj: LoraLinear,
+    o_proj: LoraLinear,
     num_heads: usize,
     num_kv_heads: usize,
     num_kv_groups: usize,
     head_dim: usize,
     hidden_size: usize,
     rotary_emb: Arc<RotaryEmbedding>,
-    kv_cache: Option<(Tensor, Tensor)>,
+    kv_cache: KvCache,
     use_flash_attn: bool,
+    attention_chunk_size: Option<usize>,
 }
 
 impl Attention {
@@ -122,10 +133,10 @@ impl Attention {
         let num_kv_heads = cfg.num_key_value_heads;
         let num_kv_groups = num_heads / num_kv_heads;
         let head_dim = hidden_sz / num_heads;
-        let q_proj = linear_no_bias(hidden_sz, num_heads * head_dim, vb.pp("q_proj"))?;
-        let k_proj = linear_no_bias(hidden_sz, num_kv_heads * head_dim, vb.pp("k_proj"))?;
-        let v_proj = linear_no_bias(hidden_sz, num_kv_heads * head_dim, vb.pp("v_proj"))?;
-        let o_proj = linear_no_bias(num_heads * head_dim, hidden_sz, vb.pp("o_proj"))?;
+        let q_proj = LoraLinear::new(hidden_sz, num_heads * head_dim, vb.pp("q_proj"))?;
+        let k_proj = LoraLinear::new(hidden_sz, num_kv_heads * head_dim, vb.pp("k_proj"))?;
+        let v_proj = LoraLinear::new(hidden_sz, num_kv_heads * head_dim, vb.pp("v_proj"))?;
+        let o_proj = LoraLinear::new(num_heads * head_dim, hidden_sz, vb.pp("o_proj"))?;
         Ok(Self {
             q_proj,
             k_proj,
@@ -137,16 +148,39 @@ impl Attention {
             head_dim,
             hidden_size: hidden_sz,
             rotary_emb,
-            kv_cache: None,
+            kv_cache: KvCache::new(2, cfg.max_position_embeddings)
+                .with_sliding_window(cfg.sliding_window),
             use_flash_attn: cfg.use_flash_attn,
+            attention_chunk_size: cfg.attention_chunk_size,
+        })
+    }
+
+    fn try_clone(&self) -> Result<Self> {
+        Ok(Self {
+            q_proj: self.q_proj.clone(),
+            k_proj: self.k_proj.clone(),
+            v_proj: self.v_proj.clone(),
+            o_proj: self.o_proj.clone(),
+            num_heads: self.num_heads,
+            num_kv_heads: self.num_kv_heads,
+            num_kv_groups: self.num_kv_groups,
+            head_dim: self.head_dim,
+            hidden_size: self.hidden_size,
+            rotary_emb: self.rotary_emb.clone(),
+            kv_cache: self.kv_cache.try_clone()?,
+            use_flash_attn: self.use_flash_attn,
+            attention_chunk_size: self.attention_chunk_size,
         })
     }
 
+    /// Pushes the attention weights to `attention_weights` when it is given,
+    /// except with flash-attn or the chunked attention.
     fn forward(
         &mut self,
         xs: &Tensor,
         attention_mask: Option<&Tensor>,
         seqlen_offset: usize,
+        attention_weights: Option<&mut Vec<Tensor>>,
     ) -> Result<Tensor> {
         let (b_sz, q_len, _) = xs.dims3()?;
 
@@ -168,15 +202,7 @@ impl Attention {
             self.rotary_emb
                 .apply_rotary_emb_qkv(&query_states, &key_states, seqlen_offset)?;
 
-        let (key_states, value_states) = match &self.kv_cache {
-            None => (key_states, value_states),
-            Some((prev_k, prev_v)) => {
-                let key_states = Tensor::cat(&[prev_k, &key_states], 2)?;
-                let value_states = Tensor::cat(&[prev_v, &value_states], 2)?;
-                (key_states, value_states)
-            }
-        };
-        self.kv_cache = Some((key_states.clone(), value_states.clone()));
+        let (key_states, value_states) = self.kv_cache.append(&key_states, &value_states)?;
 
         let key_states = candle_transformers::utils::repeat_kv(key_states, self.num_kv_groups)?;
         let value_states = candle_transformers::utils::repeat_kv(value_states, self.num_kv_groups)?;
@@ -188,6 +214,16 @@ impl Attention {
             let v = value_states.transpose(1, 2)?;
             let softmax_scale = 1f32 / (self.head_dim as f32).sqrt();
             flash_attn(&q, &k, &v, softmax_scale, q_len > 1)?.transpose(1, 2)?
+        } else if let Some(chunk_size) = self.attention_chunk_size {
+            let scale = 1f64 / f64::sqrt(self.head_dim as f64);
+            chunked_attention(
+                &query_states,
+                &key_states,
+                &value_states,
+                attention_mask,
+                scale,
+                chunk_size,
+            )?
         } else {
             let scale = 1f64 / f64::sqrt(self.head_dim as f64);
             let attn_weights = (query_states.matmul(&key_states.transpose(2, 3)?)? * scale)?;
@@ -197,6 +233,9 @@ impl Attention {
                 Some(mask) => attn_weights.broadcast_add(mask)?,
             };
             let attn_weights = candle_nn::ops::softmax_last_dim(&attn_weights)?;
+            if let Some(attention_weights) = attention_weights {
+                attention_weights.push(attn_weights.to_dtype(DType::F32)?);
+            }
             attn_weights.matmul(&value_states)?
         };
         attn_output
@@ -206,15 +245,20 @@ impl Attention {
     }
 
     fn clear_kv_cache(&mut self) {
-        self.kv_cache = None
+        self.kv_cache.reset()
+    }
+
+    fn truncate_kv_cache(&mut self, len: usize) -> Result<()> {
+        self.kv_cache.truncate(len);
+        Ok(())
     }
 }
 
 #[derive(Debug, Clone)]
 struct BlockSparseTop2MLP {
-    w1: Linear,
-    w2: Linear,
-    w3: Linear,
+    w1: LoraLinear,
+    w2: LoraLinear,
+    w3: LoraLinear,
     act_fn: Activation,
 }
 
@@ -222,9 +266,9 @@ impl BlockSparseTop2MLP {
     fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
         let hidden_sz = cfg.hidden_size;
         let intermediate_sz = cfg.intermediate_size;
-        let w1 = linear_no_bias(hidden_sz, intermediate_sz, vb.pp("w1"))?;
-        let w2 = linear_no_bias(intermediate_sz, hidden_sz, vb.pp("w2"))?;
-        let w3 = linear_no_bias(hidden_sz, intermediate_sz, vb.pp("w3"))?;
+        let w1 = LoraLinear::new(hidden_sz, intermediate_sz, vb.pp("w1"))?;
+        let w2 = LoraLinear::new(intermediate_sz, hidden_sz, vb.pp("w2"))?;
+        let w3 = LoraLinear::new(hidden_sz, intermediate_sz, vb.pp("w3"))?;
         Ok(Self {
             w1,
             w2,
@@ -268,6 +312,17 @@ impl SparseMoeBlock {
 
 impl Module for SparseMoeBlock {
     fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        self.forward_with_routing(xs, None)
+    }
+}
+
+impl SparseMoeBlock {
+    /// Pushes the experts selected for each token to `routing` when it is given.
+    fn forward_with_routing(
+        &self,
+        xs: &Tensor,
+        mut routing: Option<&mut Vec<LayerRouting>>,
+    ) -> Result<Tensor> {
         let (b_size, seq_len, hidden_dim) = xs.dims3()?;
         let xs = xs.reshape(((), hidden_dim))?;
         let router_logits = xs.apply(&self.gate)?;
@@ -296,6 +351,18 @@ impl Module for SparseMoeBlock {
                 let routing_weight = rw[expert_idx];
                 selected_rws[expert_idx].push(routing_weight / sum_routing_weights)
             }
+            if let Some(routing) = routing.as_mut() {
+                let experts = dst
+                    .iter()
+                    .take(self.num_experts_per_tok)
+                    .map(|&expert_idx| expert_idx as usize)
+                    .collect::<Vec<_>>();
+                let weights = experts
+                    .iter()
+                    .map(|&expert_idx| rw[expert_idx] / sum_routing_weights)
+                    .collect();
+                routing.push(LayerRouting { experts, weights })
+            }
         }
 
         // routing_weights /= routing_weights.sum(dim=-1, keepdim=True)
@@ -326,7 +393,7 @@ impl Module for SparseMoeBlock {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 struct DecoderLayer {
     self_attn: Attention,
     block_sparse_moe: SparseMoeBlock,
@@ -353,35 +420,72 @@ impl DecoderLayer {
         })
     }
 
+    fn try_clone(&self) -> Result<Self> {
+        Ok(Self {
+            self_attn: self.self_attn.try_clone()?,
+            block_sparse_moe: self.block_sparse_moe.clone(),
+            input_layernorm: self.input_layernorm.clone(),
+            post_attention_layernorm: self.post_attention_layernorm.clone(),
+        })
+    }
+
     fn forward(
         &mut self,
         xs: &Tensor,
         attention_mask: Option<&Tensor>,
         seqlen_offset: usize,
+        routing: Option<&mut Vec<LayerRouting>>,
+        attention_weights: Option<&mut Vec<Tensor>>,
     ) -> Result<Tensor> {
         let residual = xs;
         let xs = self.input_layernorm.forward(xs)?;
-        let xs = self.self_attn.forward(&xs, attention_mask, seqlen_offset)?;
+        let xs = self
+            .self_attn
+            .forward(&xs, attention_mask, seqlen_offset, attention_weights)?;
         let xs = (xs + residual)?;
         let residual = &xs;
-        let xs = xs
-            .apply(&self.post_attention_layernorm)?
-            .apply(&self.block_sparse_moe)?;
+        let xs = xs.apply(&self.post_attention_layernorm)?;
+        let xs = self.block_sparse_moe.forward_with_routing(&xs, routing)?;
         residual + xs
     }
 
     fn clear_kv_cache(&mut self) {
         self.self_attn.clear_kv_cache()
     }
+
+    fn truncate_kv_cache(&mut self, len: usize) -> Result<()> {
+        self.self_attn.truncate_kv_cache(len)
+    }
 }
 
-#[derive(Debug, Clone)]
+/// The experts a token is routed to in one layer, with the routing weights
+/// normalized over the selected experts.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LayerRouting {
+    pub experts: Vec<usize>,
+    pub weights: Vec<f32>,
+}
+
+/// The routing of the token at `position` in every layer.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenRouting {
+    pub position: usize,
+    pub layers: Vec<LayerRouting>,
+}
+
+#[derive(Debug)]
 pub struct Model {
     embed_tokens: candle_nn::Embedding,
     layers: Vec<DecoderLayer>,
     norm: RmsNorm,
     lm_head: Linear,
     sliding_window: Option<usize>,
+    max_position_embeddings: usize,
+    // `Some` while the attention weights are recorded
+    attention_weights: Option<Vec<TokenAttention>>,
+    // `Some` while the expert routing is recorded
+    expert_routing: Option<Vec<TokenRouting>>,
+    kv_cache_tokens: KvCacheTokens,
     device: Device,
     dtype: DType,
 }
@@ -406,21 +510,50 @@ impl Model {
             norm,
             lm_head,
             sliding_window: cfg.sliding_window,
+            max_position_embeddings: cfg.max_position_embeddings,
+            attention_weights: None,
+            expert_routing: None,
+            kv_cache_tokens: KvCacheTokens::default(),
             device: vb.device().clone(),
             dtype: vb.dtype(),
         })
     }
 
+    /// Copies the model with its own kv cache, the weights are shared.
+    pub fn try_clone(&self) -> Result<Self> {
+        Ok(Self {
+            embed_tokens: self.embed_tokens.clone(),
+            layers: self
+                .layers
+                .iter()
+                .map(DecoderLayer::try_clone)
+                .collect::<Result<_>>()?,
+            norm: self.norm.clone(),
+            lm_head: self.lm_head.clone(),
+            sliding_window: self.sliding_window,
+            max_position_embeddings: self.max_position_embeddings,
+            attention_weights: self.attention_weights.clone(),
+            expert_routing: self.expert_routing.clone(),
+            kv_cache_tokens: self.kv_cache_tokens.clone(),
+            device: self.device.clone(),
+            dtype: self.dtype,
+        })
+    }
+
     fn prepare_decoder_attention_mask(
         &self,
         b_size: usize,
         tgt_len: usize,
         seqlen_offset: usize,
     ) -> Result<Tensor> {
-        let sliding_window = self.sliding_window.unwrap_or(tgt_len + 1);
+        let sliding_window = self.sliding_window.unwrap_or(tgt_len + seqlen_offset + 1);
+        // the kv cache only returns the keys within the sliding window of the first query
+        let start = seqlen_offset.saturating_sub(sliding_window);
+        let src_len = seqlen_offset + tgt_len - start;
         let mask: Vec<_> = (0..tgt_len)
             .flat_map(|i| {
-                (0..tgt_len).map(move |j| {
+                (0..src_len).map(move |j| {
+                    let (i, j) = (i + seqlen_offset, j + start);
                     if i < j || j + sliding_window < i {
                         f32::NEG_INFINITY
                     } else {
@@ -429,18 +562,18 @@ impl Model {
                 })
             })
             .collect();
-        let mask = Tensor::from_slice(&mask, (tgt_len, tgt_len), &self.device)?;
-        let mask = if seqlen_offset > 0 {
-            let mask0 = Tensor::zeros((tgt_len, seqlen_offset), DType::F32, &self.device)?;
-            Tensor::cat(&[&mask0, &mask], D::Minus1)?
-        } else {
-            mask
-        };
-        mask.expand((b_size, 1, tgt_len, tgt_len + seqlen_offset))?
+        let mask = Tensor::from_slice(&mask, (tgt_len, src_len), &self.device)?;
+        mask.expand((b_size, 1, tgt_len, src_len))?
             .to_dtype(self.dtype)
     }
 
-    pub fn forward(&mut self, input_ids: &Tensor, seqlen_offset: usize) -> Result<Tensor> {
+    fn forward_hidden_states(
+        &mut self,
+        input_ids: &Tensor,
+        seqlen_offset: usize,
+    ) -> Result<Tensor> {
+        // left empty when the forward fails, since some layers may have cached the input
+        let mut kv_cache_tokens = std::mem::take(&mut self.kv_cache_tokens);
         let (b_size, seq_len) = input_ids.dims2()?;
         let attention_mask = if seq_len <= 1 {
             None
@@ -449,20 +582,120 @@ impl Model {
             Some(mask)
         };
         let mut xs = self.embed_tokens.forward(input_ids)?;
+        let record_routing = self.expert_routing.is_some();
+        let record_attention = self.attention_weights.is_some();
+        let mut layer_routing = Vec::new();
+        let mut attention_weights = Vec::new();
         for layer in self.layers.iter_mut() {
-            xs = layer.forward(&xs, attention_mask.as_ref(), seqlen_offset)?
+            let mut routing = Vec::new();
+            let routing_mut = if record_routing {
+                Some(&mut routing)
+            } else {
+                None
+            };
+            let attention_weights_mut = if record_attention {
+                Some(&mut attention_weights)
+            } else {
+                None
+            };
+            xs = layer.forward(
+                &xs,
+                attention_mask.as_ref(),
+                seqlen_offset,
+                routing_mut,
+                attention_weights_mut,
+            )?;
+            layer_routing.push(routing.into_iter());
+        }
+        if let Some(recorded) = self.attention_weights.as_mut() {
+            recorded.extend(split_by_token(&attention_weights, seqlen_offset)?);
+        }
+        if let Some(expert_routing) = self.expert_routing.as_mut() {
+            // the routing is recorded per row of (b_size, seq_len), only the first sequence is kept
+            for i in 0..seq_len {
+                expert_routing.push(TokenRouting {
+                    position: seqlen_offset + i,
+                    layers: layer_routing.iter_mut().filter_map(|r| r.next()).collect(),
+                })
+            }
         }
-        xs.narrow(1, seq_len - 1, 1)?
+        kv_cache_tokens.append(input_ids, seqlen_offset)?;
+        self.kv_cache_tokens = kv_cache_tokens;
+        Ok(xs)
+    }
+
+    pub fn forward(&mut self, input_ids: &Tensor, seqlen_offset: usize) -> Result<Tensor> {
+        let (_b_size, seq_len) = input_ids.dims2()?;
+        self.forward_hidden_states(input_ids, seqlen_offset)?
+            .narrow(1, seq_len - 1, 1)?
+            .apply(&self.norm)?
+            .apply(&self.lm_head)
+    }
+
+    /// Returns the logits of every input position instead of only the last one.
+    pub fn forward_all(&mut self, input_ids: &Tensor, seqlen_offset: usize) -> Result<Tensor> {
+        self.forward_hidden_states(input_ids, seqlen_offset)?
             .apply(&self.norm)?
             .apply(&self.lm_head)
     }
 
+    /// Returns the final normed hidden states of every input position, `(b_size, seq_len, hidden_size)`.
+    /// The input is fed from the position 0 and the kv cache of the generation is kept as is,
+    /// the attention weights and the expert routing are not recorded.
+    pub fn hidden_states(&mut self, input_ids: &Tensor) -> Result<Tensor> {
+        let (_b_size, seq_len) = input_ids.dims2()?;
+        let kv_caches = self
+            .layers
+            .iter_mut()
+            .map(|layer| layer.self_attn.kv_cache.replace_with_empty(seq_len))
+            .collect::<Vec<_>>();
+        let kv_cache_tokens = std::mem::take(&mut self.kv_cache_tokens);
+        // not a generation step, nothing is recorded
+        let attention_weights = self.attention_weights.take();
+        let expert_routing = self.expert_routing.take();
+        let hidden_states = self
+            .forward_hidden_states(input_ids, 0)
+            .and_then(|xs| xs.apply(&self.norm));
+        for (layer, kv_cache) in self.layers.iter_mut().zip(kv_caches) {
+            layer.self_attn.kv_cache = kv_cache;
+        }
+        self.kv_cache_tokens = kv_cache_tokens;
+        self.attention_weights = attention_weights;
+        self.expert_routing = expert_routing;
+        hidden_states
+    }
+
+    /// The mean of `hidden_states` over the positions, `(b_size, hidden_size)`.
+    pub fn pooled_hidden_states(&mut self, input_ids: &Tensor) -> Result<Tensor> {
+        self.hidden_states(input_ids)?.mean(1)
+    }
+
+    /// The input embeddings of the tokens from `embed_tokens`, `(b_size, seq_len, hidden_size)`.
+    pub fn token_embeddings(&self, input_ids: &Tensor) -> Result<Tensor> {
+        self.embed_tokens.forward(input_ids)
+    }
+
     pub fn clear_kv_cache(&mut self) {
+        self.kv_cache_tokens.clear();
         for layer in self.layers.iter_mut() {
             layer.clear_kv_cache()
         }
     }
 
+    /// Drops the cached keys and values after the first `len` positions.
+    pub fn truncate_kv_cache(&mut self, len: usize) -> Result<()> {
+        self.kv_cache_tokens.truncate(len);
+        for layer in self.layers.iter_mut() {
+            layer.truncate_kv_cache(len)?
+        }
+        Ok(())
+    }
+
+    /// The tokens whose keys and values are in the kv cache.
+    pub fn kv_cache_tokens(&self) -> &[u32] {
+        self.kv_cache_tokens.tokens()
+    }
+
     pub fn device(&self) -> &Device {
         &self.device
     }
@@ -470,4 +703,132 @@ impl Model {
     pub fn dtype(&self) -> DType {
         self.dtype
     }
+
+    pub fn max_position_embeddings(&self) -> usize {
+        self.max_position_embeddings
+    }
+
+    /// Starts or stops recording the attention weights of each token.
+    /// Stopping drops the weights not taken yet.
+    /// The weights are not computed with flash-attn or the chunked attention, so it can not be recorded.
+    pub fn record_attention_weights(&mut self, enabled: bool) -> Result<()> {
+        if enabled
+            && self.layers.iter().any(|layer| {
+                layer.self_attn.use_flash_attn || layer.self_attn.attention_chunk_size.is_some()
+            })
+        {
+            candle_core::bail!(
+                "the attention weights can not be recorded with flash-attn or the chunked attention"
+            )
+        }
+        self.attention_weights = if enabled { Some(Vec::new()) } else { None };
+        Ok(())
+    }
+
+    /// Returns the attention weights recorded since the last call, in the order the tokens were fed.
+    pub fn take_attention_weights(&mut self) -> Vec<TokenAttention> {
+        self.attention_weights
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    /// Starts or stops recording the experts each token is routed to.
+    /// Stopping drops the routing not taken yet.
+    pub fn record_expert_routing(&mut self, enabled: bool) {
+        self.expert_routing = if enabled { Some(Vec::new()) } else { None };
+    }
+
+    /// Returns the routing recorded since the last call, in the order the tokens were fed.
+    pub fn take_expert_routing(&mut self) -> Vec<TokenRouting> {
+        self.expert_routing
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+}
+
+impl LoraModel for Model {
+    fn lora_linears(&mut self) -> anyhow::Result<Vec<(String, &mut LoraLinear)>> {
+        let mut linears = Vec::new();
+        for (i, layer) in self.layers.iter_mut().enumerate() {
+            let prefix = format!("model.layers.{i}");
+            let Attention {
+                q_proj,
+                k_proj,
+                v_proj,
+                o_proj,
+                ..
+            } = &mut layer.self_attn;
+            for (name, linear) in [
+                ("q_proj", q_proj),
+                ("k_proj", k_proj),
+                ("v_proj", v_proj),
+                ("o_proj", o_proj),
+            ] {
+                linears.push((format!("{prefix}.self_attn.{name}"), linear));
+            }
+            for (j, expert) in layer.block_sparse_moe.experts.iter_mut().enumerate() {
+                let BlockSparseTop2MLP { w1, w2, w3, .. } = expert;
+                for (name, linear) in [("w1", w1), ("w2", w2), ("w3", w3)] {
+                    linears.push((
+                        format!("{prefix}.block_sparse_moe.experts.{j}.{name}"),
+                        linear,
+                    ));
+                }
+            }
+        }
+        Ok(linears)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::test_utils::{
+        chunked_attention_tests, hidden_states_tests, lora_tests, tiny_config, values, var_builder,
+    };
+
+    fn model() -> Model {
+        Model::new(&tiny_config(), var_builder()).unwrap()
+    }
+
+    hidden_states_tests!();
+    lora_tests!("model.layers.1.block_sparse_moe.experts.0.w2");
+    chunked_attention_tests!();
+
+    #[test]
+    fn test_expert_routing() {
+        let mut model = model();
+        let input = Tensor::new(&[[1u32, 2, 3]], &Device::Cpu).unwrap();
+        model.record_expert_routing(true);
+        model.forward(&input, 0).unwrap();
+        model
+            .forward(&Tensor::new(&[[4u32]], &Device::Cpu).unwrap(), 3)
+            .unwrap();
+
+        let routing = model.take_expert_routing();
+        assert_eq!(
+            routing.iter().map(|r| r.position).collect::<Vec<_>>(),
+            vec![0, 1, 2, 3]
+        );
+        for layer in routing.iter().flat_map(|r| r.layers.iter()) {
+            assert_eq!(layer.experts.len(), 2);
+            assert!((layer.weights.iter().sum::<f32>() - 1.).abs() < 1e-5);
+        }
+        assert!(model.take_expert_routing().is_empty());
+    }
+
+    #[test]
+    fn test_hidden_states_not_routed() {
+        let mut model = model();
+        model.record_expert_routing(true);
+        model
+            .forward(&Tensor::new(&[[1u32, 2, 3]], &Device::Cpu).unwrap(), 0)
+            .unwrap();
+        model
+            .hidden_states(&Tensor::new(&[[5u32, 6]], &Device::Cpu).unwrap())
+            .unwrap();
+        assert_eq!(model.take_expert_routing().len(), 3);
+    }
 }
@@ -0,0 +1,487 @@
+/// OPT Model, used by Dart v1
+/// https://github.com/huggingface/transformers/blob/main/src/transformers/models/opt/modeling_opt.py
+use candle_core::{DType, Device, Module, Result, Tensor, D};
+use candle_nn::{Activation, VarBuilder};
+use candle_transformers::models::with_tracing::{layer_norm, linear_b, LayerNorm, Linear};
+use serde::Deserialize;
+
+use crate::models::kv_cache::{KvCache, KvCacheTokens};
+
+fn default_true() -> bool {
+    true
+}
+
+/// https://github.com/huggingface/transformers/blob/main/src/transformers/models/opt/configuration_opt.py
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Config {
+    pub vocab_size: usize,
+    pub hidden_size: usize,
+    pub num_hidden_layers: usize,
+    pub ffn_dim: usize,
+    pub num_attention_heads: usize,
+    pub max_position_embeddings: usize,
+    pub word_embed_proj_dim: Option<usize>,
+    #[serde(rename = "activation_function")]
+    pub hidden_act: Activation,
+    #[serde(default = "default_true")]
+    pub do_layer_norm_before: bool,
+    #[serde(default = "default_true")]
+    pub enable_bias: bool,
+}
+
+impl Config {
+    fn word_embed_proj_dim(&self) -> usize {
+        self.word_embed_proj_dim.unwrap_or(self.hidden_size)
+    }
+}
+
+// OPT offsets the positions by 2, the first two embeddings are never used
+const POSITION_OFFSET: usize = 2;
+
+#[derive(Debug)]
+struct Attention {
+    q_proj: Linear,
+    k_proj: Linear,
+    v_proj: Linear,
+    out_proj: Linear,
+    num_heads: usize,
+    head_dim: usize,
+    hidden_size: usize,
+    kv_cache: KvCache,
+}
+
+impl Attention {
+    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let hidden_sz = cfg.hidden_size;
+        let num_heads = cfg.num_attention_heads;
+        let head_dim = hidden_sz / num_heads;
+        let bias = cfg.enable_bias;
+        let q_proj = linear_b(hidden_sz, hidden_sz, bias, vb.pp("q_proj"))?;
+        let k_proj = linear_b(hidden_sz, hidden_sz, bias, vb.pp("k_proj"))?;
+        let v_proj = linear_b(hidden_sz, hidden_sz, bias, vb.pp("v_proj"))?;
+        let out_proj = linear_b(hidden_sz, hidden_sz, bias, vb.pp("out_proj"))?;
+        Ok(Self {
+            q_proj,
+            k_proj,
+            v_proj,
+            out_proj,
+            num_heads,
+            head_dim,
+            hidden_size: hidden_sz,
+            kv_cache: KvCache::new(2, cfg.max_position_embeddings),
+        })
+    }
+
+    fn try_clone(&self) -> Result<Self> {
+        Ok(Self {
+            q_proj: self.q_proj.clone(),
+            k_proj: self.k_proj.clone(),
+            v_proj: self.v_proj.clone(),
+            out_proj: self.out_proj.clone(),
+            num_heads: self.num_heads,
+            head_dim: self.head_dim,
+            hidden_size: self.hidden_size,
+            kv_cache: self.kv_cache.try_clone()?,
+        })
+    }
+
+    fn forward(&mut self, xs: &Tensor, attention_mask: Option<&Tensor>) -> Result<Tensor> {
+        let (b_sz, q_len, _) = xs.dims3()?;
+
+        let query_states = self.q_proj.forward(xs)?;
+        let key_states = self.k_proj.forward(xs)?;
+        let value_states = self.v_proj.forward(xs)?;
+
+        let query_states = query_states
+            .reshape((b_sz, q_len, self.num_heads, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+        let key_states = key_states
+            .reshape((b_sz, q_len, self.num_heads, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+        let value_states = value_states
+            .reshape((b_sz, q_len, self.num_heads, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+
+        let (key_states, value_states) = self.kv_cache.append(&key_states, &value_states)?;
+
+        let scale = 1f64 / f64::sqrt(self.head_dim as f64);
+        let attn_weights = (query_states.matmul(&key_states.transpose(2, 3)?)? * scale)?;
+        let attn_weights = match attention_mask {
+            None => attn_weights,
+            Some(mask) => attn_weights.broadcast_add(mask)?,
+        };
+        let attn_weights = candle_nn::ops::softmax_last_dim(&attn_weights)?;
+        attn_weights
+            .matmul(&value_states)?
+            .transpose(1, 2)?
+            .reshape((b_sz, q_len, self.hidden_size))?
+            .apply(&self.out_proj)
+    }
+
+    fn clear_kv_cache(&mut self) {
+        self.kv_cache.reset()
+    }
+
+    fn truncate_kv_cache(&mut self, len: usize) -> Result<()> {
+        self.kv_cache.truncate(len);
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct DecoderLayer {
+    self_attn: Attention,
+    self_attn_layer_norm: LayerNorm,
+    fc1: Linear,
+    fc2: Linear,
+    final_layer_norm: LayerNorm,
+    act_fn: Activation,
+    do_layer_norm_before: bool,
+}
+
+impl DecoderLayer {
+    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let self_attn = Attention::new(cfg, vb.pp("self_attn"))?;
+        let self_attn_layer_norm =
+            layer_norm(cfg.hidden_size, 1e-5, vb.pp("self_attn_layer_norm"))?;
+        let fc1 = linear_b(cfg.hidden_size, cfg.ffn_dim, cfg.enable_bias, vb.pp("fc1"))?;
+        let fc2 = linear_b(cfg.ffn_dim, cfg.hidden_size, cfg.enable_bias, vb.pp("fc2"))?;
+        let final_layer_norm = layer_norm(cfg.hidden_size, 1e-5, vb.pp("final_layer_norm"))?;
+        Ok(Self {
+            self_attn,
+            self_attn_layer_norm,
+            fc1,
+            fc2,
+            final_layer_norm,
+            act_fn: cfg.hidden_act,
+            do_layer_norm_before: cfg.do_layer_norm_before,
+        })
+    }
+
+    fn try_clone(&self) -> Result<Self> {
+        Ok(Self {
+            self_attn: self.self_attn.try_clone()?,
+            self_attn_layer_norm: self.self_attn_layer_norm.clone(),
+            fc1: self.fc1.clone(),
+            fc2: self.fc2.clone(),
+            final_layer_norm: self.final_layer_norm.clone(),
+            act_fn: self.act_fn,
+            do_layer_norm_before: self.do_layer_norm_before,
+        })
+    }
+
+    fn forward(&mut self, xs: &Tensor, attention_mask: Option<&Tensor>) -> Result<Tensor> {
+        let residual = xs;
+        let xs = if self.do_layer_norm_before {
+            xs.apply(&self.self_attn_layer_norm)?
+        } else {
+            xs.clone()
+        };
+        let xs = self.self_attn.forward(&xs, attention_mask)?;
+        let xs = (xs + residual)?;
+        let xs = if self.do_layer_norm_before {
+            xs
+        } else {
+            xs.apply(&self.self_attn_layer_norm)?
+        };
+
+        let residual = &xs;
+        let ys = if self.do_layer_norm_before {
+            xs.apply(&self.final_layer_norm)?
+        } else {
+            xs.clone()
+        };
+        let ys = ys.apply(&self.fc1)?.apply(&self.act_fn)?.apply(&self.fc2)?;
+        let ys = (ys + residual)?;
+        if self.do_layer_norm_before {
+            Ok(ys)
+        } else {
+            ys.apply(&self.final_layer_norm)
+        }
+    }
+
+    fn clear_kv_cache(&mut self) {
+        self.self_attn.clear_kv_cache()
+    }
+
+    fn truncate_kv_cache(&mut self, len: usize) -> Result<()> {
+        self.self_attn.truncate_kv_cache(len)
+    }
+}
+
+#[derive(Debug)]
+pub struct Model {
+    embed_tokens: candle_nn::Embedding,
+    embed_positions: candle_nn::Embedding,
+    project_in: Option<Linear>,
+    project_out: Option<Linear>,
+    layers: Vec<DecoderLayer>,
+    final_layer_norm: Option<LayerNorm>,
+    lm_head: candle_nn::Linear,
+    max_position_embeddings: usize,
+    kv_cache_tokens: KvCacheTokens,
+    device: Device,
+    dtype: DType,
+}
+
+impl Model {
+    pub fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let vb_m = vb.pp("model").pp("decoder");
+        let word_embed_proj_dim = cfg.word_embed_proj_dim();
+        let embed_tokens =
+            candle_nn::embedding(cfg.vocab_size, word_embed_proj_dim, vb_m.pp("embed_tokens"))?;
+        let embed_positions = candle_nn::embedding(
+            cfg.max_position_embeddings + POSITION_OFFSET,
+            cfg.hidden_size,
+            vb_m.pp("embed_positions"),
+        )?;
+        let (project_in, project_out) = if word_embed_proj_dim != cfg.hidden_size {
+            let project_in = linear_b(
+                word_embed_proj_dim,
+                cfg.hidden_size,
+                false,
+                vb_m.pp("project_in"),
+            )?;
+            let project_out = linear_b(
+                cfg.hidden_size,
+                word_embed_proj_dim,
+                false,
+                vb_m.pp("project_out"),
+            )?;
+            (Some(project_in), Some(project_out))
+        } else {
+            (None, None)
+        };
+        let mut layers = Vec::with_capacity(cfg.num_hidden_layers);
+        let vb_l = vb_m.pp("layers");
+        for layer_idx in 0..cfg.num_hidden_layers {
+            let layer = DecoderLayer::new(cfg, vb_l.pp(layer_idx))?;
+            layers.push(layer)
+        }
+        let final_layer_norm = if cfg.do_layer_norm_before {
+            Some(layer_norm(
+                cfg.hidden_size,
+                1e-5,
+                vb_m.pp("final_layer_norm"),
+            )?)
+        } else {
+            None
+        };
+        // the lm head is tied to the token embeddings
+        let lm_head = candle_nn::Linear::new(embed_tokens.embeddings().clone(), None);
+        Ok(Self {
+            embed_tokens,
+            embed_positions,
+            project_in,
+            project_out,
+            layers,
+            final_layer_norm,
+            lm_head,
+            max_position_embeddings: cfg.max_position_embeddings,
+            kv_cache_tokens: KvCacheTokens::default(),
+            device: vb.device().clone(),
+            dtype: vb.dtype(),
+        })
+    }
+
+    /// Copies the model with its own kv cache, the weights are shared.
+    pub fn try_clone(&self) -> Result<Self> {
+        Ok(Self {
+            embed_tokens: self.embed_tokens.clone(),
+            embed_positions: self.embed_positions.clone(),
+            project_in: self.project_in.clone(),
+            project_out: self.project_out.clone(),
+            layers: self
+                .layers
+                .iter()
+                .map(DecoderLayer::try_clone)
+                .collect::<Result<_>>()?,
+            final_layer_norm: self.final_layer_norm.clone(),
+            lm_head: self.lm_head.clone(),
+            max_position_embeddings: self.max_position_embeddings,
+            kv_cache_tokens: self.kv_cache_tokens.clone(),
+            device: self.device.clone(),
+            dtype: self.dtype,
+        })
+    }
+
+    fn prepare_decoder_attention_mask(
+        &self,
+        tgt_len: usize,
+        seqlen_offset: usize,
+    ) -> Result<Tensor> {
+        let mask: Vec<_> = (0..tgt_len)
+            .flat_map(|i| (0..tgt_len).map(move |j| if i < j { f32::NEG_INFINITY } else { 0. }))
+            .collect();
+        let mask = Tensor::from_slice(&mask, (tgt_len, tgt_len), &self.device)?;
+        let mask = if seqlen_offset > 0 {
+            let mask0 = Tensor::zeros((tgt_len, seqlen_offset), DType::F32, &self.device)?;
+            Tensor::cat(&[&mask0, &mask], D::Minus1)?
+        } else {
+            mask
+        };
+        mask.expand((1, 1, tgt_len, tgt_len + seqlen_offset))?
+            .to_dtype(self.dtype)
+    }
+
+    fn forward_hidden_states(
+        &mut self,
+        input_ids: &Tensor,
+        seqlen_offset: usize,
+    ) -> Result<Tensor> {
+        // left empty when the forward fails, since some layers may have cached the input
+        let mut kv_cache_tokens = std::mem::take(&mut self.kv_cache_tokens);
+        let (_b_size, seq_len) = input_ids.dims2()?;
+        let attention_mask = if seq_len <= 1 {
+            None
+        } else {
+            let mask = self.prepare_decoder_attention_mask(seq_len, seqlen_offset)?;
+            Some(mask)
+        };
+        let positions = Tensor::arange(
+            (seqlen_offset + POSITION_OFFSET) as u32,
+            (seqlen_offset + POSITION_OFFSET + seq_len) as u32,
+            &self.device,
+        )?
+        .unsqueeze(0)?;
+
+        let mut xs = self.embed_tokens.forward(input_ids)?;
+        if let Some(project_in) = &self.project_in {
+            xs = xs.apply(project_in)?;
+        }
+        let mut xs = xs.broadcast_add(&self.embed_positions.forward(&positions)?)?;
+        for layer in self.layers.iter_mut() {
+            xs = layer.forward(&xs, attention_mask.as_ref())?
+        }
+        kv_cache_tokens.append(input_ids, seqlen_offset)?;
+        self.kv_cache_tokens = kv_cache_tokens;
+        Ok(xs)
+    }
+
+    fn apply_head(&self, xs: &Tensor) -> Result<Tensor> {
+        let mut xs = xs.clone();
+        if let Some(final_layer_norm) = &self.final_layer_norm {
+            xs = xs.apply(final_layer_norm)?;
+        }
+        if let Some(project_out) = &self.project_out {
+            xs = xs.apply(project_out)?;
+        }
+        xs.apply(&self.lm_head)
+    }
+
+    pub fn forward(&mut self, input_ids: &Tensor, seqlen_offset: usize) -> Result<Tensor> {
+        let (_b_size, seq_len) = input_ids.dims2()?;
+        let xs = self.forward_hidden_states(input_ids, seqlen_offset)?;
+        self.apply_head(&xs.narrow(1, seq_len - 1, 1)?)
+    }
+
+    /// Returns the logits of every input position instead of only the last one.
+    pub fn forward_all(&mut self, input_ids: &Tensor, seqlen_offset: usize) -> Result<Tensor> {
+        let xs = self.forward_hidden_states(input_ids, seqlen_offset)?;
+        self.apply_head(&xs)
+    }
+
+    pub fn clear_kv_cache(&mut self) {
+        self.kv_cache_tokens.clear();
+        for layer in self.layers.iter_mut() {
+            layer.clear_kv_cache()
+        }
+    }
+
+    /// Drops the cached keys and values after the first `len` positions.
+    pub fn truncate_kv_cache(&mut self, len: usize) -> Result<()> {
+        self.kv_cache_tokens.truncate(len);
+        for layer in self.layers.iter_mut() {
+            layer.truncate_kv_cache(len)?
+        }
+        Ok(())
+    }
+
+    /// The tokens whose keys and values are in the kv cache.
+    pub fn kv_cache_tokens(&self) -> &[u32] {
+        self.kv_cache_tokens.tokens()
+    }
+
+    pub fn device(&self) -> &Device {
+        &self.device
+    }
+
+    pub fn dtype(&self) -> DType {
+        self.dtype
+    }
+
+    pub fn max_position_embeddings(&self) -> usize {
+        self.max_position_embeddings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::test_utils::{assert_close, values, var_builder};
+
+    fn config() -> Config {
+        Config {
+            vocab_size: 8,
+            hidden_size: 8,
+            num_hidden_layers: 2,
+            ffn_dim: 16,
+            num_attention_heads: 2,
+            max_position_embeddings: 16,
+            word_embed_proj_dim: None,
+            hidden_act: Activation::Relu,
+            do_layer_norm_before: true,
+            enable_bias: true,
+        }
+    }
+
+    fn model(cfg: &Config) -> Model {
+        Model::new(cfg, var_builder()).unwrap()
+    }
+
+    #[test]
+    fn test_forward() {
+        // with and without the projections and the final layer norm
+        let configs = [
+            config(),
+            Config {
+                word_embed_proj_dim: Some(4),
+                do_layer_norm_before: false,
+                ..config()
+            },
+        ];
+        for cfg in configs {
+            let mut model = model(&cfg);
+            let input = Tensor::new(&[[1u32, 2, 3]], &Device::Cpu).unwrap();
+            let logits = model.forward_all(&input, 0).unwrap();
+            assert_eq!(logits.dims(), &[1, 3, 8]);
+
+            // feeding the tokens one by one reuses the kv cache
+            model.clear_kv_cache();
+            let mut steps = Vec::new();
+            for (position, token) in [1u32, 2, 3].into_iter().enumerate() {
+                let input = Tensor::new(&[[token]], &Device::Cpu).unwrap();
+                steps.extend(values(&model.forward(&input, position).unwrap()));
+            }
+            assert_close(&steps, &values(&logits));
+        }
+    }
+
+    #[test]
+    fn test_truncate_kv_cache() {
+        let mut model = model(&config());
+        let input = Tensor::new(&[[1u32, 2, 3, 4]], &Device::Cpu).unwrap();
+        model.forward(&input, 0).unwrap();
+        model.truncate_kv_cache(2).unwrap();
+        let next = Tensor::new(&[[5u32]], &Device::Cpu).unwrap();
+        let truncated = values(&model.forward(&next, 2).unwrap());
+
+        model.clear_kv_cache();
+        let input = Tensor::new(&[[1u32, 2, 5]], &Device::Cpu).unwrap();
+        let expected = values(&model.forward(&input, 0).unwrap());
+        assert_close(&truncated, &expected);
+    }
+}
@@ -0,0 +1,203 @@
+//! Fixtures shared by the tests of the decoder models.
+
+use candle_core::{DType, Device, Tensor};
+use candle_nn::{VarBuilder, VarMap};
+use serde::de::DeserializeOwned;
+
+/// A two layer config small enough to run on the cpu in tests.
+/// The Mixtral keys are ignored by the configs without experts.
+pub(crate) fn tiny_config<C: DeserializeOwned>() -> C {
+    serde_json::from_value(serde_json::json!({
+        "vocab_size": 8,
+        "hidden_size": 8,
+        "intermediate_size": 16,
+        "num_hidden_layers": 2,
+        "num_attention_heads": 2,
+        "num_key_value_heads": 1,
+        "hidden_act": "silu",
+        "max_position_embeddings": 16,
+        "rms_norm_eps": 1e-5,
+        "rope_theta": 10000.0,
+        "sliding_window": null,
+        "num_experts_per_tok": 2,
+        "num_local_experts": 4,
+        "use_flash_attn": false
+    }))
+    .unwrap()
+}
+
+/// Randomly initialized weights on the cpu.
+pub(crate) fn var_builder() -> VarBuilder<'static> {
+    VarBuilder::from_varmap(&VarMap::new(), DType::F32, &Device::Cpu)
+}
+
+pub(crate) fn values(xs: &Tensor) -> Vec<f32> {
+    xs.flatten_all().unwrap().to_vec1().unwrap()
+}
+
+pub(crate) fn assert_close(a: &[f32], b: &[f32]) {
+    assert_eq!(a.len(), b.len());
+    for (a, b) in a.iter().zip(b.iter()) {
+        assert!((a - b).abs() < 1e-5, "{a} != {b}");
+    }
+}
+
+/// Tests of the hidden states extraction, for a test module defining `model()`.
+macro_rules! hidden_states_tests {
+    () => {
+        #[test]
+        fn test_hidden_states() {
+            let mut model = model();
+            let input = Tensor::new(&[[1u32, 2, 3]], &Device::Cpu).unwrap();
+
+            let hidden_states = model.hidden_states(&input).unwrap();
+            assert_eq!(hidden_states.dims(), &[1, 3, 8]);
+            let logits = model.forward_all(&input, 0).unwrap();
+            assert_eq!(
+                values(&hidden_states.apply(&model.lm_head).unwrap()),
+                values(&logits)
+            );
+
+            let pooled = model.pooled_hidden_states(&input).unwrap();
+            assert_eq!(pooled.dims(), &[1, 8]);
+            assert_eq!(values(&pooled), values(&hidden_states.mean(1).unwrap()));
+
+            let embeddings = model.token_embeddings(&input).unwrap();
+            assert_eq!(embeddings.dims(), &[1, 3, 8]);
+        }
+
+        #[test]
+        fn test_hidden_states_keep_kv_cache() {
+            let mut model = model();
+            let input = Tensor::new(&[[1u32, 2, 3]], &Device::Cpu).unwrap();
+            let next = Tensor::new(&[[4u32]], &Device::Cpu).unwrap();
+            model.forward(&input, 0).unwrap();
+            let expected = model.forward(&next, 3).unwrap();
+
+            model.clear_kv_cache();
+            model.forward(&input, 0).unwrap();
+            model
+                .hidden_states(&Tensor::new(&[[5u32, 6]], &Device::Cpu).unwrap())
+                .unwrap();
+            let logits = model.forward(&next, 3).unwrap();
+            assert_eq!(values(&logits), values(&expected));
+        }
+
+        #[test]
+        fn test_hidden_states_not_recorded() {
+            let mut model = model();
+            model.record_attention_weights(true).unwrap();
+            model
+                .forward(&Tensor::new(&[[1u32, 2, 3]], &Device::Cpu).unwrap(), 0)
+                .unwrap();
+            model
+                .hidden_states(&Tensor::new(&[[5u32, 6]], &Device::Cpu).unwrap())
+                .unwrap();
+            assert_eq!(model.take_attention_weights().len(), 3);
+        }
+    };
+}
+pub(crate) use hidden_states_tests;
+
+/// Tests of the LoRA adapters, for a test module defining `model()`.
+/// `$mlp_linear` names a linear of the second layer taking the intermediate states.
+macro_rules! lora_tests {
+    ($mlp_linear:expr) => {
+        // the logits of every position, fed from the position 0
+        fn logits(model: &mut Model, input: &Tensor) -> Vec<f32> {
+            model.clear_kv_cache();
+            values(&model.forward_all(input, 0).unwrap())
+        }
+
+        #[test]
+        fn test_lora() {
+            use crate::models::lora::{LoraAdapter, LoraConfig, LoraModel};
+            use std::collections::HashMap;
+
+            let mut model = model();
+            let input = Tensor::new(&[[1u32, 2, 3]], &Device::Cpu).unwrap();
+            let base = logits(&mut model, &input);
+
+            let lora = |dims: (usize, usize)| {
+                let a = Tensor::randn(0f32, 1., (2, dims.1), &Device::Cpu).unwrap();
+                let b = Tensor::randn(0f32, 1., (dims.0, 2), &Device::Cpu).unwrap();
+                (a, b)
+            };
+            let weights = HashMap::from([
+                ("model.layers.0.self_attn.q_proj".to_string(), lora((8, 8))),
+                ($mlp_linear.to_string(), lora((8, 16))),
+            ]);
+            let config = LoraConfig {
+                r: 2,
+                lora_alpha: 2.,
+                use_rslora: false,
+            };
+            let adapter = LoraAdapter::new("style", config.clone(), weights);
+
+            model.load_lora(&adapter, 1.0).unwrap();
+            assert!(model.load_lora(&adapter, 1.0).is_err());
+            assert_eq!(
+                model.lora_adapters().unwrap(),
+                vec![("style".to_string(), 1.0)]
+            );
+            let runtime = logits(&mut model, &input);
+            assert_ne!(runtime, base);
+
+            model.set_lora_scale("style", 0.0).unwrap();
+            assert_eq!(logits(&mut model, &input), base);
+
+            model.set_lora_scale("style", 1.0).unwrap();
+            model.merge_lora("style").unwrap();
+            assert!(model.lora_adapters().unwrap().is_empty());
+            let merged = logits(&mut model, &input);
+            for (x, y) in merged.iter().zip(runtime.iter()) {
+                assert!((x - y).abs() < 1e-4);
+            }
+
+            let weights = HashMap::from([("lm_head".to_string(), lora((8, 8)))]);
+            let unsupported = LoraAdapter::new("head", config, weights);
+            assert!(model.load_lora(&unsupported, 1.0).is_err());
+        }
+    };
+}
+pub(crate) use lora_tests;
+
+/// Tests of the chunked attention against the full attention with the same sliding window,
+/// for a test module using `lora_tests!`.
+macro_rules! chunked_attention_tests {
+    () => {
+        #[test]
+        fn test_chunked_attention() {
+            let cfg = Config {
+                sliding_window: Some(3),
+                ..tiny_config()
+            };
+            let vb = var_builder();
+            let mut reference = Model::new(&cfg, vb.clone()).unwrap();
+            let cfg = Config {
+                attention_chunk_size: Some(2),
+                ..cfg
+            };
+            let mut chunked = Model::new(&cfg, vb).unwrap();
+
+            let input = Tensor::new(&[[1u32, 2, 3, 4, 5]], &Device::Cpu).unwrap();
+            let next = Tensor::new(&[[6u32]], &Device::Cpu).unwrap();
+            let expected = [
+                logits(&mut reference, &input),
+                values(&reference.forward(&next, 5).unwrap()),
+            ];
+            let output = [
+                logits(&mut chunked, &input),
+                values(&chunked.forward(&next, 5).unwrap()),
+            ];
+            for (expected, output) in expected.iter().zip(output.iter()) {
+                for (a, b) in expected.iter().zip(output.iter()) {
+                    assert!((a - b).abs() < 1e-5);
+                }
+            }
+            // the chunked attention does not compute the weights
+            assert!(chunked.record_attention_weights(true).is_err());
+        }
+    };
+}
+pub(crate) use chunked_attention_tests;
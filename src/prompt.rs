@@ -1,7 +1,13 @@
+use anyhow::Result;
+use std::str::FromStr;
+
 use crate::tags::SpecialTag::{
-    Bos, CharacterEnd, CharacterStart, CopyrightEnd, CopyrightStart, GeneralStart, InputEnd,
+    Bos, CharacterEnd, CharacterStart, CopyrightEnd, CopyrightStart, Eos, GeneralEnd, GeneralStart,
+    InputEnd, RatingEnd, RatingStart,
+};
+use crate::tags::{
+    AspectRatioTag, IdentityTag, LengthTag, LengthTagV1, RatingTag, RatingTagV1, SpecialTag, Tag,
 };
-use crate::tags::{AspectRatioTag, IdentityTag, LengthTag, RatingTag, Tag};
 
 pub fn compose_prompt_v2(
     copyright: &str,
@@ -39,6 +45,106 @@ pub fn compose_prompt_v2(
     }
 }
 
+pub fn compose_prompt_v1(
+    copyright: &str,
+    character: &str,
+    rating: RatingTagV1,
+    length: LengthTagV1,
+    prompt: &str,
+    do_completion: bool,
+) -> String {
+    let rating = rating.to_tag();
+    let length = length.to_tag();
+
+    if do_completion {
+        format!(
+            "\
+{Bos}\
+{RatingStart}{rating}{RatingEnd}\
+{CopyrightStart}{copyright}{CopyrightEnd}\
+{CharacterStart}{character}{CharacterEnd}\
+{GeneralStart}{length}{prompt}{InputEnd}"
+        )
+    } else {
+        format!(
+            "\
+{Bos}\
+{RatingStart}{rating}{RatingEnd}\
+{CopyrightStart}{copyright}{CopyrightEnd}\
+{CharacterStart}{character}{CharacterEnd}\
+{GeneralStart}{length}{prompt}"
+        )
+    }
+}
+
+/// The sections of a Dart v1 prompt or generated text.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PromptV1 {
+    pub rating: Option<RatingTagV1>,
+    pub copyright: Vec<String>,
+    pub character: Vec<String>,
+    pub length: Option<LengthTagV1>,
+    pub general: Vec<String>,
+}
+
+// the text between the start and end tags, or until the end of the text if the section is not closed
+fn section<'a>(text: &'a str, start: &SpecialTag, end: &SpecialTag) -> Option<&'a str> {
+    let (_, rest) = text.split_once(&start.to_tag())?;
+    match rest.split_once(&end.to_tag()) {
+        Some((section, _)) => Some(section),
+        None => Some(rest),
+    }
+}
+
+fn split_tags(section: &str) -> Vec<String> {
+    section
+        .split(',')
+        .map(|tag| tag.trim())
+        .filter(|tag| !tag.is_empty())
+        .map(|tag| tag.to_string())
+        .collect()
+}
+
+pub fn parse_prompt_v1(text: &str) -> Result<PromptV1> {
+    // the most specific rating is the last one, e.g. `rating:sfw, rating:general`
+    let rating = match section(text, &RatingStart, &RatingEnd) {
+        Some(rating) => split_tags(rating)
+            .last()
+            .map(|tag| RatingTagV1::from_str(tag))
+            .transpose()?,
+        None => None,
+    };
+    let copyright = section(text, &CopyrightStart, &CopyrightEnd)
+        .map(split_tags)
+        .unwrap_or_default();
+    let character = section(text, &CharacterStart, &CharacterEnd)
+        .map(split_tags)
+        .unwrap_or_default();
+
+    let general = section(text, &GeneralStart, &GeneralEnd)
+        .unwrap_or_default()
+        .replace(&InputEnd.to_tag(), ", ")
+        .replace(&Eos.to_tag(), "");
+    let (length, general) = match LengthTagV1::VARIANTS
+        .into_iter()
+        .find(|length| general.starts_with(&length.to_tag()))
+    {
+        Some(length) => {
+            let general = general[length.to_tag().len()..].to_string();
+            (Some(length), general)
+        }
+        None => (None, general),
+    };
+
+    Ok(PromptV1 {
+        rating,
+        copyright,
+        character,
+        length,
+        general: split_tags(&general),
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -90,4 +196,71 @@ mod tests {
 <general>1girl"
         )
     }
+
+    #[test]
+    fn test_compose_prompt_v1() {
+        let prompt = compose_prompt_v1(
+            "vocaloid",
+            "hatsune miku",
+            RatingTagV1::General,
+            LengthTagV1::Long,
+            "1girl, blue hair",
+            true,
+        );
+
+        assert_eq!(
+            prompt,
+            "\
+<|bos|>\
+<rating>rating:sfw, rating:general</rating>\
+<copyright>vocaloid</copyright>\
+<character>hatsune miku</character>\
+<general><|long|>1girl, blue hair<|input_end|>"
+        )
+    }
+
+    #[test]
+    fn test_parse_prompt_v1() {
+        let text = "\
+<|bos|>\
+<rating>rating:sfw, rating:general</rating>\
+<copyright>vocaloid</copyright>\
+<character>hatsune miku</character>\
+<general><|long|>1girl, blue hair<|input_end|>twintails, skirt</general><|eos|>";
+
+        let prompt = parse_prompt_v1(text).unwrap();
+        assert_eq!(
+            prompt,
+            PromptV1 {
+                rating: Some(RatingTagV1::General),
+                copyright: vec!["vocaloid".to_string()],
+                character: vec!["hatsune miku".to_string()],
+                length: Some(LengthTagV1::Long),
+                general: vec![
+                    "1girl".to_string(),
+                    "blue hair".to_string(),
+                    "twintails".to_string(),
+                    "skirt".to_string()
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_prompt_v1_roundtrip() {
+        let prompt = compose_prompt_v1(
+            "",
+            "",
+            RatingTagV1::Nsfw,
+            LengthTagV1::VeryShort,
+            "1girl",
+            false,
+        );
+
+        let parsed = parse_prompt_v1(&prompt).unwrap();
+        assert_eq!(parsed.rating, Some(RatingTagV1::Nsfw));
+        assert_eq!(parsed.length, Some(LengthTagV1::VeryShort));
+        assert!(parsed.copyright.is_empty());
+        assert_eq!(parsed.general, vec!["1girl".to_string()]);
+    }
 }
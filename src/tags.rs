@@ -1,4 +1,5 @@
 use anyhow::{Error as E, Result};
+use serde::{Deserialize, Serialize};
 use std::fmt;
 use std::str::FromStr;
 
@@ -7,7 +8,41 @@ pub trait Tag {
     fn is_special(tag: &str) -> bool;
 }
 
-#[derive(Debug, Clone)]
+/// Parses either a short name (`tall`) or a full special tag (`<|aspect_ratio:tall|>`).
+fn parse_tag<T: Clone>(
+    s: &str,
+    category: &str,
+    variants: &[T],
+    name: fn(&T) -> &'static str,
+) -> Result<T> {
+    let value = s
+        .strip_prefix(&format!("<|{category}:"))
+        .and_then(|value| value.strip_suffix("|>"))
+        .unwrap_or(s);
+    find_tag(s, value, category, variants, name)
+}
+
+fn find_tag<T: Clone>(
+    s: &str,
+    value: &str,
+    category: &str,
+    variants: &[T],
+    name: fn(&T) -> &'static str,
+) -> Result<T> {
+    match variants.iter().find(|variant| name(variant) == value) {
+        Some(variant) => Ok(variant.clone()),
+        None => {
+            let expected = variants.iter().map(name).collect::<Vec<_>>().join(", ");
+            Err(E::msg(format!(
+                "invalid {} tag: {s:?}, expected one of: {expected}",
+                category.replace('_', " ")
+            )))
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum LengthTag {
     VeryShort,
     Short,
@@ -18,13 +53,7 @@ pub enum LengthTag {
 
 impl Tag for LengthTag {
     fn to_tag(&self) -> String {
-        match self {
-            Self::VeryShort => "<|length:very_short|>".to_string(),
-            Self::Short => "<|length:short|>".to_string(),
-            Self::Medium => "<|length:medium|>".to_string(),
-            Self::Long => "<|length:long|>".to_string(),
-            Self::VeryLong => "<|length:very_long|>".to_string(),
-        }
+        format!("<|length:{}|>", self.name())
     }
 
     fn is_special(tag: &str) -> bool {
@@ -32,22 +61,72 @@ impl Tag for LengthTag {
     }
 }
 
+impl LengthTag {
+    pub const VARIANTS: [LengthTag; 5] = [
+        Self::VeryShort,
+        Self::Short,
+        Self::Medium,
+        Self::Long,
+        Self::VeryLong,
+    ];
+
+    /// The name of the tag without the category, e.g. `very_long`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::VeryShort => "very_short",
+            Self::Short => "short",
+            Self::Medium => "medium",
+            Self::Long => "long",
+            Self::VeryLong => "very_long",
+        }
+    }
+
+    /// The range of the number of general tags `[min, max)` the bucket was trained with.
+    pub fn tag_count_range(&self) -> (usize, Option<usize>) {
+        match self {
+            Self::VeryShort => (0, Some(10)),
+            Self::Short => (10, Some(20)),
+            Self::Medium => (20, Some(30)),
+            Self::Long => (30, Some(40)),
+            Self::VeryLong => (40, None),
+        }
+    }
+
+    /// Chooses the bucket which contains the number of general tags.
+    pub fn from_tag_count(count: usize) -> Self {
+        Self::VARIANTS
+            .into_iter()
+            .find(|tag| match tag.tag_count_range() {
+                (_, Some(max)) => count < max,
+                (_, None) => true,
+            })
+            .unwrap_or(Self::VeryLong)
+    }
+
+    /// Chooses the bucket of comma separated tags, e.g. the generated output.
+    pub fn from_tags(tags: &str) -> Self {
+        let count = tags.split(',').filter(|tag| !tag.trim().is_empty()).count();
+        Self::from_tag_count(count)
+    }
+}
+
+impl fmt::Display for LengthTag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = self.to_tag();
+        write!(f, "{}", s)
+    }
+}
+
 impl FromStr for LengthTag {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "very_short" => Ok(Self::VeryShort),
-            "short" => Ok(Self::Short),
-            "medium" => Ok(Self::Medium),
-            "long" => Ok(Self::Long),
-            "very_long" => Ok(Self::VeryLong),
-            _ => Err(E::msg("invalid length tag")),
-        }
+        parse_tag(s, "length", &Self::VARIANTS, Self::name)
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum AspectRatioTag {
     UltraWide,
     Wide,
@@ -58,13 +137,7 @@ pub enum AspectRatioTag {
 
 impl Tag for AspectRatioTag {
     fn to_tag(&self) -> String {
-        match self {
-            Self::UltraWide => "<|aspect_ratio:ultra_wide|>".to_string(),
-            Self::Wide => "<|aspect_ratio:wide|>".to_string(),
-            Self::Square => "<|aspect_ratio:square|>".to_string(),
-            Self::Tall => "<|aspect_ratio:tall|>".to_string(),
-            Self::UltraTall => "<|aspect_ratio:ultra_tall|>".to_string(),
-        }
+        format!("<|aspect_ratio:{}|>", self.name())
     }
 
     fn is_special(tag: &str) -> bool {
@@ -72,22 +145,77 @@ impl Tag for AspectRatioTag {
     }
 }
 
+impl AspectRatioTag {
+    pub const VARIANTS: [AspectRatioTag; 5] = [
+        Self::UltraWide,
+        Self::Wide,
+        Self::Square,
+        Self::Tall,
+        Self::UltraTall,
+    ];
+
+    /// The name of the tag without the category, e.g. `ultra_tall`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::UltraWide => "ultra_wide",
+            Self::Wide => "wide",
+            Self::Square => "square",
+            Self::Tall => "tall",
+            Self::UltraTall => "ultra_tall",
+        }
+    }
+
+    /// Chooses the bucket of an image size with the same thresholds as the training data.
+    /// Fails if the width or the height is 0.
+    pub fn from_resolution(width: u32, height: u32) -> Result<Self> {
+        if width == 0 || height == 0 {
+            return Err(E::msg(format!(
+                "the resolution must be at least 1x1, got {width}x{height}"
+            )));
+        }
+        let aspect_ratio = width as f64 / height as f64;
+        Ok(if aspect_ratio <= 1.0 / 3f64.sqrt() {
+            Self::UltraTall
+        } else if aspect_ratio <= 8.0 / 9.0 {
+            Self::Tall
+        } else if aspect_ratio < 9.0 / 8.0 {
+            Self::Square
+        } else if aspect_ratio < 3f64.sqrt() {
+            Self::Wide
+        } else {
+            Self::UltraWide
+        })
+    }
+
+    /// A suggested SDXL resolution `(width, height)` for the bucket.
+    pub fn sdxl_resolution(&self) -> (u32, u32) {
+        match self {
+            Self::UltraWide => (1536, 640),
+            Self::Wide => (1216, 832),
+            Self::Square => (1024, 1024),
+            Self::Tall => (832, 1216),
+            Self::UltraTall => (640, 1536),
+        }
+    }
+}
+
+impl fmt::Display for AspectRatioTag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = self.to_tag();
+        write!(f, "{}", s)
+    }
+}
+
 impl FromStr for AspectRatioTag {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "ultra_wide" => Ok(Self::UltraWide),
-            "wide" => Ok(Self::Wide),
-            "square" => Ok(Self::Square),
-            "tall" => Ok(Self::Tall),
-            "ultra_tall" => Ok(Self::UltraTall),
-            _ => Err(E::msg("invalid aspect ratio tag")),
-        }
+        parse_tag(s, "aspect_ratio", &Self::VARIANTS, Self::name)
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum RatingTag {
     Sfw,
     General,
@@ -99,14 +227,7 @@ pub enum RatingTag {
 
 impl Tag for RatingTag {
     fn to_tag(&self) -> String {
-        match self {
-            Self::Sfw => "<|rating:sfw|>".to_string(),
-            Self::General => "<|rating:general|>".to_string(),
-            Self::Sensitive => "<|rating:sensitive|>".to_string(),
-            Self::Nsfw => "<|rating:nsfw|>".to_string(),
-            Self::Questionable => "<|rating:questionable|>".to_string(),
-            Self::Explicit => "<|rating:explicit|>".to_string(),
-        }
+        format!("<|rating:{}|>", self.name())
     }
 
     fn is_special(tag: &str) -> bool {
@@ -114,23 +235,46 @@ impl Tag for RatingTag {
     }
 }
 
+impl RatingTag {
+    pub const VARIANTS: [RatingTag; 6] = [
+        Self::Sfw,
+        Self::General,
+        Self::Sensitive,
+        Self::Nsfw,
+        Self::Questionable,
+        Self::Explicit,
+    ];
+
+    /// The name of the tag without the category, e.g. `explicit`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Sfw => "sfw",
+            Self::General => "general",
+            Self::Sensitive => "sensitive",
+            Self::Nsfw => "nsfw",
+            Self::Questionable => "questionable",
+            Self::Explicit => "explicit",
+        }
+    }
+}
+
+impl fmt::Display for RatingTag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = self.to_tag();
+        write!(f, "{}", s)
+    }
+}
+
 impl FromStr for RatingTag {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "sfw" => Ok(Self::Sfw),
-            "general" => Ok(Self::General),
-            "sensitive" => Ok(Self::Sensitive),
-            "nsfw" => Ok(Self::Nsfw),
-            "questionable" => Ok(Self::Questionable),
-            "explicit" => Ok(Self::Explicit),
-            _ => Err(E::msg("invalid rating tag")),
-        }
+        parse_tag(s, "rating", &Self::VARIANTS, Self::name)
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum IdentityTag {
     None,
     Lax,
@@ -139,11 +283,7 @@ pub enum IdentityTag {
 
 impl Tag for IdentityTag {
     fn to_tag(&self) -> String {
-        match self {
-            Self::None => "<|identity:none|>".to_string(),
-            Self::Lax => "<|identity:lax|>".to_string(),
-            Self::Strict => "<|identity:strict|>".to_string(),
-        }
+        format!("<|identity:{}|>", self.name())
     }
 
     fn is_special(tag: &str) -> bool {
@@ -151,20 +291,157 @@ impl Tag for IdentityTag {
     }
 }
 
+impl IdentityTag {
+    pub const VARIANTS: [IdentityTag; 3] = [Self::None, Self::Lax, Self::Strict];
+
+    /// The name of the tag without the category, e.g. `strict`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Lax => "lax",
+            Self::Strict => "strict",
+        }
+    }
+}
+
+impl fmt::Display for IdentityTag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = self.to_tag();
+        write!(f, "{}", s)
+    }
+}
+
 impl FromStr for IdentityTag {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "none" => Ok(Self::None),
-            "lax" => Ok(Self::Lax),
-            "strict" => Ok(Self::Strict),
-            _ => Err(E::msg("invalid identity tag")),
+        parse_tag(s, "identity", &Self::VARIANTS, Self::name)
+    }
+}
+
+/// The rating of Dart v1, written as plain tags in the `<rating>` section.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RatingTagV1 {
+    Sfw,
+    General,
+    Sensitive,
+    Nsfw,
+    Questionable,
+    Explicit,
+}
+
+impl Tag for RatingTagV1 {
+    /// Includes the parent rating, e.g. `rating:sfw, rating:general`.
+    fn to_tag(&self) -> String {
+        match self {
+            Self::Sfw | Self::Nsfw => format!("rating:{}", self.name()),
+            Self::General | Self::Sensitive => format!("rating:sfw, rating:{}", self.name()),
+            Self::Questionable | Self::Explicit => {
+                format!("rating:nsfw, rating:{}", self.name())
+            }
+        }
+    }
+
+    fn is_special(tag: &str) -> bool {
+        tag.starts_with("rating:")
+    }
+}
+
+impl RatingTagV1 {
+    pub const VARIANTS: [RatingTagV1; 6] = [
+        Self::Sfw,
+        Self::General,
+        Self::Sensitive,
+        Self::Nsfw,
+        Self::Questionable,
+        Self::Explicit,
+    ];
+
+    /// The name of the tag without the category, e.g. `explicit`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Sfw => "sfw",
+            Self::General => "general",
+            Self::Sensitive => "sensitive",
+            Self::Nsfw => "nsfw",
+            Self::Questionable => "questionable",
+            Self::Explicit => "explicit",
         }
     }
 }
 
-#[derive(Debug, Clone)]
+impl fmt::Display for RatingTagV1 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = self.to_tag();
+        write!(f, "{}", s)
+    }
+}
+
+impl FromStr for RatingTagV1 {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let value = s.strip_prefix("rating:").unwrap_or(s);
+        find_tag(s, value, "rating", &Self::VARIANTS, Self::name)
+    }
+}
+
+/// The length tag of Dart v1, which has no medium length.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LengthTagV1 {
+    VeryShort,
+    Short,
+    Long,
+    VeryLong,
+}
+
+impl Tag for LengthTagV1 {
+    fn to_tag(&self) -> String {
+        format!("<|{}|>", self.name())
+    }
+
+    fn is_special(tag: &str) -> bool {
+        Self::VARIANTS.iter().any(|variant| variant.to_tag() == tag)
+    }
+}
+
+impl LengthTagV1 {
+    pub const VARIANTS: [LengthTagV1; 4] =
+        [Self::VeryShort, Self::Short, Self::Long, Self::VeryLong];
+
+    /// The name of the tag, e.g. `very_long`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::VeryShort => "very_short",
+            Self::Short => "short",
+            Self::Long => "long",
+            Self::VeryLong => "very_long",
+        }
+    }
+}
+
+impl fmt::Display for LengthTagV1 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = self.to_tag();
+        write!(f, "{}", s)
+    }
+}
+
+impl FromStr for LengthTagV1 {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let value = s
+            .strip_prefix("<|")
+            .and_then(|value| value.strip_suffix("|>"))
+            .unwrap_or(s);
+        find_tag(s, value, "length", &Self::VARIANTS, Self::name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum SpecialTag {
     Bos,
     Eos,
@@ -175,6 +452,8 @@ pub enum SpecialTag {
     GeneralStart,
     GeneralEnd,
     InputEnd,
+    RatingStart,
+    RatingEnd,
 }
 
 impl Tag for SpecialTag {
@@ -189,28 +468,195 @@ impl Tag for SpecialTag {
             Self::GeneralStart => "<general>".to_string(),
             Self::GeneralEnd => "</general>".to_string(),
             Self::InputEnd => "<|input_end|>".to_string(),
+            Self::RatingStart => "<rating>".to_string(),
+            Self::RatingEnd => "</rating>".to_string(),
         }
     }
 
     fn is_special(tag: &str) -> bool {
-        match tag {
-            "<|bos|>" => true,
-            "<|eos|>" => true,
-            "<copyright>" => true,
-            "</copyright>" => true,
-            "<character>" => true,
-            "</character>" => true,
-            "<general>" => true,
-            "</general>" => true,
-            "<|input_end|>" => true,
-            _ => false,
+        Self::VARIANTS.iter().any(|variant| variant.to_tag() == tag)
+    }
+}
+
+impl SpecialTag {
+    pub const VARIANTS: [SpecialTag; 11] = [
+        Self::Bos,
+        Self::Eos,
+        Self::CopyrightStart,
+        Self::CopyrightEnd,
+        Self::CharacterStart,
+        Self::CharacterEnd,
+        Self::GeneralStart,
+        Self::GeneralEnd,
+        Self::InputEnd,
+        Self::RatingStart,
+        Self::RatingEnd,
+    ];
+}
+
+impl FromStr for SpecialTag {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match Self::VARIANTS.iter().find(|variant| variant.to_tag() == s) {
+            Some(variant) => Ok(variant.clone()),
+            None => {
+                let expected = Self::VARIANTS
+                    .iter()
+                    .map(|variant| variant.to_tag())
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                Err(E::msg(format!(
+                    "invalid special tag: {s:?}, expected one of: {expected}"
+                )))
+            }
         }
     }
 }
 
+/// Whether the tag is any of the special tags, including the rating, aspect ratio, length and identity tags.
+pub fn is_special_tag(tag: &str) -> bool {
+    SpecialTag::is_special(tag)
+        || RatingTag::is_special(tag)
+        || AspectRatioTag::is_special(tag)
+        || LengthTag::is_special(tag)
+        || IdentityTag::is_special(tag)
+        || RatingTagV1::is_special(tag)
+        || LengthTagV1::is_special(tag)
+}
+
 impl fmt::Display for SpecialTag {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let s = self.to_tag();
         write!(f, "{}", s)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display() {
+        assert_eq!(LengthTag::VeryLong.to_string(), "<|length:very_long|>");
+        assert_eq!(IdentityTag::None.to_string(), "<|identity:none|>");
+    }
+
+    #[test]
+    fn test_serde() {
+        let json = serde_json::to_string(&AspectRatioTag::UltraWide).unwrap();
+        assert_eq!(json, "\"ultra_wide\"");
+        let tag: RatingTag = serde_json::from_str("\"questionable\"").unwrap();
+        assert_eq!(tag, RatingTag::Questionable);
+    }
+
+    #[test]
+    fn test_from_str() {
+        assert_eq!(
+            AspectRatioTag::from_str("tall").unwrap(),
+            AspectRatioTag::Tall
+        );
+        assert_eq!(
+            AspectRatioTag::from_str("<|aspect_ratio:tall|>").unwrap(),
+            AspectRatioTag::Tall
+        );
+        assert_eq!(
+            IdentityTag::from_str("<|identity:none|>").unwrap(),
+            IdentityTag::None
+        );
+        assert_eq!(
+            SpecialTag::from_str("</general>").unwrap(),
+            SpecialTag::GeneralEnd
+        );
+        assert!(LengthTag::from_str("<|rating:long|>").is_err());
+    }
+
+    #[test]
+    fn test_from_str_error() {
+        let err = RatingTag::from_str("safe").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid rating tag: \"safe\", expected one of: \
+            sfw, general, sensitive, nsfw, questionable, explicit"
+        );
+    }
+
+    #[test]
+    fn test_aspect_ratio_from_resolution() {
+        assert_eq!(
+            AspectRatioTag::from_resolution(1024, 1024).unwrap(),
+            AspectRatioTag::Square
+        );
+        assert_eq!(
+            AspectRatioTag::from_resolution(832, 1216).unwrap(),
+            AspectRatioTag::Tall
+        );
+        assert_eq!(
+            AspectRatioTag::from_resolution(800, 900).unwrap(),
+            AspectRatioTag::Tall
+        );
+        assert_eq!(
+            AspectRatioTag::from_resolution(880, 800).unwrap(),
+            AspectRatioTag::Square
+        );
+        assert_eq!(
+            AspectRatioTag::from_resolution(1920, 1080).unwrap(),
+            AspectRatioTag::UltraWide
+        );
+        for tag in AspectRatioTag::VARIANTS {
+            let (width, height) = tag.sdxl_resolution();
+            assert_eq!(AspectRatioTag::from_resolution(width, height).unwrap(), tag);
+        }
+        assert!(AspectRatioTag::from_resolution(0, 1024).is_err());
+        assert!(AspectRatioTag::from_resolution(1024, 0).is_err());
+    }
+
+    #[test]
+    fn test_length_from_tag_count() {
+        assert_eq!(LengthTag::from_tag_count(0), LengthTag::VeryShort);
+        assert_eq!(LengthTag::from_tag_count(9), LengthTag::VeryShort);
+        assert_eq!(LengthTag::from_tag_count(20), LengthTag::Medium);
+        assert_eq!(LengthTag::from_tag_count(39), LengthTag::Long);
+        assert_eq!(LengthTag::from_tag_count(100), LengthTag::VeryLong);
+        for tag in LengthTag::VARIANTS {
+            let (min, _) = tag.tag_count_range();
+            assert_eq!(LengthTag::from_tag_count(min), tag);
+        }
+    }
+
+    #[test]
+    fn test_length_from_tags() {
+        assert_eq!(LengthTag::from_tags(""), LengthTag::VeryShort);
+        let tags = (0..25).map(|i| format!("tag{i}")).collect::<Vec<_>>();
+        assert_eq!(LengthTag::from_tags(&tags.join(", ")), LengthTag::Medium);
+    }
+
+    #[test]
+    fn test_v1_tags() {
+        assert_eq!(RatingTagV1::General.to_tag(), "rating:sfw, rating:general");
+        assert_eq!(RatingTagV1::Nsfw.to_tag(), "rating:nsfw");
+        assert_eq!(
+            RatingTagV1::from_str("rating:explicit").unwrap(),
+            RatingTagV1::Explicit
+        );
+        assert_eq!(LengthTagV1::Long.to_tag(), "<|long|>");
+        assert_eq!(
+            LengthTagV1::from_str("<|very_short|>").unwrap(),
+            LengthTagV1::VeryShort
+        );
+        assert!(LengthTagV1::from_str("medium").is_err());
+    }
+
+    #[test]
+    fn test_variants() {
+        for tag in LengthTag::VARIANTS {
+            let json = serde_json::to_string(&tag).unwrap();
+            let parsed: LengthTag = serde_json::from_str(&json).unwrap();
+            assert_eq!(parsed, tag);
+        }
+        assert_eq!(RatingTag::VARIANTS.len(), 6);
+        for tag in SpecialTag::VARIANTS {
+            assert!(SpecialTag::is_special(&tag.to_tag()));
+        }
+    }
+}
@@ -3,10 +3,17 @@ from dartrs.dartrs import (
     DartV2Mixtral,
     DartDevice,
     DartTokenizer,
+    GenerationCache,
     GenerationConfig,
+    LengthTag,
+    RatingTag,
+    SpecialTag,
 )
 from dotenv import load_dotenv
+import copy
 import os
+import pickle
+import pytest
 
 load_dotenv()
 
@@ -35,6 +42,103 @@ def test_generation_config():
     assert config is not None
 
 
+def test_generation_config_properties():
+    config = GenerationConfig(
+        DartDevice.Cpu(),
+        DartTokenizer.from_pretrained("p1atdev/dart-v2-moe-sft"),
+        "<|bos|><general>1girl<|input_end|>",
+        top_p=0.9,
+    )
+
+    assert config.max_new_tokens == 256
+    assert config.top_p == 0.9
+    assert config.ban_tags == []
+
+    config.seed = 42
+    config.ban_tags = ["*nude*"]
+    assert config.seed == 42
+    assert config.ban_tags == ["*nude*"]
+
+    for name, value in [
+        ("top_p", 0.0),
+        ("top_p", 1.5),
+        ("top_k", 0),
+        ("temperature", 0.0),
+        ("max_new_tokens", 0),
+        ("ban_tags", ["re:("]),
+    ]:
+        with pytest.raises(ValueError):
+            setattr(config, name, value)
+    assert config.top_p == 0.9
+
+
+def test_generation_config_replace():
+    config = GenerationConfig(
+        device=DartDevice.Cpu(),
+        tokenizer=DartTokenizer.from_pretrained("p1atdev/dart-v2-moe-sft"),
+        prompt="<|bos|><general>1girl<|input_end|>",
+        seed=0,
+    )
+
+    replaced = config.replace(seed=1, prompt="<|bos|><general>1boy<|input_end|>")
+    assert replaced.seed == 1
+    assert replaced.prompt == "<|bos|><general>1boy<|input_end|>"
+    assert config.seed == 0
+    assert config.replace() == config
+
+    with pytest.raises(ValueError):
+        config.replace(top_p=2.0)
+    with pytest.raises(ValueError):
+        config.replace(eos_token=10**9)
+    with pytest.raises(TypeError):
+        config.replace(sed=1)
+
+
+def test_pickle_generation_config():
+    config = GenerationConfig(
+        device=DartDevice.Cpu(),
+        tokenizer=DartTokenizer.from_pretrained("p1atdev/dart-v2-moe-sft"),
+        prompt="<|bos|><general>1girl<|input_end|>",
+        top_p=0.9,
+        seed=42,
+        ban_tags=["*nude*", "category:rating"],
+    )
+
+    restored = pickle.loads(pickle.dumps(config))
+    assert restored == config
+    assert repr(restored) == repr(config)
+    assert copy.deepcopy(config) == config
+    assert "top_p=0.9" in repr(config)
+    assert "ban_tags=['*nude*', 'category:rating']" in repr(config)
+
+
+def test_pickle_tokenizer():
+    tokenizer = DartTokenizer.from_pretrained("p1atdev/dart-v2-moe-sft")
+
+    restored = pickle.loads(pickle.dumps(tokenizer))
+    assert restored == tokenizer
+    assert restored.encode("1girl, cat ears") == tokenizer.encode("1girl, cat ears")
+
+
+def test_pickle_generation_cache():
+    cache = GenerationCache([0, 1, 2])
+
+    restored = pickle.loads(pickle.dumps(cache))
+    assert restored == cache
+    assert restored.input_tokens() == [0, 1, 2]
+    assert repr(cache) == (
+        "GenerationCache(input_tokens=[0, 1, 2], output_tokens=[], finished=False)"
+    )
+    assert GenerationCache([0, 1]) != cache
+
+
+def test_pickle_tags():
+    for tag in [LengthTag.Long, RatingTag.Sfw, SpecialTag.Bos, DartDevice("cuda:1")]:
+        assert pickle.loads(pickle.dumps(tag)) == tag
+        assert copy.copy(tag) == tag
+    assert repr(LengthTag.Long) == "LengthTag.Long"
+
+
 def test_mistral_model():
     model_name = "p1atdev/dart-v2-sft"
 
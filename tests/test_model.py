@@ -1,10 +1,13 @@
-from dartrs.dartrs import DartTokenizer, GenerationCache
+from dartrs.dartrs import CancellationToken, DartTokenizer, GenerationCache
 from dartrs.v2 import (
     MixtralModel,
     compose_prompt,
 )
 from dartrs.utils import get_generation_config, DType, Device
+from concurrent.futures import ThreadPoolExecutor
+import asyncio
 from random import randint
+import threading
 
 
 def prepare_models(dtype: DType = "fp32", device: Device = "cpu"):
@@ -257,6 +260,34 @@ def test_generate_with_ban_token_ids():
         assert "animal ears" not in result, result
 
 
+def test_generate_with_ban_tags():
+    model, tokenizer = prepare_models()
+
+    prompt = compose_prompt(
+        prompt="1girl, solo",
+        length="long",
+        identity="none",
+        aspect_ratio="tall",
+        rating="sfw",
+    )
+
+    for index in range(0, 30):
+        config = get_generation_config(
+            prompt=prompt,
+            tokenizer=tokenizer,
+            seed=index,
+            ban_tags=["*ears*", "re:^animal ", "category:identity"],
+            temperature=0.9,
+            top_p=0.9,
+            top_k=100,
+        )
+
+        result = model.generate(config)
+
+        assert "ears" not in result, result
+        assert "animal " not in result, result
+
+
 def test_generate_next_token():
     model, tokenizer = prepare_models()
 
@@ -279,6 +310,56 @@ def test_generate_next_token():
         assert token > 0
 
 
+def test_regenerate_after_truncation():
+    model, tokenizer = prepare_models()
+
+    prompt = compose_prompt(
+        prompt="1girl, cat ears",
+    )
+    # greedy decoding, so the regenerated tokens must be the same
+    config = get_generation_config(
+        prompt=prompt,
+        tokenizer=tokenizer,
+        temperature=None,
+        top_p=None,
+        top_k=None,
+    )
+
+    cache = GenerationCache(tokenizer.encode(prompt))
+    for _ in range(0, 5):
+        _token, cache = model._get_next_token(config, cache)
+    generated = cache.output_tokens()
+
+    cache.truncate_output_tokens(2)
+    for _ in range(0, 3):
+        _token, cache = model._get_next_token(config, cache)
+
+    assert cache.output_tokens() == generated
+
+
+def test_generated_length_tag():
+    model, tokenizer = prepare_models()
+
+    prompt = compose_prompt(
+        prompt="1girl, cat ears",
+        length="very_long",
+    )
+    config = get_generation_config(
+        prompt=prompt,
+        tokenizer=tokenizer,
+        seed=42,
+    )
+
+    cache = GenerationCache(tokenizer.encode(prompt))
+    for _ in range(0, config.max_new_tokens):
+        _token, cache = model._get_next_token(config, cache)
+        if cache.finished():
+            break
+
+    length = cache.length_tag(tokenizer)
+    assert length.to_tag().startswith("<|length:")
+
+
 def test_generate_stream():
     model, tokenizer = prepare_models()
 
@@ -295,3 +376,151 @@ def test_generate_stream():
     for tag in model.generate_stream(config):
         assert tag is not None
         assert isinstance(tag, str)
+
+
+def test_generate_in_threads():
+    model, tokenizer = prepare_models()
+
+    def config(seed: int):
+        return get_generation_config(
+            prompt=compose_prompt(prompt="1girl, cat ears"),
+            tokenizer=tokenizer,
+            seed=seed,
+        )
+
+    expected = [model.generate(config(seed)) for seed in range(4)]
+
+    # the GIL is released while generating, so the other thread keeps running
+    ticks = 0
+    generating = True
+
+    def tick():
+        nonlocal ticks
+        while generating:
+            ticks += 1
+
+    ticker = threading.Thread(target=tick)
+    ticker.start()
+    with ThreadPoolExecutor(max_workers=4) as pool:
+        outputs = list(pool.map(lambda seed: model.generate(config(seed)), range(4)))
+    generating = False
+    ticker.join()
+
+    assert outputs == expected
+    assert ticks > 0
+
+
+def test_generate_stream_interleaved():
+    model, tokenizer = prepare_models()
+
+    def config(seed: int):
+        return get_generation_config(
+            prompt=compose_prompt(prompt="1girl, cat ears"),
+            tokenizer=tokenizer,
+            seed=seed,
+            max_new_tokens=16,
+        )
+
+    expected = [list(model.generate_stream(config(seed))) for seed in (1, 2)]
+
+    # the streams share the kv cache of the model
+    first, second = [], []
+    for a, b in zip(model.generate_stream(config(1)), model.generate_stream(config(2))):
+        first.append(a)
+        second.append(b)
+
+    assert first == expected[0][: len(first)]
+    assert second == expected[1][: len(second)]
+
+
+def test_agenerate():
+    model, tokenizer = prepare_models()
+
+    config = get_generation_config(
+        prompt=compose_prompt(prompt="1girl, cat ears"),
+        tokenizer=tokenizer,
+        seed=42,
+    )
+    expected = model.generate(config)
+
+    async def main():
+        # the event loop keeps running while generating
+        ticks = 0
+        generation = asyncio.create_task(model.agenerate(config))
+        while not generation.done():
+            ticks += 1
+            await asyncio.sleep(0)
+        return await generation, ticks
+
+    output, ticks = asyncio.run(main())
+    assert output == expected
+    assert ticks > 1
+
+
+def test_agenerate_stream():
+    model, tokenizer = prepare_models()
+
+    config = get_generation_config(
+        prompt=compose_prompt(prompt="1girl, cat ears"),
+        tokenizer=tokenizer,
+        seed=42,
+    )
+    expected = model.generate(config)
+
+    async def main():
+        return [tag async for tag in model.agenerate_stream(config)]
+
+    # the tags are sampled like `generate`
+    tags = asyncio.run(main())
+    assert ", ".join(tag for tag in tags if tag) == expected
+
+
+def test_agenerate_cancel():
+    model, tokenizer = prepare_models()
+
+    config = get_generation_config(
+        prompt=compose_prompt(prompt="1girl, cat ears", length="very_long"),
+        tokenizer=tokenizer,
+        seed=42,
+    )
+
+    async def main():
+        generation = asyncio.create_task(model.agenerate(config))
+        await asyncio.sleep(0.01)
+        generation.cancel()
+        try:
+            await generation
+        except asyncio.CancelledError:
+            pass
+
+        tags = []
+        async for tag in model.agenerate_stream(config):
+            tags.append(tag)
+            if len(tags) == 2:
+                break
+        return tags
+
+    assert len(asyncio.run(main())) == 2
+    # the model is usable after the cancelled generations
+    assert model.generate(config) == model.generate(config)
+
+
+def test_generate_cancel():
+    model, tokenizer = prepare_models()
+
+    config = get_generation_config(
+        prompt=compose_prompt(prompt="1girl, cat ears", length="very_long"),
+        tokenizer=tokenizer,
+        seed=42,
+    )
+    expected = model.generate(config)
+
+    cancellation = CancellationToken()
+    cancellation.cancel()
+    assert model.generate(config, cancellation) == ""
+
+    # cancelled from another thread, the tags so far are returned
+    cancellation = CancellationToken()
+    threading.Timer(0.05, cancellation.cancel).start()
+    output = model.generate(config, cancellation)
+    assert expected.startswith(output)
@@ -1,4 +1,6 @@
-from dartrs import dartrs, v2
+import pytest
+
+from dartrs import dartrs, v1, v2
 
 
 def test_compose_prompt_v2():
@@ -43,3 +45,55 @@ def test_compose_prompt_v2_wrapper():
         f"<|rating:sfw|><|aspect_ratio:tall|><|length:long|>"
         f"<general>1girl, cat ears<|identity:lax|><|input_end|>"
     )
+
+
+def test_parse_tags():
+    assert dartrs.AspectRatioTag("tall").to_tag() == "<|aspect_ratio:tall|>"
+    assert (
+        dartrs.AspectRatioTag("<|aspect_ratio:tall|>").to_tag()
+        == "<|aspect_ratio:tall|>"
+    )
+    assert dartrs.IdentityTag("none").to_tag() == "<|identity:none|>"
+    assert dartrs.SpecialTag("<|eos|>").to_tag() == "<|eos|>"
+
+    with pytest.raises(ValueError, match="expected one of: sfw, general"):
+        dartrs.RatingTag("safe")
+
+
+def test_aspect_ratio_from_resolution():
+    tag = dartrs.AspectRatioTag.from_resolution(832, 1216)
+
+    assert tag.to_tag() == "<|aspect_ratio:tall|>"
+    assert tag.sdxl_resolution() == (832, 1216)
+    assert (
+        dartrs.AspectRatioTag.from_resolution(1920, 1080).to_tag()
+        == "<|aspect_ratio:ultra_wide|>"
+    )
+    with pytest.raises(ValueError):
+        dartrs.AspectRatioTag.from_resolution(0, 1216)
+
+
+def test_length_from_tag_count():
+    assert dartrs.LengthTag.from_tag_count(20).to_tag() == "<|length:medium|>"
+    assert dartrs.LengthTag.Medium.tag_count_range() == (20, 30)
+    assert dartrs.LengthTag.VeryLong.tag_count_range() == (40, None)
+    assert dartrs.LengthTag.from_tags("1girl, solo").to_tag() == "<|length:very_short|>"
+
+
+def test_compose_prompt_v1():
+    prompt = v1.compose_prompt(
+        prompt="1girl, cat ears",
+        copyright="vocaloid",
+        character="hatsune miku",
+        rating="general",
+        length="long",
+        do_completion=True,
+    )
+
+    assert prompt == (
+        f"<|bos|>"
+        f"<rating>rating:sfw, rating:general</rating>"
+        f"<copyright>vocaloid</copyright>"
+        f"<character>hatsune miku</character>"
+        f"<general><|long|>1girl, cat ears<|input_end|>"
+    )
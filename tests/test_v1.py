@@ -0,0 +1,23 @@
+from dartrs.dartrs import DartTokenizer
+from dartrs.utils import get_generation_config
+from dartrs.v1 import OptModel, compose_prompt
+
+
+def test_v1_opt_model():
+    model = OptModel.from_pretrained("p1atdev/dart-v1-sft")
+
+    assert model is not None
+
+
+def test_v1_generate():
+    model = OptModel.from_pretrained("p1atdev/dart-v1-sft")
+    tokenizer = DartTokenizer.from_pretrained("p1atdev/dart-v1-sft")
+
+    config = get_generation_config(
+        prompt=compose_prompt(prompt="1girl, cat ears"),
+        tokenizer=tokenizer,
+        seed=42,
+    )
+
+    output = model.generate(config)
+    assert output is not None
@@ -1,6 +1,17 @@
-from dartrs.v2 import MistralModel, MixtralModel
+from dartrs.dartrs import DartTokenizer
+from dartrs.utils import get_generation_config
+from dartrs.v2 import (
+    MistralModel,
+    MixtralModel,
+    SpeculativeModel,
+    V2Model,
+    compose_prompt,
+)
 from dotenv import load_dotenv
+import json
 import os
+import struct
+import pytest
 
 load_dotenv()
 
@@ -17,6 +28,14 @@ def test_v2_mixtral_model():
     assert model is not None
 
 
+def test_v2_model_detects_architecture():
+    mistral = V2Model.from_pretrained("p1atdev/dart-v2-sft")
+    mixtral = V2Model.from_pretrained("p1atdev/dart-v2-moe-sft")
+
+    assert mistral.model.architecture() == "mistral"
+    assert mixtral.model.architecture() == "mixtral"
+
+
 def test_v2_mistral_model_with_auth_token():
     TEST_HF_TOKEN = os.getenv("TEST_HF_TOKEN")
 
@@ -25,3 +44,126 @@ def test_v2_mistral_model_with_auth_token():
     )
 
     assert model is not None
+
+
+def test_v2_speculative_generate():
+    model = SpeculativeModel.from_pretrained(
+        "p1atdev/dart-v2-sft", "p1atdev/dart-v2-moe-sft", num_draft_tokens=4
+    )
+    tokenizer = DartTokenizer.from_pretrained("p1atdev/dart-v2-moe-sft")
+
+    config = get_generation_config(
+        prompt=compose_prompt(prompt="1girl, cat ears"),
+        tokenizer=tokenizer,
+        seed=42,
+    )
+
+    output = model.generate(config)
+    assert output is not None
+    assert 0.0 <= model.acceptance_rate() <= 1.0
+
+
+def test_v2_mixtral_expert_routing():
+    model = MixtralModel.from_pretrained("p1atdev/dart-v2-moe-sft")
+    tokenizer = DartTokenizer.from_pretrained("p1atdev/dart-v2-moe-sft")
+
+    config = get_generation_config(
+        prompt=compose_prompt(prompt="1girl, cat ears"),
+        tokenizer=tokenizer,
+        seed=42,
+    )
+
+    output, routing = model.generate_with_expert_routing(config)
+    assert output is not None
+    assert len(routing) > 0
+    for tag in routing:
+        assert len(tag.experts()) == len(tag.weights())
+        for weights in tag.weights():
+            assert abs(sum(weights) - 1.0) < 1e-3
+
+
+def test_v2_mistral_embed():
+    np = pytest.importorskip("numpy")
+
+    model = MistralModel.from_pretrained("p1atdev/dart-v2-sft")
+    tokenizer = DartTokenizer.from_pretrained("p1atdev/dart-v2-sft")
+
+    pooled = np.asarray(model.embed("1girl, cat ears", tokenizer))
+    hidden_states = np.asarray(
+        model.embed("1girl, cat ears", tokenizer, pooling="none")
+    )
+    assert pooled.shape == (hidden_states.shape[1],)
+    assert np.allclose(pooled, hidden_states.mean(axis=0), atol=1e-4)
+
+    tags = np.asarray(model.embed_tags(["1girl", "cat ears"], tokenizer))
+    assert tags.shape == (2, hidden_states.shape[1])
+
+
+def write_zero_lora_adapter(path, module: str, in_dim: int, out_dim: int, r: int = 2):
+    """Writes a PEFT adapter whose lora_B is zero, so it does not change the output."""
+    header = {}
+    offset = 0
+    for key, shape in [
+        (f"base_model.model.{module}.lora_A.weight", [r, in_dim]),
+        (f"base_model.model.{module}.lora_B.weight", [out_dim, r]),
+    ]:
+        size = shape[0] * shape[1] * 4
+        header[key] = {
+            "dtype": "F32",
+            "shape": shape,
+            "data_offsets": [offset, offset + size],
+        }
+        offset += size
+    header = json.dumps(header).encode()
+    with open(path / "adapter_model.safetensors", "wb") as f:
+        f.write(struct.pack("<Q", len(header)) + header + b"\0" * offset)
+    with open(path / "adapter_config.json", "w") as f:
+        json.dump({"r": r, "lora_alpha": r, "target_modules": ["q_proj"]}, f)
+
+
+def test_v2_mistral_lora(tmp_path):
+    model = MistralModel.from_pretrained("p1atdev/dart-v2-sft")
+    tokenizer = DartTokenizer.from_pretrained("p1atdev/dart-v2-sft")
+    hidden_size = model.embed_tags(["1girl"], tokenizer).shape()[1]
+    write_zero_lora_adapter(
+        tmp_path, "model.layers.0.self_attn.q_proj", hidden_size, hidden_size
+    )
+
+    def generate():
+        config = get_generation_config(
+            prompt=compose_prompt(prompt="1girl, cat ears"),
+            tokenizer=tokenizer,
+            seed=42,
+        )
+        return model.generate(config)
+
+    expected = generate()
+    model.load_lora("style", str(tmp_path), scale=0.5)
+    assert model.model.lora_adapters() == [("style", 0.5)]
+    assert generate() == expected
+
+    model.set_lora_scale("style", 1.0)
+    model.model.merge_lora("style")
+    assert model.model.lora_adapters() == []
+    assert generate() == expected
+
+
+def test_v2_mistral_attention_weights():
+    model = MistralModel.from_pretrained("p1atdev/dart-v2-sft")
+    tokenizer = DartTokenizer.from_pretrained("p1atdev/dart-v2-sft")
+
+    config = get_generation_config(
+        prompt=compose_prompt(prompt="1girl, cat ears"),
+        tokenizer=tokenizer,
+        seed=42,
+    )
+
+    output, tokens, attention = model.generate_with_attention_weights(config)
+    assert output is not None
+    prompt_len = len(tokens) - len(attention)
+    for i, tag in enumerate(attention):
+        assert tag.tag() == tokens[prompt_len + i]
+        for layer in tag.weights():
+            for head in layer:
+                assert len(head) == prompt_len + i
+                assert abs(sum(head) - 1.0) < 1e-3